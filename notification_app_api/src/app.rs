@@ -1,52 +1,300 @@
-use axum::http::{header::CONTENT_TYPE, StatusCode};
+use anyhow::format_err;
+use axum::{
+    http::{header::CONTENT_TYPE, StatusCode},
+    serve::Listener,
+    Router,
+};
 use deadqueue::unlimited::Queue;
-use log::debug;
-use stack_string::{format_sstr, StackString};
-use std::{collections::HashSet, net::SocketAddr, sync::Arc};
-use tokio::{net::TcpListener, task::spawn};
+use log::{debug, error, info};
+use stack_string::format_sstr;
+use std::{
+    fmt::Debug,
+    future::{pending, Future},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    fs,
+    net::{TcpListener, UnixListener},
+    signal,
+    task::spawn,
+    time::sleep,
+};
 use utoipa::OpenApi;
 use utoipa_axum::router::OpenApiRouter;
 
-use notification_app_bot::telegram_bot::TelegramBot;
-use notification_app_lib::config::{ApiTokenConfig, Config, TelegramMessage};
+use notification_app_bot::{
+    flood_control::FloodControl, health::BOT_HEALTH, reminders::ReminderStore,
+    scheduler::MessageScheduler, telegram_bot::TelegramBot,
+};
+use notification_app_lib::{
+    circuit_breaker::CIRCUITS,
+    config::{ApiTokenConfig, Config, TelegramMessage},
+    email_sender::EmailSender,
+    metrics::update_ses_metrics,
+    sns::SnsVerifier,
+    suppression::SuppressionList,
+    templates::Templates,
+};
 
 use crate::{
+    email_queue::EmailQueue,
     errors::ServiceError as Error,
+    listener::{bind_unix, ListenAddress, TlsListener},
+    reload::{ApiTokens, ConfigReloader},
     routes::{notify_telegram_router, ApiDoc},
+    systemd::{self, ActivatedListener},
 };
 
+/// Accepted email waiting to be sent, requests are refused once it is full
+const EMAIL_QUEUE_SIZE: usize = 1000;
+
 #[derive(Clone)]
 pub struct AppState {
     pub queue: Arc<Queue<TelegramMessage>>,
-    pub api_tokens: Arc<HashSet<StackString>>,
+    pub email_queue: Arc<EmailQueue>,
+    pub api_tokens: Arc<ApiTokens>,
+    pub flood: Arc<FloodControl>,
+    pub scheduler: Arc<MessageScheduler>,
+    pub reminders: Arc<ReminderStore>,
+    pub reloader: Arc<ConfigReloader>,
+    pub suppression: Arc<SuppressionList>,
+    pub sns: Arc<SnsVerifier>,
 }
 
 /// # Errors
 /// Returns error if app initialization fails
-pub async fn start_app() -> Result<(), Error> {
-    let config = Config::init_config()?;
+pub async fn start_app(config: Config) -> Result<(), Error> {
+    config.validate()?;
+    CIRCUITS.configure((&config).into());
+    let listen = ListenAddress::from_config(&config)?;
     let queue = Arc::new(Queue::new());
     let api_tokens_path = config
         .api_tokens_path
         .as_ref()
         .ok_or_else(|| Error::BadRequest(format_sstr!("No api token path set")))?;
-    let api_tokens = Arc::new(ApiTokenConfig::new(api_tokens_path).await?.api_tokens());
+    let token_config = ApiTokenConfig::new(api_tokens_path).await?;
+    token_config.validate_senders(None)?;
+    let api_tokens = Arc::new(ApiTokens::new(&token_config));
+    let flood = Arc::new(FloodControl::new(
+        config.flood_threshold,
+        Duration::from_secs(config.flood_window_minutes * 60),
+    ));
 
-    let telegram_bot_token = config
-        .telegram_bot_token
-        .as_ref()
-        .ok_or_else(|| Error::BadRequest(format_sstr!("No Telegram Token")))?;
-    let bot = TelegramBot::new(telegram_bot_token.as_str(), &config, queue.clone());
-    let bot = spawn(async move { bot.run().await });
+    let scheduler =
+        Arc::new(MessageScheduler::new(config.scheduled_messages_path.as_deref()).await?);
+    let scheduler_task = {
+        let scheduler = scheduler.clone();
+        let queue = queue.clone();
+        spawn(async move { scheduler.run(&queue).await })
+    };
 
-    let app = AppState { queue, api_tokens };
+    let reminders = Arc::new(ReminderStore::new(config.reminders_path.as_deref()).await?);
+    let reminder_task = {
+        let reminders = reminders.clone();
+        let queue = queue.clone();
+        spawn(async move { reminders.run(&queue).await })
+    };
 
-    run_api(app, config.port).await?;
-    bot.await??;
-    Ok(())
+    let email = EmailSender::from_config(&config).await?;
+    email.validate_senders(&token_config).await?;
+    let templates = match &config.templates_path {
+        Some(templates_path) => Templates::new(templates_path).await?,
+        None => Templates::default(),
+    };
+    let reloader = Arc::new(
+        ConfigReloader::new(config.clone(), api_tokens.clone(), flood.clone())
+            .with_templates(templates)
+            .with_email(Some(email)),
+    );
+    let suppression =
+        Arc::new(SuppressionList::new(config.suppression_list_path.as_deref()).await?);
+    let sns = Arc::new(SnsVerifier::from_config(&config));
+    {
+        let reloader = reloader.clone();
+        spawn(async move {
+            loop {
+                let ses = reloader.email().and_then(|email| email.ses().cloned());
+                if let Some(ses) = ses {
+                    if reloader.config().sending_email_address.is_some() {
+                        if let Err(e) = update_ses_metrics(&ses).await {
+                            error!("Failed to fetch SES quota {e}");
+                        }
+                    }
+                }
+                sleep(Duration::from_secs(300)).await;
+            }
+        });
+    }
+
+    if config.telegram_bot_token.is_none() {
+        return Err(Error::BadRequest(format_sstr!("No Telegram Token")));
+    }
+    let mut bot = {
+        let reloader = reloader.clone();
+        let queue = queue.clone();
+        let flood = flood.clone();
+        let reminders = reminders.clone();
+        spawn(async move {
+            loop {
+                let config = reloader.config();
+                let telegram_bot_token = config
+                    .telegram_bot_token
+                    .as_ref()
+                    .ok_or_else(|| format_err!("No Telegram Token"))?;
+                let bot = TelegramBot::new(
+                    telegram_bot_token.as_str(),
+                    &config,
+                    queue.clone(),
+                    flood.clone(),
+                    reminders.clone(),
+                );
+                tokio::select! {
+                    result = bot.run() => return result,
+                    () = reloader.bot_restart() => {
+                        info!("restarting telegram bot with the new configuration");
+                    }
+                }
+            }
+        })
+    };
+
+    {
+        let reloader = reloader.clone();
+        spawn(async move {
+            let mut hangup = match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+                Ok(hangup) => hangup,
+                Err(e) => {
+                    error!("Failed to listen for SIGHUP {e}");
+                    return;
+                }
+            };
+            while hangup.recv().await.is_some() {
+                if let Err(e) = reloader.reload().await {
+                    error!("Failed to reload configuration {e}");
+                }
+            }
+        });
+    }
+
+    if let Some(interval) = systemd::watchdog_interval() {
+        spawn(async move {
+            loop {
+                sleep(interval / 2).await;
+                if BOT_HEALTH.status().is_healthy() {
+                    if let Err(e) = systemd::notify("WATCHDOG=1") {
+                        error!("Failed to notify systemd {e}");
+                    }
+                } else {
+                    error!("Telegram bot is not running, skipping watchdog heartbeat");
+                }
+            }
+        });
+    }
+
+    let email_queue = Arc::new(EmailQueue::new(
+        EMAIL_QUEUE_SIZE,
+        config.email_queue_path.as_deref(),
+    ));
+    let restored = email_queue.restore().await?;
+    if restored > 0 {
+        info!("restored {restored} unsent emails");
+    }
+    let email_task = {
+        let email_queue = email_queue.clone();
+        let reloader = reloader.clone();
+        spawn(async move { email_queue.send_all(&reloader).await })
+    };
+
+    let app = AppState {
+        queue,
+        email_queue,
+        api_tokens,
+        flood,
+        scheduler,
+        reminders,
+        reloader,
+        suppression,
+        sns,
+    };
+
+    let scheduler = app.scheduler.clone();
+    let queue = app.queue.clone();
+    let flood = app.flood.clone();
+    let email_queue = app.email_queue.clone();
+    let (result, bot_running) = tokio::select! {
+        result = run_api(app, listen, shutdown_signal()) => (result, true),
+        result = &mut bot => {
+            // nothing is left to empty the queue, persist it right away
+            let result = match result {
+                Ok(Err(e)) => Err(e.into()),
+                Err(e) => Err(e.into()),
+                Ok(Ok(())) => Err(Error::AnyhowError(format_err!("Telegram bot exited"))),
+            };
+            (result, false)
+        }
+    };
+
+    scheduler_task.abort();
+    reminder_task.abort();
+    if bot_running {
+        let deadline = Duration::from_secs(config.shutdown_timeout_seconds);
+        MessageScheduler::wait_delivered(&queue, deadline).await;
+        // a message the bot is sending goes back on the queue once it has
+        // stopped, only then is the queue persisted
+        bot.abort();
+        bot.await.ok();
+    }
+    let persisted = scheduler.drain(&queue, &flood).await?;
+    if persisted > 0 {
+        info!("persisted {persisted} undelivered messages");
+    }
+    let email_deadline = Duration::from_secs(config.shutdown_timeout_seconds);
+    if !email_queue.wait_empty(email_deadline).await {
+        error!("{} emails are still unsent", email_queue.len());
+    }
+    email_task.abort();
+    let persisted = email_queue.persist().await?;
+    if persisted > 0 {
+        info!("persisted {persisted} unsent emails");
+    }
+    result
+}
+
+/// Resolves on ctrl-c or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = signal::ctrl_c().await {
+            error!("Failed to listen for ctrl-c {e}");
+            pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM {e}");
+                pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {},
+        () = terminate => {},
+    }
+    info!("shutting down");
+    if let Err(e) = systemd::notify("STOPPING=1") {
+        error!("Failed to notify systemd {e}");
+    }
 }
 
-async fn run_api(app: AppState, port: u32) -> Result<(), Error> {
+fn api_router(app: AppState) -> Result<Router, Error> {
     let app = Arc::new(app);
 
     let (router, api) = OpenApiRouter::with_openapi(ApiDoc::openapi())
@@ -56,7 +304,7 @@ async fn run_api(app: AppState, port: u32) -> Result<(), Error> {
     let spec_json = serde_json::to_string_pretty(&api)?;
     let spec_yaml = serde_yml::to_string(&api)?;
 
-    let router = router
+    Ok(router
         .route(
             "/notify/openapi/json",
             axum::routing::get(|| async move {
@@ -72,79 +320,772 @@ async fn run_api(app: AppState, port: u32) -> Result<(), Error> {
             axum::routing::get(|| async move {
                 (StatusCode::OK, [(CONTENT_TYPE, "text/yaml")], spec_yaml)
             }),
-        );
+        ))
+}
 
-    let addr: SocketAddr = format_sstr!("127.0.0.1:{port}").parse()?;
-    debug!("{addr:?}");
-    let listener = TcpListener::bind(&addr).await?;
-    axum::serve(listener, router.into_make_service()).await?;
+async fn run_api(
+    app: AppState,
+    listen: ListenAddress,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), Error> {
+    let router = api_router(app)?;
+    debug!("{listen:?}");
+    match listen {
+        ListenAddress::Tcp { addr, tls: None } => {
+            let listener = TcpListener::bind(&addr).await?;
+            serve(listener, router, shutdown).await
+        }
+        ListenAddress::Tcp {
+            addr,
+            tls: Some(tls),
+        } => {
+            let listener = TlsListener::bind(addr, tls).await?;
+            serve(listener, router, shutdown).await
+        }
+        ListenAddress::Systemd { tls } => match systemd::take_listener()? {
+            ActivatedListener::Tcp(listener) => {
+                let listener = TcpListener::from_std(listener)?;
+                match tls {
+                    Some(tls) => serve(TlsListener::new(listener, tls)?, router, shutdown).await,
+                    None => serve(listener, router, shutdown).await,
+                }
+            }
+            ActivatedListener::Unix(listener) => {
+                serve(UnixListener::from_std(listener)?, router, shutdown).await
+            }
+        },
+        ListenAddress::Unix { path, mode } => {
+            let listener = bind_unix(&path, mode)?;
+            let result = serve(listener, router, shutdown).await;
+            fs::remove_file(&path).await?;
+            result
+        }
+    }
+}
 
+async fn serve<L>(
+    listener: L,
+    router: Router,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), Error>
+where
+    L: Listener,
+    L::Addr: Debug,
+{
+    if let Err(e) = systemd::notify("READY=1") {
+        error!("Failed to notify systemd {e}");
+    }
+    axum::serve(listener, router.into_make_service())
+        .with_graceful_shutdown(shutdown)
+        .await?;
     Ok(())
 }
 
 #[cfg(test)]
 mod test {
-    use anyhow::Error;
-    use axum::http::{header::AUTHORIZATION, StatusCode};
+    use anyhow::{format_err, Error};
+    use axum::{
+        http::{
+            header::{AUTHORIZATION, CONTENT_TYPE},
+            StatusCode,
+        },
+        serve::Listener,
+    };
+    use base64::{engine::general_purpose::STANDARD, Engine};
     use deadqueue::unlimited::Queue;
-    use maplit::{hashmap, hashset};
-    use stack_string::format_sstr;
-    use std::sync::Arc;
+    use maplit::hashmap;
+    use stack_string::{format_sstr, StackString};
+    use std::{
+        collections::HashSet,
+        net::{Ipv4Addr, SocketAddr},
+        os::unix::fs::PermissionsExt,
+        path::Path,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+    use tempfile::TempDir;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, UnixStream},
+        sync::oneshot,
+        task::JoinHandle,
+    };
+
+    use notification_app_bot::{
+        flood_control::FloodControl, reminders::ReminderStore, scheduler::MessageScheduler,
+    };
+    use notification_app_lib::{
+        config::{Config, ConfigInner, MessageFormat},
+        email_sender::EmailSender,
+        ses_client::{SesInstance, SesQuotas},
+        smtp_client::{SmtpInstance, SmtpSecurity},
+        sns::{parse_topic_arns, SnsVerifier},
+        suppression::SuppressionList,
+        templates::Templates,
+    };
+
+    use crate::{
+        app::{api_router, run_api, serve, AppState},
+        email_queue::EmailQueue,
+        errors::ServiceError,
+        listener::{ListenAddress, TlsFiles, TlsListener},
+        reload::{ApiTokens, ConfigReloader},
+    };
+
+    const TOPIC: &str = "arn:aws:sns:us-east-1:123456789012:ses-feedback";
+
+    /// The api served on a port picked by the os
+    struct TestServer {
+        port: u16,
+        shutdown: oneshot::Sender<()>,
+        server: JoinHandle<Result<(), ServiceError>>,
+    }
+
+    impl TestServer {
+        async fn start(app: AppState) -> Result<Self, Error> {
+            Self::start_on(app, TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?).await
+        }
 
-    use crate::app::{run_api, AppState};
+        async fn start_on<L>(app: AppState, listener: L) -> Result<Self, Error>
+        where
+            L: Listener<Addr = SocketAddr>,
+        {
+            let port = listener.local_addr()?.port();
+            let router = api_router(app)?;
+            let (shutdown, rx) = oneshot::channel::<()>();
+            let server = tokio::task::spawn(serve(listener, router, async move {
+                rx.await.ok();
+            }));
+            Ok(Self {
+                port,
+                shutdown,
+                server,
+            })
+        }
+
+        fn url(&self, path: &str) -> StackString {
+            format_sstr!("http://localhost:{}/notify{path}", self.port)
+        }
+
+        async fn stop(self) -> Result<(), Error> {
+            self.shutdown.send(()).ok();
+            tokio::time::timeout(Duration::from_secs(5), self.server).await???;
+            Ok(())
+        }
+    }
+
+    /// Test state that sends email through an smtp relay, which is never
+    /// contacted since the test state runs no background sender
+    async fn email_state(name: &str) -> Result<AppState, Error> {
+        let smtp = SmtpInstance::new("127.0.0.1", 25, SmtpSecurity::None);
+        sender_state(name, EmailSender::Smtp(smtp)).await
+    }
+
+    async fn sender_state(name: &str, sender: EmailSender) -> Result<AppState, Error> {
+        let state = test_state(name).await?;
+        let config: Config = ConfigInner {
+            sending_email_address: Some("bot@example.com".into()),
+            ..ConfigInner::default()
+        }
+        .into();
+        let reloader = ConfigReloader::new(config, state.api_tokens.clone(), state.flood.clone())
+            .with_email(Some(sender));
+        Ok(AppState {
+            reloader: Arc::new(reloader),
+            ..state
+        })
+    }
+
+    /// State with a single api token "12345" owned by `name`
+    async fn test_state(name: &str) -> Result<AppState, Error> {
+        let api_tokens: Arc<ApiTokens> = Arc::new(hashmap! {"12345".into() => name.into()}.into());
+        let flood = Arc::new(FloodControl::new(20, Duration::from_secs(600)));
+        Ok(AppState {
+            queue: Arc::new(Queue::new()),
+            email_queue: Arc::new(EmailQueue::new(1, None)),
+            reloader: Arc::new(ConfigReloader::new(
+                Config::default(),
+                api_tokens.clone(),
+                flood.clone(),
+            )),
+            api_tokens,
+            flood,
+            scheduler: Arc::new(MessageScheduler::new(None).await?),
+            reminders: Arc::new(ReminderStore::new(None).await?),
+            suppression: Arc::new(SuppressionList::new(None).await?),
+            sns: Arc::new(SnsVerifier::new(HashSet::new())),
+        })
+    }
 
     #[tokio::test]
-    async fn test_run_app() -> Result<(), Error> {
-        let api_tokens = Arc::new(hashset! {"12345".into()});
-        let queue = Arc::new(Queue::new());
-        let app = {
-            let queue = queue.clone();
-            AppState { queue, api_tokens }
-        };
+    async fn test_notify() -> Result<(), Error> {
+        let app = test_state("notify").await?;
+        let queue = app.queue.clone();
+        let server = TestServer::start(app).await?;
+        let client = reqwest::Client::new();
 
-        let test_port = 12345;
-        tokio::task::spawn({
-            async move {
-                env_logger::init();
-                run_api(app, test_port).await.unwrap()
-            }
-        });
-        tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+        let response = client
+            .post(server.url("").as_str())
+            .header(AUTHORIZATION, "Bearer 12345")
+            .json(&hashmap! {"recipient" => "ddboline", "message" => "test message"})
+            .send()
+            .await?
+            .error_for_status()?;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.text().await?, "message sent");
+        let message = queue.try_pop().unwrap();
+        assert_eq!(message.recipient, "ddboline");
+        assert_eq!(message.message, "test message");
+        assert_eq!(message.sender.as_ref().unwrap(), "notify");
+        assert_eq!(message.format, MessageFormat::Text);
 
+        client
+            .post(server.url("").as_str())
+            .header(AUTHORIZATION, "Bearer 12345")
+            .json(&serde_json::json!({
+                "recipient": "ddboline",
+                "title": "disk full",
+                "severity": "warning",
+                "fields": [{"name": "host", "value": "db1"}],
+            }))
+            .send()
+            .await?
+            .error_for_status()?;
+        let message = queue.try_pop().unwrap();
+        assert_eq!(message.format, MessageFormat::Html);
+        assert!(message.message.contains("<b>[WARNING] disk full</b>"));
+        assert!(message.message.contains("<b>host:</b> db1"));
+        assert!(queue.try_pop().is_none());
+
+        let response = client
+            .post(server.url("").as_str())
+            .header(AUTHORIZATION, "Bearer 54321")
+            .json(&hashmap! {"recipient" => "ddboline", "message" => "test message"})
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        server.stop().await
+    }
+
+    #[tokio::test]
+    async fn test_metrics() -> Result<(), Error> {
+        let server = TestServer::start(test_state("metrics").await?).await?;
         let client = reqwest::Client::new();
-        let data = hashmap! {
-            "recipient" => "ddboline",
-            "message" => "test message",
+        client
+            .post(server.url("").as_str())
+            .header(AUTHORIZATION, "Bearer 12345")
+            .json(&hashmap! {"recipient" => "ddboline", "message" => "counted"})
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let response = client.get(server.url("/metrics").as_str()).send().await?;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let metrics = client
+            .get(server.url("/metrics").as_str())
+            .header(AUTHORIZATION, "Bearer 12345")
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        assert!(metrics.contains(
+            "notification_messages_accepted_total{channel=\"telegram\",token=\"metrics\"} 1"
+        ));
+        assert!(metrics.contains("notification_queue_depth 1"));
+        assert!(metrics.contains("notification_circuit_state{dependency=\"telegram\"} 0"));
+        server.stop().await
+    }
+
+    #[tokio::test]
+    async fn test_preview_template() -> Result<(), Error> {
+        let state = test_state("preview").await?;
+        let templates = Templates::from_raw([("greeting", "hello {{ name }}")])?;
+        let reloader = ConfigReloader::new(
+            Config::default(),
+            state.api_tokens.clone(),
+            state.flood.clone(),
+        )
+        .with_templates(templates);
+        let app = AppState {
+            reloader: Arc::new(reloader),
+            ..state
         };
+        let server = TestServer::start(app).await?;
+
+        let preview: serde_json::Value = reqwest::Client::new()
+            .post(server.url("/preview").as_str())
+            .header(AUTHORIZATION, "Bearer 12345")
+            .json(&serde_json::json!({
+                "recipient": "ddboline",
+                "template": "greeting",
+                "vars": {"name": "world"},
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(preview["telegram"], "hello world");
+        assert_eq!(preview["email"], "hello world");
+        server.stop().await
+    }
+
+    #[tokio::test]
+    async fn test_openapi_spec() -> Result<(), Error> {
+        let server = TestServer::start(test_state("openapi").await?).await?;
+        let spec_yaml = reqwest::get(server.url("/openapi/yaml").as_str())
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        assert_eq!(
+            spec_yaml,
+            include_str!("../../scripts/openapi.yaml"),
+            "regenerate with `notification-app-api openapi --output scripts/openapi.yaml`"
+        );
+        server.stop().await
+    }
+
+    #[tokio::test]
+    async fn test_ready() -> Result<(), Error> {
+        let server = TestServer::start(test_state("ready").await?).await?;
+        let response = reqwest::get(server.url("/ready").as_str()).await?;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let status: serde_json::Value = response.json().await?;
+        assert_eq!(status["ready"], false);
+        assert_eq!(status["bot_running"], false);
+        assert_eq!(status["queue_depth"], 0);
+        assert_eq!(status["circuits"][0]["name"], "telegram");
+        assert_eq!(status["circuits"][0]["state"], "closed");
+        server.stop().await
+    }
 
-        let url = format_sstr!("http://localhost:{test_port}/notify");
+    #[tokio::test]
+    async fn test_admin_routes_require_admin() -> Result<(), Error> {
+        let server = TestServer::start(test_state("admin").await?).await?;
+        let client = reqwest::Client::new();
+        let response = client
+            .post(server.url("/admin/reload").as_str())
+            .header(AUTHORIZATION, "Bearer 12345")
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = client
+            .get(server.url("/admin/suppressions").as_str())
+            .header(AUTHORIZATION, "Bearer 12345")
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        server.stop().await
+    }
+
+    #[tokio::test]
+    async fn test_notify_email() -> Result<(), Error> {
+        let server = TestServer::start(test_state("email").await?).await?;
+        let client = reqwest::Client::new();
+        let url = server.url("/email");
         let response = client
             .post(url.as_str())
             .header(AUTHORIZATION, "Bearer 12345")
-            .json(&data)
+            .json(&hashmap! {"to" => "a@example.com"})
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = "--b\r\nContent-Disposition: form-data; name=\"to\"\r\n\r\na@example.com\r\n\
+                    --b\r\nContent-Disposition: form-data; name=\"text\"\r\n\r\nhi\r\n--b--\r\n";
+        let response = client
+            .post(url.as_str())
+            .header(AUTHORIZATION, "Bearer 12345")
+            .header(CONTENT_TYPE, "multipart/form-data; boundary=b")
+            .body(body)
+            .send()
+            .await?;
+        // the test config has no sending address
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let error: serde_json::Value = response.json().await?;
+        assert!(error["message"]
+            .as_str()
+            .unwrap()
+            .contains("No sending email address"));
+
+        let url = server.url("/email/stats");
+        let response = client.get(url.as_str()).send().await?;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = client
+            .get(url.as_str())
+            .header(AUTHORIZATION, "Bearer 12345")
+            .send()
+            .await?;
+        // no email backend in the test state
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        server.stop().await?;
+
+        // accepted email is queued for the background sender, which the
+        // test state doesn't run, so its single slot stays taken
+        let app = email_state("email").await?;
+        let email_queue = app.email_queue.clone();
+        let server = TestServer::start(app).await?;
+        let url = server.url("/email");
+        let send = || {
+            client
+                .post(url.as_str())
+                .header(AUTHORIZATION, "Bearer 12345")
+                .header(CONTENT_TYPE, "multipart/form-data; boundary=b")
+                .body(body)
+                .send()
+        };
+        let response = send().await?;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(response.text().await?, "email queued");
+        let response = send().await?;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let request = email_queue.try_pop().unwrap();
+        assert_eq!(request.from.address, "bot@example.com");
+        assert_eq!(request.token.as_ref().unwrap(), "email");
+        server.stop().await
+    }
+
+    #[tokio::test]
+    async fn test_notify_email_quota_low() -> Result<(), Error> {
+        let config: Config = ConfigInner {
+            ses_region: Some("us-east-1".into()),
+            ses_endpoint_url: Some("http://127.0.0.1:1".parse()?),
+            ses_quota_reserve: 0.05,
+            ..ConfigInner::default()
+        }
+        .into();
+        let ses = SesInstance::from_config(&config).await;
+        let throttle = ses.throttle().unwrap();
+        // 10 of the 200 are held in reserve
+        throttle.update(
+            SesQuotas {
+                max_24_hour_send: 200.0,
+                max_send_rate: 1.0,
+                sent_last_24_hours: 190.0,
+            },
+            Instant::now(),
+        );
+        let app = sender_state("quota", EmailSender::Ses(ses)).await?;
+        let email_queue = app.email_queue.clone();
+        let server = TestServer::start(app).await?;
+        let body = "--b\r\nContent-Disposition: form-data; name=\"to\"\r\n\r\na@example.com\r\n\
+            --b\r\nContent-Disposition: form-data; name=\"text\"\r\n\r\nhello\r\n--b--\r\n";
+        let response = reqwest::Client::new()
+            .post(server.url("/email").as_str())
+            .header(AUTHORIZATION, "Bearer 12345")
+            .header(CONTENT_TYPE, "multipart/form-data; boundary=b")
+            .body(body)
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let text = response.text().await?;
+        assert!(text.contains("quota exhausted, 10 of 200 left"), "{}", text);
+        assert!(email_queue.is_empty());
+        server.stop().await
+    }
+
+    #[tokio::test]
+    async fn test_notify_structured_email() -> Result<(), Error> {
+        let app = email_state("alert").await?;
+        let email_queue = app.email_queue.clone();
+        let server = TestServer::start(app).await?;
+        let field = |name: &str, value: &str| {
+            format!("--b\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n")
+        };
+        let body = [
+            field("to", "a@example.com"),
+            field("title", "Disk full"),
+            field("severity", "critical"),
+            field("field", "host: db1"),
+            "--b--\r\n".into(),
+        ]
+        .concat();
+        let response = reqwest::Client::new()
+            .post(server.url("/email").as_str())
+            .header(AUTHORIZATION, "Bearer 12345")
+            .header(CONTENT_TYPE, "multipart/form-data; boundary=b")
+            .body(body)
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let request = email_queue.try_pop().unwrap();
+        let mime = String::from_utf8(request.to_mime()?)?;
+        assert!(
+            mime.contains("Subject: [CRITICAL] Disk full\r\n"),
+            "{}",
+            mime
+        );
+        assert!(
+            mime.contains("Content-Type: multipart/alternative"),
+            "{}",
+            mime
+        );
+        let text = mime_part(&mime, "text/plain")?;
+        assert_eq!(text, "[CRITICAL] Disk full\nhost: db1");
+        let html = mime_part(&mime, "text/html")?;
+        assert!(
+            html.contains("<th align=\"left\">host</th><td>db1</td>"),
+            "{}",
+            html
+        );
+        server.stop().await
+    }
+
+    /// Decoded body of the base64 part of `mime` with the given content type
+    fn mime_part(mime: &str, content_type: &str) -> Result<String, Error> {
+        let header = format_sstr!("Content-Type: {content_type}");
+        let start = mime
+            .find(header.as_str())
+            .ok_or_else(|| format_err!("No {content_type} part"))?;
+        let part = &mime[start..];
+        let body_start = part
+            .find("\r\n\r\n")
+            .ok_or_else(|| format_err!("No body"))?
+            + 4;
+        let body = &part[body_start..];
+        let body_end = body.find("--").unwrap_or(body.len());
+        let encoded: String = body[..body_end].split_whitespace().collect();
+        Ok(String::from_utf8(STANDARD.decode(encoded)?)?)
+    }
+
+    #[tokio::test]
+    async fn test_sns_rejects_untrusted() -> Result<(), Error> {
+        let app = AppState {
+            sns: Arc::new(SnsVerifier::new(parse_topic_arns(TOPIC))),
+            ..test_state("sns").await?
+        };
+        let server = TestServer::start(app).await?;
+        let client = reqwest::Client::new();
+        let url = server.url("/sns");
+        let response = client.post(url.as_str()).body("{}").send().await?;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let message = serde_json::json!({
+            "Type": "Notification",
+            "MessageId": "22b80b92",
+            "TopicArn": TOPIC,
+            "Message": "{}",
+            "Timestamp": "2024-01-01T00:00:00.000Z",
+            "SignatureVersion": "2",
+            "Signature": "AAAA",
+            "SigningCertURL": "https://example.com/cert.pem",
+        });
+        let response = client.post(url.as_str()).json(&message).send().await?;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let error: serde_json::Value = response.json().await?;
+        assert!(error["message"].as_str().unwrap().contains("Untrusted"));
+        server.stop().await
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_messages() -> Result<(), Error> {
+        let app = test_state("scheduled").await?;
+        let queue = app.queue.clone();
+        let server = TestServer::start(app).await?;
+        let client = reqwest::Client::new();
+
+        let text = client
+            .post(server.url("").as_str())
+            .header(AUTHORIZATION, "Bearer 12345")
+            .json(&serde_json::json!({
+                "recipient": "ddboline",
+                "message": "later",
+                "delay": 3600,
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        assert!(text.starts_with("message scheduled"));
+        assert!(queue.try_pop().is_none());
+
+        let scheduled: Vec<serde_json::Value> = client
+            .get(server.url("/scheduled").as_str())
+            .header(AUTHORIZATION, "Bearer 12345")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(scheduled.len(), 1);
+        assert_eq!(scheduled[0]["message"], "later");
+
+        let id = scheduled[0]["id"].as_str().unwrap();
+        let url = server.url(&format_sstr!("/scheduled/{id}"));
+        let response = client
+            .delete(url.as_str())
+            .header(AUTHORIZATION, "Bearer 12345")
             .send()
             .await?
             .error_for_status()?;
-        assert_eq!(response.status(), StatusCode::CREATED);
-        let text = response.text().await?;
-        assert_eq!(text, "message sent");
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = client
+            .delete(url.as_str())
+            .header(AUTHORIZATION, "Bearer 12345")
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        server.stop().await
+    }
 
-        let url = format_sstr!("http://localhost:{test_port}/notify/openapi/yaml");
-        let spec_yaml = client
+    #[tokio::test]
+    async fn test_reminders() -> Result<(), Error> {
+        let server = TestServer::start(test_state("reminders").await?).await?;
+        let client = reqwest::Client::new();
+        let url = server.url("/reminders");
+        let reminder: serde_json::Value = client
+            .post(url.as_str())
+            .header(AUTHORIZATION, "Bearer 12345")
+            .json(&serde_json::json!({
+                "recipient": "ddboline",
+                "message": "standup",
+                "cron": "0 9 * * 1-5",
+                "timezone": "America/New_York",
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(reminder["cron"], "0 9 * * 1-5");
+        let reminders: Vec<serde_json::Value> = client
             .get(url.as_str())
+            .header(AUTHORIZATION, "Bearer 12345")
             .send()
             .await?
             .error_for_status()?
-            .text()
+            .json()
+            .await?;
+        assert_eq!(reminders.len(), 1);
+        let id = reminder["id"].as_str().unwrap();
+        let url = server.url(&format_sstr!("/reminders/{id}"));
+        let response = client
+            .delete(url.as_str())
+            .header(AUTHORIZATION, "Bearer 12345")
+            .send()
+            .await?
+            .error_for_status()?;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = client
+            .delete(url.as_str())
+            .header(AUTHORIZATION, "Bearer 12345")
+            .send()
             .await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        server.stop().await
+    }
 
-        tokio::fs::write("../scripts/openapi.yaml", &spec_yaml).await?;
+    #[tokio::test]
+    async fn test_graceful_shutdown() -> Result<(), Error> {
+        let dir = TempDir::new()?;
+        let scheduler = Arc::new(
+            MessageScheduler::new(Some(&dir.path().join("scheduled_messages.json"))).await?,
+        );
+        let app = AppState {
+            scheduler: scheduler.clone(),
+            ..test_state("shutdown").await?
+        };
+        let queue = app.queue.clone();
+        let flood = app.flood.clone();
+        let server = TestServer::start(app).await?;
+        let client = reqwest::Client::new();
+        let url = server.url("");
+
+        let response = client
+            .post(url.as_str())
+            .header(AUTHORIZATION, "Bearer 12345")
+            .json(&hashmap! {"recipient" => "shutdown", "message" => "pending"})
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(queue.len(), 1);
+
+        server.stop().await?;
+        assert!(client
+            .post(url.as_str())
+            .header(AUTHORIZATION, "Bearer 12345")
+            .json(&hashmap! {"recipient" => "shutdown", "message" => "rejected"})
+            .send()
+            .await
+            .is_err());
+
+        assert!(!MessageScheduler::wait_delivered(&queue, Duration::from_millis(200)).await);
+        let persisted = scheduler.drain(&queue, &flood).await?;
+        assert_eq!(persisted, 1);
+        assert!(queue.is_empty());
+        assert_eq!(
+            scheduler.list("shutdown").await[0].message.message,
+            "pending"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_api_tls() -> Result<(), Error> {
+        let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("../tests/data");
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let listener = TlsListener::new(
+            listener,
+            TlsFiles {
+                cert: data.join("test_tls_cert.pem"),
+                key: data.join("test_tls_key.pem"),
+            },
+        )?;
+        let server = TestServer::start_on(test_state("tls").await?, listener).await?;
+
+        let ca = reqwest::Certificate::from_pem(&std::fs::read(data.join("test_tls_ca.pem"))?)?;
+        let client = reqwest::Client::builder()
+            .add_root_certificate(ca)
+            .build()?;
+        let url = server.url("/health");
+        let response = client
+            .get(url.replacen("http://", "https://", 1))
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(client.get(url.as_str()).send().await.is_err());
+        server.stop().await
+    }
+
+    #[tokio::test]
+    async fn test_run_api_unix_socket() -> Result<(), Error> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("notification.sock");
+        let listen = ListenAddress::Unix {
+            path: path.clone(),
+            mode: Some(0o660),
+        };
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = tokio::task::spawn(run_api(test_state("unix").await?, listen, async move {
+            rx.await.ok();
+        }));
 
-        while let Some(message) = queue.try_pop() {
-            assert_eq!(message.recipient, "ddboline");
-            assert_eq!(message.message, "test message");
-            println!("{message:?}");
+        let mut stream = None;
+        for _ in 0..100 {
+            if let Ok(s) = UnixStream::connect(&path).await {
+                stream.replace(s);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
         }
+        let mut stream = stream.unwrap();
+        assert_eq!(
+            std::fs::metadata(&path)?.permissions().mode() & 0o777,
+            0o660
+        );
+        stream
+            .write_all(
+                b"GET /notify/health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            )
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 503"));
+
+        tx.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server).await???;
+        assert!(!path.exists());
         Ok(())
     }
 }
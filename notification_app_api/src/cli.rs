@@ -0,0 +1,385 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use stack_string::{format_sstr, StackString};
+use std::path::{Path, PathBuf};
+use tokio::{fs, task::spawn};
+use uuid::Uuid;
+
+use notification_app_bot::telegram_bot::send_test_message;
+use notification_app_lib::{
+    config::{ApiTokenConfig, ApiTokenEntry, Config},
+    email::EmailRequest,
+    email_sender::EmailSender,
+    ses_client::SesInstance,
+    templates::Channel,
+};
+
+use crate::{
+    app::start_app, errors::ServiceError as Error, routes::api_spec, SesStatisticsWrapper,
+};
+
+#[derive(Parser, Debug)]
+#[clap(version, about = "Notification service")]
+pub struct NotificationCli {
+    /// Structured config file, defaults to `config.toml` / `config.yaml` in
+    /// the working directory or the config directory
+    #[clap(short, long, global = true)]
+    config: Option<PathBuf>,
+    /// Listen on this port instead of the configured one
+    #[clap(short, long, global = true)]
+    port: Option<u16>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the api server and telegram bot (the default)
+    Serve,
+    /// Report every problem with the configuration
+    CheckConfig,
+    /// Print the OpenAPI spec
+    Openapi {
+        #[clap(short, long, value_enum, default_value = "yaml")]
+        format: SpecFormat,
+        /// Write the spec to this file instead of stdout
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Manage entries in the api tokens file
+    Tokens {
+        #[clap(subcommand)]
+        command: TokensCommand,
+    },
+    /// Print the SES sending quota and bounce, complaint and reject totals
+    SesStats {
+        #[clap(short, long, value_enum, default_value = "table")]
+        format: StatsFormat,
+    },
+    /// Send a test message to a token entry, bypassing the running server
+    SendTest {
+        /// Name of the entry in the api tokens file
+        #[clap(short, long)]
+        recipient: StackString,
+        #[clap(long, value_enum, default_value = "telegram")]
+        channel: TestChannel,
+        #[clap(short, long, default_value = "Test message from notification-app-api")]
+        message: StackString,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum TokensCommand {
+    /// List token entries without their tokens
+    List,
+    /// Add an entry with a newly generated token and print the token
+    Add {
+        name: StackString,
+        #[clap(long)]
+        email: Option<StackString>,
+        #[clap(long)]
+        telegram_userid: Option<i64>,
+        /// Allow the token to use admin endpoints
+        #[clap(long)]
+        admin: bool,
+    },
+    /// Replace the token of an existing entry and print the new token
+    Rotate {
+        name: StackString,
+    },
+    Remove {
+        name: StackString,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum SpecFormat {
+    Json,
+    Yaml,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum StatsFormat {
+    Json,
+    Table,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum TestChannel {
+    Telegram,
+    Email,
+}
+
+impl From<TestChannel> for Channel {
+    fn from(item: TestChannel) -> Self {
+        match item {
+            TestChannel::Telegram => Self::Telegram,
+            TestChannel::Email => Self::Email,
+        }
+    }
+}
+
+fn new_token() -> StackString {
+    format_sstr!("{}", Uuid::new_v4().simple())
+}
+
+impl NotificationCli {
+    fn load_config(&self) -> Result<Config, Error> {
+        let config = match &self.config {
+            Some(path) => Config::from_file(path),
+            None => Config::init_config(),
+        }?;
+        Ok(config.with_port_override(self.port.map(Into::into)))
+    }
+
+    /// # Errors
+    /// Return error if the command fails
+    pub async fn run(self) -> Result<(), Error> {
+        match self.command.as_ref().unwrap_or(&Command::Serve) {
+            Command::Serve => {
+                let config = self.load_config()?;
+                spawn(async move { start_app(config).await }).await?
+            }
+            Command::CheckConfig => {
+                let problems = Config::check(self.config.as_deref())?;
+                for problem in &problems {
+                    println!("{problem}");
+                }
+                if problems.is_empty() {
+                    println!("configuration ok");
+                    Ok(())
+                } else {
+                    Err(Error::BadRequest(format_sstr!(
+                        "{} configuration problems",
+                        problems.len()
+                    )))
+                }
+            }
+            Command::Openapi { format, output } => {
+                let spec = api_spec();
+                let spec = match format {
+                    SpecFormat::Json => serde_json::to_string_pretty(&spec)?,
+                    SpecFormat::Yaml => serde_yml::to_string(&spec)?,
+                };
+                match output {
+                    Some(output) => fs::write(output, spec).await?,
+                    None => println!("{spec}"),
+                }
+                Ok(())
+            }
+            Command::Tokens { command } => {
+                let config = self.load_config()?;
+                let path = config
+                    .api_tokens_path
+                    .as_ref()
+                    .ok_or_else(|| Error::BadRequest("No api token path set".into()))?;
+                tokens(command, path).await
+            }
+            Command::SesStats { format } => {
+                let config = self.load_config()?;
+                let ses = SesInstance::from_config(&config).await;
+                let (quota, stats) = ses.get_statistics().await?;
+                let statistics = SesStatisticsWrapper::new(quota, stats);
+                match format {
+                    StatsFormat::Json => {
+                        println!("{}", serde_json::to_string_pretty(&statistics)?);
+                    }
+                    StatsFormat::Table => print!("{}", statistics.to_table()),
+                }
+                Ok(())
+            }
+            Command::SendTest {
+                recipient,
+                channel,
+                message,
+            } => {
+                let config = self.load_config()?;
+                send_test(&config, recipient, *channel, message).await
+            }
+        }
+    }
+}
+
+async fn tokens(command: &TokensCommand, path: &Path) -> Result<(), Error> {
+    let mut tokens = if path.exists() {
+        ApiTokenConfig::new(path).await?
+    } else {
+        ApiTokenConfig::default()
+    };
+    match command {
+        TokensCommand::List => {
+            let mut names: Vec<_> = tokens.keys().collect();
+            names.sort();
+            for name in names {
+                let entry = &tokens[name];
+                let email = entry.email.as_ref().map_or("", StackString::as_str);
+                let userid = entry
+                    .telegram_userid
+                    .map_or_else(StackString::new, |u| format_sstr!("{u}"));
+                let admin = if entry.admin { "admin" } else { "" };
+                println!("{name} {email} {userid} {admin}");
+            }
+            return Ok(());
+        }
+        TokensCommand::Add {
+            name,
+            email,
+            telegram_userid,
+            admin,
+        } => {
+            if tokens.contains_key(name) {
+                return Err(Error::BadRequest(format_sstr!(
+                    "Entry {name} already exists"
+                )));
+            }
+            let token = new_token();
+            tokens.insert(
+                name.clone(),
+                ApiTokenEntry {
+                    email: email.clone(),
+                    telegram_userid: *telegram_userid,
+                    api_token: Some(token.clone()),
+                    admin: *admin,
+                    ..ApiTokenEntry::default()
+                },
+            );
+            println!("{token}");
+        }
+        TokensCommand::Rotate { name } => {
+            let mut entry = tokens
+                .remove(name)
+                .ok_or_else(|| Error::BadRequest(format_sstr!("No entry {name}")))?;
+            let token = new_token();
+            entry.api_token = Some(token.clone());
+            tokens.insert(name.clone(), entry);
+            println!("{token}");
+        }
+        TokensCommand::Remove { name } => {
+            tokens
+                .remove(name)
+                .ok_or_else(|| Error::BadRequest(format_sstr!("No entry {name}")))?;
+        }
+    }
+    tokens.write(path).await?;
+    Ok(())
+}
+
+async fn send_test(
+    config: &Config,
+    recipient: &str,
+    channel: TestChannel,
+    message: &str,
+) -> Result<(), Error> {
+    let path = config
+        .api_tokens_path
+        .as_ref()
+        .ok_or_else(|| Error::BadRequest("No api token path set".into()))?;
+    let tokens = ApiTokenConfig::new(path).await?;
+    let entry = tokens
+        .get(recipient)
+        .ok_or_else(|| Error::BadRequest(format_sstr!("No entry {recipient}")))?;
+    match channel {
+        TestChannel::Telegram => {
+            let bot_token = config
+                .telegram_bot_token
+                .as_ref()
+                .ok_or_else(|| Error::BadRequest("No Telegram Token".into()))?;
+            let chatid = entry.telegram_chatid.ok_or_else(|| {
+                Error::BadRequest(format_sstr!("{recipient} has not sent /init to the bot"))
+            })?;
+            send_test_message(bot_token, chatid, message).await?;
+        }
+        TestChannel::Email => {
+            let from = config.sender()?;
+            let dest = entry
+                .email
+                .as_ref()
+                .ok_or_else(|| Error::BadRequest(format_sstr!("{recipient} has no email")))?;
+            let sender = EmailSender::from_config(config).await?;
+            let request = EmailRequest {
+                subject: format_sstr!("Notification from {}", from.address),
+                from,
+                to: vec![dest.parse()?],
+                text: Some(message.into()),
+                ..EmailRequest::default()
+            };
+            sender.send_email(&request).await?;
+        }
+    }
+    println!(
+        "sent test message to {recipient} via {}",
+        Channel::from(channel).as_str()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+    use clap::Parser;
+    use tempfile::TempDir;
+
+    use notification_app_lib::config::ApiTokenConfig;
+
+    use crate::cli::{tokens, Command, NotificationCli, StatsFormat, TokensCommand};
+
+    #[test]
+    fn test_parse_cli() {
+        let cli = NotificationCli::parse_from(["notification-app-api"]);
+        assert!(cli.command.is_none());
+        let cli = NotificationCli::parse_from([
+            "notification-app-api",
+            "openapi",
+            "--format",
+            "json",
+            "--config",
+            "config.yaml",
+        ]);
+        assert!(matches!(cli.command, Some(Command::Openapi { .. })));
+        assert_eq!(cli.config.unwrap().to_str(), Some("config.yaml"));
+        let cli = NotificationCli::parse_from(["notification-app-api", "--port", "8080", "serve"]);
+        assert_eq!(cli.port, Some(8080));
+        let cli = NotificationCli::parse_from(["notification-app-api", "ses-stats", "-f", "json"]);
+        assert!(matches!(
+            cli.command,
+            Some(Command::SesStats {
+                format: StatsFormat::Json
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_tokens() -> Result<(), Error> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("api_tokens.toml");
+        tokens(
+            &TokensCommand::Add {
+                name: "ops".into(),
+                email: None,
+                telegram_userid: Some(1),
+                admin: true,
+            },
+            &path,
+        )
+        .await?;
+        let config = ApiTokenConfig::new(&path).await?;
+        let token = config["ops"].api_token.clone().unwrap();
+        assert!(config.admin_names().contains("ops"));
+
+        let add_again = TokensCommand::Add {
+            name: "ops".into(),
+            email: None,
+            telegram_userid: None,
+            admin: false,
+        };
+        assert!(tokens(&add_again, &path).await.is_err());
+
+        tokens(&TokensCommand::Rotate { name: "ops".into() }, &path).await?;
+        let config = ApiTokenConfig::new(&path).await?;
+        assert_ne!(config["ops"].api_token.as_ref().unwrap(), &token);
+        assert_eq!(config["ops"].telegram_userid, Some(1));
+
+        tokens(&TokensCommand::Remove { name: "ops".into() }, &path).await?;
+        assert!(ApiTokenConfig::new(&path).await?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+use anyhow::Error;
+use deadqueue::limited;
+use log::{error, warn};
+use std::{
+    path::{Path, PathBuf},
+    sync::{PoisonError, RwLock},
+    time::Instant,
+};
+use tokio::{
+    fs,
+    time::{sleep, Duration},
+};
+
+use notification_app_lib::{email::EmailRequest, email_sender::EmailSender};
+
+use crate::reload::ConfigReloader;
+
+/// Pause before retrying an email held back by the SES quota, an open
+/// circuit or missing email configuration
+const RETRY_PAUSE: Duration = Duration::from_secs(10);
+
+/// Accepted email waiting for the background sender. Email that cannot be
+/// sent yet stays here and is persisted to `path` on shutdown, so that it is
+/// sent after the next start rather than lost.
+pub struct EmailQueue {
+    queue: limited::Queue<EmailRequest>,
+    /// Email taken by the sender and not yet sent
+    current: RwLock<Option<EmailRequest>>,
+    path: Option<PathBuf>,
+}
+
+impl EmailQueue {
+    #[must_use]
+    pub fn new(size: usize, path: Option<&Path>) -> Self {
+        Self {
+            queue: limited::Queue::new(size),
+            current: RwLock::new(None),
+            path: path.map(Into::into),
+        }
+    }
+
+    /// Returns false if the queue is full
+    #[must_use]
+    pub fn try_push(&self, request: EmailRequest) -> bool {
+        self.queue.try_push(request).is_ok()
+    }
+
+    pub fn try_pop(&self) -> Option<EmailRequest> {
+        self.queue.try_pop()
+    }
+
+    /// Number of unsent emails, including the one being sent
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.queue.len() + usize::from(self.current().is_some())
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn current(&self) -> Option<EmailRequest> {
+        self.current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn set_current(&self, request: Option<EmailRequest>) {
+        *self.current.write().unwrap_or_else(PoisonError::into_inner) = request;
+    }
+
+    /// The email to send next, the last one again if it was not sent
+    async fn next(&self) -> EmailRequest {
+        if let Some(request) = self.current() {
+            return request;
+        }
+        let request = self.queue.pop().await;
+        self.set_current(Some(request.clone()));
+        request
+    }
+
+    /// Send queued email one at a time, so request handlers never wait for
+    /// the SES quota. Email that fails because the quota is exhausted, the
+    /// circuit is open or email is not configured is retried, any other
+    /// failure drops it.
+    pub async fn send_all(&self, reloader: &ConfigReloader) {
+        loop {
+            let request = self.next().await;
+            let result = match reloader.email() {
+                Some(sender) => sender.send_email(&request).await,
+                None => {
+                    warn!("Email is not configured, holding queued email");
+                    sleep(RETRY_PAUSE).await;
+                    continue;
+                }
+            };
+            match result {
+                Err(e) if EmailSender::is_transient(&e) => {
+                    warn!("Failed to send email, retrying {e}");
+                    sleep(RETRY_PAUSE).await;
+                }
+                Err(e) => {
+                    error!("Failed to send email {e}");
+                    self.set_current(None);
+                }
+                Ok(()) => self.set_current(None),
+            }
+        }
+    }
+
+    /// Wait up to `deadline` for every email to be sent, returns false on
+    /// timeout
+    pub async fn wait_empty(&self, deadline: Duration) -> bool {
+        let start = Instant::now();
+        while !self.is_empty() {
+            if start.elapsed() >= deadline {
+                return false;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        true
+    }
+
+    /// Queue email persisted by the last shutdown, returns the number of
+    /// queued emails
+    /// # Errors
+    /// Return error if the file cannot be read, parsed or removed
+    pub async fn restore(&self) -> Result<usize, Error> {
+        let Some(path) = &self.path else {
+            return Ok(0);
+        };
+        if !path.exists() {
+            return Ok(0);
+        }
+        let data = fs::read(path).await?;
+        let requests: Vec<EmailRequest> = serde_json::from_slice(&data)?;
+        let total = requests.len();
+        let mut count = 0;
+        for mut request in requests {
+            request.queued = Some(Instant::now());
+            if self.queue.try_push(request).is_ok() {
+                count += 1;
+            }
+        }
+        if count < total {
+            error!(
+                "Email queue is full, dropping {} persisted emails",
+                total - count
+            );
+        }
+        fs::remove_file(path).await?;
+        Ok(count)
+    }
+
+    /// Persist every unsent email, call once the sender has stopped, returns
+    /// the number of persisted emails
+    /// # Errors
+    /// Return error if writing the file fails
+    pub async fn persist(&self) -> Result<usize, Error> {
+        let mut pending: Vec<_> = self
+            .current
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+            .into_iter()
+            .collect();
+        while let Some(request) = self.queue.try_pop() {
+            pending.push(request);
+        }
+        if pending.is_empty() {
+            return Ok(0);
+        }
+        let Some(path) = &self.path else {
+            warn!(
+                "No email_queue_path, dropping {} unsent emails",
+                pending.len()
+            );
+            return Ok(0);
+        };
+        let data = serde_json::to_vec_pretty(&pending)?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, &data).await?;
+        fs::rename(&tmp, path).await?;
+        Ok(pending.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+    use tokio::time::{sleep, Duration};
+
+    use notification_app_bot::flood_control::FloodControl;
+    use notification_app_lib::{
+        config::{ApiTokenConfig, Config, ConfigInner},
+        email::{EmailAddress, EmailRequest},
+    };
+
+    use crate::{
+        email_queue::EmailQueue,
+        reload::{ApiTokens, ConfigReloader},
+    };
+
+    fn request(subject: &str) -> EmailRequest {
+        EmailRequest {
+            from: EmailAddress::new("bot@example.com", None),
+            to: vec![EmailAddress::new("a@example.com", None)],
+            subject: subject.into(),
+            text: Some("body".into()),
+            ..EmailRequest::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_email_queue_holds_unsent_email() -> Result<(), Error> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("email_queue.json");
+        let queue = Arc::new(EmailQueue::new(10, Some(&path)));
+        assert!(queue.try_push(request("first")));
+        assert!(queue.try_push(request("second")));
+
+        // without an email backend the first email is held, not dropped
+        let config = Config::from(ConfigInner::default());
+        let reloader = ConfigReloader::new(
+            config,
+            Arc::new(ApiTokens::new(&ApiTokenConfig::default())),
+            Arc::new(FloodControl::new(20, Duration::from_secs(600))),
+        );
+        let sender = {
+            let queue = queue.clone();
+            tokio::spawn(async move { queue.send_all(&reloader).await })
+        };
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(queue.len(), 2);
+        assert!(!queue.wait_empty(Duration::from_millis(200)).await);
+        sender.abort();
+
+        assert_eq!(queue.persist().await?, 2);
+        assert!(queue.is_empty());
+
+        let restored = EmailQueue::new(10, Some(&path));
+        assert_eq!(restored.restore().await?, 2);
+        assert!(!path.exists());
+        let subjects: Vec<_> = std::iter::from_fn(|| restored.try_pop())
+            .map(|r| r.subject)
+            .collect();
+        assert_eq!(subjects, ["first", "second"]);
+        Ok(())
+    }
+}
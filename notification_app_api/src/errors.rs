@@ -7,7 +7,6 @@ use axum::{
     },
     response::{IntoResponse, Response},
 };
-use log::error;
 use serde::Serialize;
 use serde_json::Error as SerdeJsonError;
 use serde_yml::Error as YamlError;
@@ -15,6 +14,7 @@ use stack_string::{format_sstr, StackString};
 use std::{fmt::Debug, net::AddrParseError};
 use thiserror::Error;
 use tokio::task::JoinError;
+
 use utoipa::{
     openapi::{ContentBuilder, ResponseBuilder, ResponsesBuilder},
     IntoResponses, PartialSchema, ToSchema,
@@ -38,6 +38,10 @@ pub enum ServiceError {
     BadRequest(StackString),
     #[error("Unauthorized")]
     Unauthorized,
+    #[error("NotFound: {0}")]
+    NotFound(StackString),
+    #[error("Too Many Requests: {0}")]
+    TooManyRequests(StackString),
     #[error("SerdeJsonError {0}")]
     SerdeJsonError(#[from] SerdeJsonError),
     #[error("YamlError {0}")]
@@ -73,6 +77,18 @@ impl IntoResponse for ServiceError {
                 ErrorMessage { message },
             )
                 .into_response(),
+            Self::NotFound(message) => (
+                StatusCode::NOT_FOUND,
+                [(CONTENT_TYPE, mime::APPLICATION_JSON.essence_str())],
+                ErrorMessage { message },
+            )
+                .into_response(),
+            Self::TooManyRequests(message) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(CONTENT_TYPE, mime::APPLICATION_JSON.essence_str())],
+                ErrorMessage { message },
+            )
+                .into_response(),
             e => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 [(CONTENT_TYPE, mime::APPLICATION_JSON.essence_str())],
@@ -110,6 +126,22 @@ impl IntoResponses for ServiceError {
                     error_message_content.clone(),
                 ),
             )
+            .response(
+                StatusCode::NOT_FOUND.as_str(),
+                ResponseBuilder::new().description("Not Found").content(
+                    mime::APPLICATION_JSON.essence_str(),
+                    error_message_content.clone(),
+                ),
+            )
+            .response(
+                StatusCode::TOO_MANY_REQUESTS.as_str(),
+                ResponseBuilder::new()
+                    .description("Too Many Requests")
+                    .content(
+                        mime::APPLICATION_JSON.essence_str(),
+                        error_message_content.clone(),
+                    ),
+            )
             .response(
                 StatusCode::INTERNAL_SERVER_ERROR.as_str(),
                 ResponseBuilder::new()
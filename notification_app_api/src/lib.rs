@@ -8,14 +8,37 @@
 #![allow(clippy::similar_names)]
 
 pub mod app;
+pub mod cli;
+pub mod email_queue;
 pub mod errors;
+pub mod listener;
+pub mod multipart;
+pub mod reload;
 pub mod routes;
+pub mod systemd;
 
 use serde::{Deserialize, Serialize};
-use stack_string::StackString;
+use serde_json::{Map, Value};
+use stack_string::{format_sstr, StackString};
+use std::convert::TryFrom;
+use time::{format_description::well_known::Rfc3339, Duration, OffsetDateTime};
 use utoipa::ToSchema;
+use uuid::Uuid;
 
-use notification_app_lib::config::TelegramMessage;
+use notification_app_bot::{
+    flood_control::HeldMessage, health::HealthStatus, reminders::Reminder,
+    scheduler::ScheduledMessage,
+};
+use notification_app_lib::{
+    circuit_breaker::{CircuitState, CircuitStatus},
+    config::{MessageFormat, TelegramMessage},
+    ses_client::{EmailStats, SesQuotas},
+    structured::{MessageField, Severity, StructuredMessage},
+    suppression::SuppressionEntry,
+    templates::{Channel, Templates},
+};
+
+use crate::errors::ServiceError as Error;
 
 #[derive(Serialize, Deserialize, Default, Debug, ToSchema)]
 #[schema(as = TelegramMessage)]
@@ -40,14 +63,553 @@ impl From<TelegramMessageWrapper> for TelegramMessage {
         Self {
             recipient: item.recipient,
             message: item.message,
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, ToSchema)]
+#[schema(as = TemplateMessage)]
+pub struct TemplateMessageWrapper {
+    #[schema(inline)]
+    pub recipient: StackString,
+    #[schema(inline)]
+    pub template: StackString,
+    #[serde(default)]
+    #[schema(value_type = Object)]
+    pub vars: Map<String, Value>,
+}
+
+impl TemplateMessageWrapper {
+    /// # Errors
+    /// Return error if template is missing or fails to render
+    pub fn render(&self, templates: &Templates, channel: Channel) -> Result<StackString, Error> {
+        templates
+            .render(&self.template, channel, &self.vars)
+            .map_err(|e| Error::BadRequest(format_sstr!("{e}")))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[schema(as = Severity)]
+pub enum SeverityWrapper {
+    #[default]
+    Info,
+    Warning,
+    Error,
+    Critical,
+}
+
+impl From<SeverityWrapper> for Severity {
+    fn from(item: SeverityWrapper) -> Self {
+        match item {
+            SeverityWrapper::Info => Self::Info,
+            SeverityWrapper::Warning => Self::Warning,
+            SeverityWrapper::Error => Self::Error,
+            SeverityWrapper::Critical => Self::Critical,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[schema(as = MessageField)]
+pub struct MessageFieldWrapper {
+    #[schema(inline)]
+    pub name: StackString,
+    #[schema(inline)]
+    pub value: StackString,
+}
+
+impl From<MessageFieldWrapper> for MessageField {
+    fn from(item: MessageFieldWrapper) -> Self {
+        Self {
+            name: item.name,
+            value: item.value,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[schema(as = StructuredMessage)]
+pub struct StructuredMessageWrapper {
+    #[schema(inline)]
+    pub recipient: StackString,
+    #[schema(inline)]
+    pub title: StackString,
+    #[serde(default)]
+    pub severity: SeverityWrapper,
+    #[serde(default)]
+    pub fields: Vec<MessageFieldWrapper>,
+    #[schema(inline)]
+    pub url: Option<StackString>,
+    #[schema(inline)]
+    pub footer: Option<StackString>,
+}
+
+impl From<StructuredMessageWrapper> for StructuredMessage {
+    fn from(item: StructuredMessageWrapper) -> Self {
+        Self {
+            title: item.title,
+            severity: item.severity.into(),
+            fields: item.fields.into_iter().map(Into::into).collect(),
+            url: item.url,
+            footer: item.footer,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[serde(untagged)]
+pub enum NotifyPayload {
+    Message(TelegramMessageWrapper),
+    Template(TemplateMessageWrapper),
+    Structured(StructuredMessageWrapper),
+}
+
+impl NotifyPayload {
+    /// # Errors
+    /// Return error if a template payload fails to render
+    pub fn into_message(self, templates: &Templates) -> Result<TelegramMessage, Error> {
+        match self {
+            Self::Message(message) => Ok(message.into()),
+            Self::Template(template) => Ok(TelegramMessage {
+                message: template.render(templates, Channel::Telegram)?,
+                recipient: template.recipient,
+                ..TelegramMessage::default()
+            }),
+            Self::Structured(structured) => {
+                let recipient = structured.recipient.clone();
+                let structured: StructuredMessage = structured.into();
+                Ok(TelegramMessage {
+                    recipient,
+                    message: structured.telegram_html(),
+                    format: MessageFormat::Html,
+                    ..TelegramMessage::default()
+                })
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct NotifyRequest {
+    #[serde(flatten)]
+    pub payload: NotifyPayload,
+    /// Deliver at this time instead of immediately
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub send_at: Option<OffsetDateTime>,
+    /// Deliver after this many seconds instead of immediately
+    pub delay: Option<u64>,
+}
+
+impl NotifyRequest {
+    /// # Errors
+    /// Return error if both `send_at` and `delay` are set
+    pub fn send_at(&self) -> Result<Option<OffsetDateTime>, Error> {
+        match (self.send_at, self.delay) {
+            (Some(_), Some(_)) => Err(Error::BadRequest(
+                "Only one of send_at and delay may be set".into(),
+            )),
+            (Some(send_at), None) => Ok(Some(send_at)),
+            (None, Some(delay)) => {
+                let delay = i64::try_from(delay)
+                    .map_err(|_| Error::BadRequest(format_sstr!("Invalid delay {delay}")))?;
+                Ok(Some(OffsetDateTime::now_utc() + Duration::seconds(delay)))
+            }
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// Fields of the `multipart/form-data` body of `/notify/email`, only used
+/// to document the form
+#[allow(dead_code)]
+#[derive(ToSchema)]
+pub struct EmailForm {
+    /// Recipients, repeated or comma separated
+    #[schema(value_type = Vec<String>)]
+    to: Vec<StackString>,
+    #[schema(value_type = Option<Vec<String>>)]
+    cc: Option<Vec<StackString>>,
+    #[schema(value_type = Option<Vec<String>>)]
+    bcc: Option<Vec<StackString>>,
+    #[schema(value_type = Option<Vec<String>>)]
+    reply_to: Option<Vec<StackString>>,
+    #[schema(value_type = Option<String>)]
+    subject: Option<StackString>,
+    /// Plain text body, generated from `html` when missing
+    #[schema(value_type = Option<String>)]
+    text: Option<StackString>,
+    #[schema(value_type = Option<String>)]
+    html: Option<StackString>,
+    /// Template rendered into the html body, instead of `text` and `html`
+    #[schema(value_type = Option<String>)]
+    template: Option<StackString>,
+    /// Json object of template variables
+    #[schema(value_type = Option<String>)]
+    vars: Option<StackString>,
+    /// Title of a structured alert, rendered as html with a text fallback
+    /// instead of `text`, `html` and `template`
+    #[schema(value_type = Option<String>)]
+    title: Option<StackString>,
+    /// Structured alert severity: info, warning, error or critical
+    #[schema(value_type = Option<String>)]
+    severity: Option<StackString>,
+    /// Structured alert field as `Name: value`, may be repeated
+    #[schema(value_type = Option<Vec<String>>)]
+    field: Option<Vec<StackString>>,
+    /// Link shown in a structured alert
+    #[schema(value_type = Option<String>)]
+    url: Option<StackString>,
+    /// Footer of a structured alert
+    #[schema(value_type = Option<String>)]
+    footer: Option<StackString>,
+    /// Custom header as `Name: value`, may be repeated
+    #[schema(value_type = Option<Vec<String>>)]
+    header: Option<Vec<StackString>>,
+    /// File to attach, may be repeated
+    #[schema(value_type = Option<Vec<String>>, format = Binary)]
+    attachment: Option<Vec<Vec<u8>>>,
+    /// Image referenced from `html` as `cid:<file name>`, may be repeated
+    #[schema(value_type = Option<Vec<String>>, format = Binary)]
+    inline: Option<Vec<Vec<u8>>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[schema(as = ScheduledMessage)]
+pub struct ScheduledMessageWrapper {
+    pub id: Uuid,
+    #[serde(with = "time::serde::rfc3339")]
+    pub send_at: OffsetDateTime,
+    #[schema(inline)]
+    pub recipient: StackString,
+    #[schema(inline)]
+    pub message: StackString,
+}
+
+impl From<ScheduledMessage> for ScheduledMessageWrapper {
+    fn from(item: ScheduledMessage) -> Self {
+        Self {
+            id: item.id,
+            send_at: item.send_at,
+            recipient: item.message.recipient,
+            message: item.message.message,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[schema(as = ReminderRequest)]
+pub struct ReminderRequestWrapper {
+    #[schema(inline)]
+    pub recipient: StackString,
+    #[schema(inline)]
+    pub message: StackString,
+    /// Five field cron expression, e.g. `0 9 * * 1-5`
+    #[schema(inline)]
+    pub cron: StackString,
+    /// IANA timezone name, defaults to UTC
+    #[serde(default = "default_timezone")]
+    #[schema(inline)]
+    pub timezone: StackString,
+}
+
+fn default_timezone() -> StackString {
+    "UTC".into()
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[schema(as = Reminder)]
+pub struct ReminderWrapper {
+    pub id: Uuid,
+    #[schema(inline)]
+    pub recipient: StackString,
+    #[schema(inline)]
+    pub message: StackString,
+    #[schema(inline)]
+    pub cron: StackString,
+    #[schema(inline)]
+    pub timezone: StackString,
+    #[serde(with = "time::serde::rfc3339")]
+    pub next_run: OffsetDateTime,
+}
+
+impl From<Reminder> for ReminderWrapper {
+    fn from(item: Reminder) -> Self {
+        Self {
+            id: item.id,
+            recipient: item.recipient,
+            message: item.message,
+            cron: item.cron,
+            timezone: item.timezone,
+            next_run: item.next_run,
         }
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[schema(as = TemplatePreview)]
+pub struct TemplatePreviewWrapper {
+    #[schema(inline)]
+    pub telegram: StackString,
+    #[schema(inline)]
+    pub email: StackString,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[schema(as = HeldMessage)]
+pub struct HeldMessageWrapper {
+    #[schema(inline)]
+    pub recipient: StackString,
+    #[schema(inline)]
+    pub sender: Option<StackString>,
+    #[schema(inline)]
+    pub message: StackString,
+    #[serde(with = "time::serde::rfc3339")]
+    pub received: OffsetDateTime,
+}
+
+impl From<HeldMessage> for HeldMessageWrapper {
+    fn from(item: HeldMessage) -> Self {
+        Self {
+            recipient: item.recipient,
+            sender: item.sender,
+            message: item.message,
+            received: item.received,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[schema(as = SuppressionEntry)]
+pub struct SuppressionEntryWrapper {
+    #[schema(inline)]
+    pub address: StackString,
+    /// `bounce` or `complaint`
+    #[schema(inline)]
+    pub reason: StackString,
+    #[schema(inline)]
+    pub detail: Option<StackString>,
+    #[schema(inline)]
+    pub message_id: Option<StackString>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created: OffsetDateTime,
+}
+
+impl From<SuppressionEntry> for SuppressionEntryWrapper {
+    fn from(item: SuppressionEntry) -> Self {
+        Self {
+            address: item.address,
+            reason: item.reason.as_str().into(),
+            detail: item.detail,
+            message_id: item.message_id,
+            created: item.created,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[schema(as = HealthStatus)]
+pub struct HealthStatusWrapper {
+    pub healthy: bool,
+    pub ready: bool,
+    pub bot_running: bool,
+    pub bot_connected: bool,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_update_poll: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_send: Option<OffsetDateTime>,
+    pub token_file_loaded: bool,
+    #[schema(inline)]
+    pub token_file_error: Option<StackString>,
+    pub queue_depth: usize,
+    pub circuits: Vec<CircuitStatusWrapper>,
+}
+
+impl HealthStatusWrapper {
+    /// Delivery is only ready while no circuit required for readiness is open
+    #[must_use]
+    pub fn new(status: HealthStatus, queue_depth: usize, circuits: Vec<CircuitStatus>) -> Self {
+        let circuits_closed = circuits
+            .iter()
+            .filter(|c| c.required_for_ready)
+            .all(|c| c.state != CircuitState::Open);
+        Self {
+            healthy: status.is_healthy(),
+            ready: status.is_ready() && circuits_closed,
+            bot_running: status.bot_running,
+            bot_connected: status.bot_connected,
+            last_update_poll: status.last_update_poll,
+            last_send: status.last_send,
+            token_file_loaded: status.token_file_loaded,
+            token_file_error: status.token_file_error,
+            queue_depth,
+            circuits: circuits.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[schema(as = CircuitStatus)]
+pub struct CircuitStatusWrapper {
+    #[schema(inline)]
+    pub name: StackString,
+    /// One of `closed`, `open` or `half_open`
+    #[schema(inline)]
+    pub state: StackString,
+    /// Failed calls within the circuit window
+    pub failures: usize,
+    /// All calls within the circuit window
+    pub calls: usize,
+}
+
+impl From<CircuitStatus> for CircuitStatusWrapper {
+    fn from(item: CircuitStatus) -> Self {
+        Self {
+            name: item.name.into(),
+            state: item.state.as_str().into(),
+            failures: item.failures,
+            calls: item.calls,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[schema(as = SesQuotas)]
+pub struct SesQuotasWrapper {
+    pub max_24_hour_send: f64,
+    /// Emails per second
+    pub max_send_rate: f64,
+    pub sent_last_24_hours: f64,
+}
+
+impl From<SesQuotas> for SesQuotasWrapper {
+    fn from(item: SesQuotas) -> Self {
+        Self {
+            max_24_hour_send: item.max_24_hour_send,
+            max_send_rate: item.max_send_rate,
+            sent_last_24_hours: item.sent_last_24_hours,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[schema(as = EmailStats)]
+pub struct EmailStatsWrapper {
+    pub delivery_attempts: i64,
+    pub bounces: i64,
+    pub complaints: i64,
+    pub rejects: i64,
+    pub bounce_rate: f64,
+    pub complaint_rate: f64,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub min_timestamp: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub max_timestamp: Option<OffsetDateTime>,
+}
+
+impl From<EmailStats> for EmailStatsWrapper {
+    fn from(item: EmailStats) -> Self {
+        Self {
+            delivery_attempts: item.delivery_attempts,
+            bounces: item.bounces,
+            complaints: item.complaints,
+            rejects: item.rejects,
+            bounce_rate: item.bounce_rate(),
+            complaint_rate: item.complaint_rate(),
+            min_timestamp: item.min_timestamp,
+            max_timestamp: item.max_timestamp,
+        }
+    }
+}
+
+/// SES sending quota with the bounce, complaint and reject totals of the
+/// last two weeks
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[schema(as = SesStatistics)]
+pub struct SesStatisticsWrapper {
+    pub quota: SesQuotasWrapper,
+    pub stats: EmailStatsWrapper,
+    /// Bounce or complaint rates close to the SES suspension thresholds
+    #[schema(value_type = Vec<String>)]
+    pub warnings: Vec<StackString>,
+}
+
+impl SesStatisticsWrapper {
+    #[must_use]
+    pub fn new(quota: SesQuotas, stats: EmailStats) -> Self {
+        Self {
+            warnings: stats.warnings(),
+            quota: quota.into(),
+            stats: stats.into(),
+        }
+    }
+
+    /// Plain text table for terminals
+    #[must_use]
+    pub fn to_table(&self) -> StackString {
+        let timestamp = |t: Option<OffsetDateTime>| {
+            t.and_then(|t| t.format(&Rfc3339).ok())
+                .unwrap_or_else(|| "-".into())
+        };
+        let mut table = format_sstr!(
+            "max 24 hour send   {}\nmax send rate      {}/s\nsent last 24 hours {}\n\
+             delivery attempts  {}\nbounces            {} ({:.2}%)\n\
+             complaints         {} ({:.2}%)\nrejects            {}\nperiod             {} - {}\n",
+            self.quota.max_24_hour_send,
+            self.quota.max_send_rate,
+            self.quota.sent_last_24_hours,
+            self.stats.delivery_attempts,
+            self.stats.bounces,
+            self.stats.bounce_rate * 100.0,
+            self.stats.complaints,
+            self.stats.complaint_rate * 100.0,
+            self.stats.rejects,
+            timestamp(self.stats.min_timestamp),
+            timestamp(self.stats.max_timestamp),
+        );
+        for warning in &self.warnings {
+            table.push_str(&format_sstr!("warning: {warning}\n"));
+        }
+        table
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use notification_app_lib::ses_client::{EmailStats, SesQuotas};
+
+    use crate::SesStatisticsWrapper;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn test_ses_statistics() {
+        let quota = SesQuotas {
+            max_24_hour_send: 200.0,
+            max_send_rate: 1.0,
+            sent_last_24_hours: 12.0,
+        };
+        let stats = EmailStats {
+            delivery_attempts: 1000,
+            bounces: 20,
+            complaints: 2,
+            ..EmailStats::default()
+        };
+        let statistics = SesStatisticsWrapper::new(quota, stats);
+        assert_eq!(statistics.warnings.len(), 1);
+        assert!(statistics.warnings[0].starts_with("Complaint rate 0.20%"));
+        let table = statistics.to_table();
+        assert!(
+            table.contains("bounces            20 (2.00%)\n"),
+            "{}",
+            table
+        );
+        assert!(table.contains("period             - - -\n"), "{}", table);
+        assert!(table.ends_with("SES review threshold\n"), "{}", table);
+    }
 }
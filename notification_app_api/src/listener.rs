@@ -0,0 +1,380 @@
+use anyhow::{format_err, Error};
+use axum::serve::Listener;
+use log::{error, info};
+use std::{
+    convert::TryFrom,
+    fs::{self, DirBuilder, Permissions},
+    net::{IpAddr, SocketAddr},
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+use tokio::{
+    io,
+    net::{TcpListener, TcpStream, UnixListener},
+    sync::mpsc,
+    task::spawn,
+    time::{sleep, timeout, Duration},
+};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring::default_provider,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+use notification_app_lib::config::Config;
+
+use crate::systemd;
+
+/// Where `run_api` accepts connections
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddress {
+    Tcp {
+        addr: SocketAddr,
+        tls: Option<TlsFiles>,
+    },
+    Unix {
+        path: PathBuf,
+        mode: Option<u32>,
+    },
+    /// Socket passed by systemd socket activation
+    Systemd {
+        tls: Option<TlsFiles>,
+    },
+}
+
+impl ListenAddress {
+    /// Plain http on `host:port`
+    #[must_use]
+    pub fn tcp(host: IpAddr, port: u16) -> Self {
+        Self::Tcp {
+            addr: SocketAddr::new(host, port),
+            tls: None,
+        }
+    }
+
+    /// # Errors
+    /// Return error if `host` or `unix_socket_mode` cannot be parsed, the
+    /// port is out of range or only one of the tls paths is set
+    pub fn from_config(config: &Config) -> Result<Self, Error> {
+        let tls = match (&config.tls_cert_path, &config.tls_key_path) {
+            (Some(cert), Some(key)) => Some(TlsFiles {
+                cert: cert.clone(),
+                key: key.clone(),
+            }),
+            (None, None) => None,
+            _ => {
+                return Err(format_err!(
+                    "TLS_CERT_PATH and TLS_KEY_PATH must be set together"
+                ))
+            }
+        };
+        if systemd::listen_fds()? > 0 {
+            return Ok(Self::Systemd { tls });
+        }
+        if let Some(path) = &config.unix_socket_path {
+            let mode = config
+                .unix_socket_mode
+                .as_ref()
+                .map(|mode| {
+                    u32::from_str_radix(mode, 8)
+                        .map_err(|e| format_err!("Invalid UNIX_SOCKET_MODE {mode}: {e}"))
+                })
+                .transpose()?;
+            return Ok(Self::Unix {
+                path: path.clone(),
+                mode,
+            });
+        }
+        let host: IpAddr = config
+            .host
+            .parse()
+            .map_err(|e| format_err!("Invalid HOST {}: {e}", config.host))?;
+        let port = u16::try_from(config.port)?;
+        Ok(Self::Tcp {
+            addr: SocketAddr::new(host, port),
+            tls,
+        })
+    }
+}
+
+/// Bind a unix socket at `path`, replacing a stale socket but never any
+/// other kind of file. The socket is created in a private directory and only
+/// moved to `path` once it has `mode`, so it is never reachable with the
+/// permissions the umask would give it.
+/// # Errors
+/// Return error if `path` exists and is not a socket, or binding fails
+pub fn bind_unix(path: &Path, mode: Option<u32>) -> Result<UnixListener, Error> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            return Err(format_err!("{} exists and is not a socket", path.display()));
+        }
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| format_err!("Invalid socket path {}", path.display()))?;
+    let private = parent.join(format!(
+        ".{}.{}",
+        file_name.to_string_lossy(),
+        process::id()
+    ));
+    DirBuilder::new().mode(0o700).create(&private)?;
+    let result = (|| {
+        let tmp = private.join(file_name);
+        let listener = UnixListener::bind(&tmp)?;
+        if let Some(mode) = mode {
+            fs::set_permissions(&tmp, Permissions::from_mode(mode))?;
+        }
+        fs::rename(&tmp, path)?;
+        Ok(listener)
+    })();
+    if let Err(e) = fs::remove_dir_all(&private) {
+        error!("Failed to remove {} {e}", private.display());
+    }
+    result
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Builds a `TlsAcceptor` from pem files, rebuilding it whenever either file
+/// changes on disk
+pub struct TlsReloader {
+    files: TlsFiles,
+    modified: Option<(SystemTime, SystemTime)>,
+}
+
+impl TlsReloader {
+    #[must_use]
+    pub fn new(files: TlsFiles) -> Self {
+        Self {
+            files,
+            modified: None,
+        }
+    }
+
+    /// Returns a new acceptor if the cert or key changed since the last call
+    /// # Errors
+    /// Return error if the cert or key cannot be loaded
+    pub fn reload(&mut self) -> Result<Option<TlsAcceptor>, Error> {
+        let current = modified(&self.files.cert).zip(modified(&self.files.key));
+        if current.is_some() && current == self.modified {
+            return Ok(None);
+        }
+        let certs = CertificateDer::pem_file_iter(&self.files.cert)
+            .map_err(|e| format_err!("{}: {e}", self.files.cert.display()))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format_err!("{}: {e}", self.files.cert.display()))?;
+        let key = PrivateKeyDer::from_pem_file(&self.files.key)
+            .map_err(|e| format_err!("{}: {e}", self.files.key.display()))?;
+        let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        self.modified = current;
+        Ok(Some(TlsAcceptor::from(Arc::new(config))))
+    }
+}
+
+/// TCP listener that completes the TLS handshake on a separate task for each
+/// connection, so a slow client cannot stall `accept`
+pub struct TlsListener {
+    local_addr: SocketAddr,
+    incoming: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+}
+
+impl TlsListener {
+    /// # Errors
+    /// Return error if binding `addr` or loading the certificate fails
+    pub async fn bind(addr: SocketAddr, files: TlsFiles) -> Result<Self, Error> {
+        let listener = TcpListener::bind(addr).await?;
+        Self::new(listener, files)
+    }
+
+    /// # Errors
+    /// Return error if loading the certificate fails
+    pub fn new(listener: TcpListener, files: TlsFiles) -> Result<Self, Error> {
+        let mut reloader = TlsReloader::new(files);
+        let acceptor = reloader
+            .reload()?
+            .ok_or_else(|| format_err!("No TLS certificate"))?;
+        let acceptor = Arc::new(RwLock::new(acceptor));
+        let local_addr = listener.local_addr()?;
+        let (tx, incoming) = mpsc::channel(64);
+
+        {
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+            spawn(async move {
+                loop {
+                    tokio::select! {
+                        () = tx.closed() => return,
+                        () = sleep(Duration::from_secs(10)) => {},
+                    }
+                    match reloader.reload() {
+                        Ok(Some(new_acceptor)) => {
+                            info!("reloaded TLS certificate");
+                            if let Ok(mut acceptor) = acceptor.write() {
+                                *acceptor = new_acceptor;
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(e) => error!("Failed to reload TLS certificate {e}"),
+                    }
+                }
+            });
+        }
+
+        spawn(async move {
+            loop {
+                let (stream, addr) = tokio::select! {
+                    () = tx.closed() => return,
+                    result = listener.accept() => match result {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            error!("accept error {e}");
+                            sleep(Duration::from_secs(1)).await;
+                            continue;
+                        }
+                    },
+                };
+                let acceptor = match acceptor.read() {
+                    Ok(acceptor) => acceptor.clone(),
+                    Err(_) => return,
+                };
+                let tx = tx.clone();
+                spawn(async move {
+                    match timeout(Duration::from_secs(10), acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            tx.send((stream, addr)).await.ok();
+                        }
+                        Ok(Err(e)) => error!("TLS handshake with {addr} failed {e}"),
+                        Err(_) => error!("TLS handshake with {addr} timed out"),
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            local_addr,
+            incoming,
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.incoming.recv().await {
+            Some(accepted) => accepted,
+            // The accept task only exits once this receiver is dropped
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+    use std::{
+        net::IpAddr,
+        os::unix::fs::{FileTypeExt, PermissionsExt},
+        path::Path,
+    };
+    use tempfile::TempDir;
+
+    use crate::listener::{bind_unix, ListenAddress, TlsFiles, TlsReloader};
+
+    fn test_files() -> TlsFiles {
+        let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("../tests/data");
+        TlsFiles {
+            cert: data.join("test_tls_cert.pem"),
+            key: data.join("test_tls_key.pem"),
+        }
+    }
+
+    #[test]
+    fn test_listen_address() {
+        let host: IpAddr = "::".parse().unwrap();
+        assert_eq!(
+            ListenAddress::tcp(host, 4083),
+            ListenAddress::Tcp {
+                addr: "[::]:4083".parse().unwrap(),
+                tls: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_unix() -> Result<(), Error> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("notification.sock");
+
+        std::fs::write(&path, "not a socket")?;
+        assert!(bind_unix(&path, Some(0o660)).is_err());
+        assert_eq!(std::fs::read_to_string(&path)?, "not a socket");
+        std::fs::remove_file(&path)?;
+
+        let listener = bind_unix(&path, Some(0o660))?;
+        let metadata = std::fs::symlink_metadata(&path)?;
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o660);
+        drop(listener);
+
+        // a stale socket is replaced
+        let _listener = bind_unix(&path, Some(0o600))?;
+        assert_eq!(
+            std::fs::metadata(&path)?.permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tls_reloader() -> Result<(), Error> {
+        let dir = TempDir::new()?;
+        let files = TlsFiles {
+            cert: dir.path().join("cert.pem"),
+            key: dir.path().join("key.pem"),
+        };
+        let mut reloader = TlsReloader::new(files.clone());
+        assert!(reloader.reload().is_err());
+
+        std::fs::copy(test_files().cert, &files.cert)?;
+        std::fs::write(&files.key, "not a key")?;
+        assert!(reloader.reload().is_err());
+
+        std::fs::copy(test_files().key, &files.key)?;
+        assert!(reloader.reload()?.is_some());
+        assert!(reloader.reload()?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,441 @@
+use axum::extract::{multipart::MultipartRejection, Multipart};
+use mime::Mime;
+use stack_string::{format_sstr, StackString};
+
+use serde_json::{Map, Value};
+
+use notification_app_lib::{
+    config::EmailIdentity,
+    email::{EmailAddress, EmailRequest},
+    mime_message::{validate_header_value, Attachment},
+    structured::{MessageField, Severity, StructuredMessage},
+    templates::{Channel, Templates},
+};
+
+use crate::errors::ServiceError as Error;
+
+/// One field of a `multipart/form-data` body
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Part {
+    pub name: StackString,
+    pub filename: Option<StackString>,
+    pub content_type: Option<StackString>,
+    pub data: Vec<u8>,
+}
+
+impl Part {
+    fn text(&self) -> Result<&str, Error> {
+        std::str::from_utf8(&self.data)
+            .map_err(|_| Error::BadRequest(format_sstr!("Field {} is not utf-8", self.name)))
+    }
+}
+
+/// Read every field of a `multipart/form-data` body
+/// # Errors
+/// Return error if the request is not `multipart/form-data`, the body is
+/// malformed or a field has no name
+pub async fn read_parts(
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<Vec<Part>, Error> {
+    let mut multipart = multipart.map_err(|e| Error::BadRequest(format_sstr!("{e}")))?;
+    let malformed = |e| Error::BadRequest(format_sstr!("Malformed multipart body: {e}"));
+    let mut parts = Vec::new();
+    while let Some(field) = multipart.next_field().await.map_err(malformed)? {
+        let name: StackString = field
+            .name()
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| Error::BadRequest("Multipart field without a name".into()))?
+            .into();
+        let filename = field.file_name().map(Into::into);
+        let content_type = field.content_type().map(Into::into);
+        let data = field.bytes().await.map_err(malformed)?.to_vec();
+        parts.push(Part {
+            name,
+            filename,
+            content_type,
+            data,
+        });
+    }
+    Ok(parts)
+}
+
+fn addresses(part: &Part) -> Result<Vec<EmailAddress>, Error> {
+    part.text()?
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse().map_err(Into::into))
+        .collect()
+}
+
+/// The content type of the part is used when it parses, otherwise the one
+/// guessed from the file name
+fn attachment(part: Part) -> Result<Attachment, Error> {
+    let filename = part
+        .filename
+        .clone()
+        .filter(|f| !f.is_empty())
+        .ok_or_else(|| Error::BadRequest(format_sstr!("Field {} must be a file", part.name)))?;
+    validate_header_value("Content-Disposition", &filename)
+        .map_err(|e| Error::BadRequest(format_sstr!("{e}")))?;
+    let mut attachment = Attachment::new(filename, part.data);
+    let content_type = part
+        .content_type
+        .as_ref()
+        .filter(|c| validate_header_value("Content-Type", c).is_ok())
+        .and_then(|c| c.parse::<Mime>().ok());
+    if let Some(content_type) = content_type {
+        attachment.content_type = content_type.as_ref().into();
+    }
+    Ok(attachment)
+}
+
+/// Build an email from the fields of an `/notify/email` form. Address
+/// fields may be repeated or hold a comma separated list, `header` fields
+/// are `Name: value` and inline images are referenced as `cid:<file name>`.
+/// A `template` field renders the html body from `templates` with the json
+/// object in `vars`. A `title` field, with optional `severity`, `field`
+/// (`Name: value`, may be repeated), `url` and `footer`, renders a
+/// structured alert as html with a text fallback. The from address,
+/// display name and default subject come from `sender` where it sets them,
+/// taking the template into account.
+/// # Errors
+/// Return error on unknown fields, invalid addresses or headers, a template
+/// that fails to render, a structured alert combined with another body, or
+/// an invalid resulting email
+pub fn email_request(
+    parts: Vec<Part>,
+    from: EmailAddress,
+    templates: &Templates,
+    sender: &EmailIdentity,
+) -> Result<EmailRequest, Error> {
+    let mut request = EmailRequest {
+        from,
+        ..EmailRequest::default()
+    };
+    let mut vars = Map::new();
+    let mut structured: Option<StructuredMessage> = None;
+    for part in parts {
+        match part.name.as_str() {
+            "to" => request.to.extend(addresses(&part)?),
+            "cc" => request.cc.extend(addresses(&part)?),
+            "bcc" => request.bcc.extend(addresses(&part)?),
+            "reply_to" => request.reply_to.extend(addresses(&part)?),
+            "subject" => request.subject = part.text()?.into(),
+            "text" => request.text = Some(part.text()?.into()),
+            "html" => request.html = Some(part.text()?.into()),
+            "template" => request.template = Some(part.text()?.into()),
+            "vars" => {
+                vars = match serde_json::from_str(part.text()?) {
+                    Ok(Value::Object(object)) => object,
+                    _ => return Err(Error::BadRequest("vars must be a json object".into())),
+                };
+            }
+            "header" => {
+                let header = part.text()?;
+                let (name, value) = header.split_once(':').ok_or_else(|| {
+                    Error::BadRequest(format_sstr!("Expected Name: value, got {header}"))
+                })?;
+                request
+                    .headers
+                    .push((name.trim().into(), value.trim().into()));
+            }
+            "title" => structured.get_or_insert_with(Default::default).title = part.text()?.into(),
+            "severity" => {
+                let severity = part.text()?.to_ascii_lowercase();
+                structured.get_or_insert_with(Default::default).severity =
+                    serde_json::from_value::<Severity>(Value::String(severity)).map_err(|_| {
+                        Error::BadRequest(
+                            "severity must be info, warning, error or critical".into(),
+                        )
+                    })?;
+            }
+            "field" => {
+                let field = part.text()?;
+                let (name, value) = field.split_once(':').ok_or_else(|| {
+                    Error::BadRequest(format_sstr!("Expected Name: value, got {field}"))
+                })?;
+                structured
+                    .get_or_insert_with(Default::default)
+                    .fields
+                    .push(MessageField {
+                        name: name.trim().into(),
+                        value: value.trim().into(),
+                    });
+            }
+            "url" => {
+                structured.get_or_insert_with(Default::default).url = Some(part.text()?.into())
+            }
+            "footer" => {
+                structured.get_or_insert_with(Default::default).footer = Some(part.text()?.into());
+            }
+            "attachment" => request.attachments.push(attachment(part)?),
+            "inline" => {
+                let attachment = attachment(part)?;
+                let content_id = attachment.filename.clone();
+                request.attachments.push(attachment.inline(content_id));
+            }
+            name => {
+                return Err(Error::BadRequest(format_sstr!("Unknown field {name}")));
+            }
+        }
+    }
+    if let Some(template) = &request.template {
+        if request.text.is_some() || request.html.is_some() {
+            return Err(Error::BadRequest(
+                "template cannot be combined with text or html".into(),
+            ));
+        }
+        // the text part is generated from the html
+        let html = templates
+            .render(template, Channel::Email, &vars)
+            .map_err(|e| Error::BadRequest(format_sstr!("{e}")))?;
+        request.html = Some(html);
+    }
+    if let Some(structured) = structured {
+        if structured.title.is_empty() {
+            return Err(Error::BadRequest("A structured alert needs a title".into()));
+        }
+        if request.text.is_some() || request.html.is_some() || request.template.is_some() {
+            return Err(Error::BadRequest(
+                "A structured alert cannot be combined with text, html or template".into(),
+            ));
+        }
+        if request.subject.is_empty() {
+            request.subject = structured.subject();
+        }
+        request.html = Some(structured.email_html());
+        request.text = Some(structured.text());
+    }
+    let sender = sender.for_template(request.template.as_ref().map(StackString::as_str));
+    request.from = sender.sender(request.from)?;
+    if request.subject.is_empty() {
+        request.subject = sender
+            .subject
+            .unwrap_or_else(|| format_sstr!("Notification from {}", request.from.address));
+    }
+    request.validate()?;
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+    use axum::{
+        body::Body,
+        extract::{FromRequest, Multipart, Request},
+        http::header::CONTENT_TYPE,
+    };
+
+    use notification_app_lib::{config::EmailIdentity, templates::Templates};
+
+    use crate::multipart::{email_request, read_parts, Part};
+
+    async fn parse(body: &'static [u8], content_type: &str) -> Result<Vec<Part>, Error> {
+        let request = Request::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body))?;
+        let multipart = Multipart::from_request(request, &()).await;
+        read_parts(multipart).await.map_err(Into::into)
+    }
+
+    const BODY: &[u8] = b"preamble\r\n--XyZ\r\n\
+        Content-Disposition: form-data; name=\"to\"\r\n\r\n\
+        a@example.com, B <b@example.com>\r\n--XyZ\r\n\
+        Content-Disposition: form-data; name=\"subject\"\r\n\r\n\
+        Report\r\n--XyZ\r\n\
+        Content-Disposition: form-data; name=\"header\"\r\n\r\n\
+        X-Report-Id: 7\r\n--XyZ\r\n\
+        Content-Disposition: form-data; name=\"html\"\r\n\r\n\
+        <img src=\"cid:chart.png\">\r\n--XyZ\r\n\
+        Content-Disposition: form-data; name=\"inline\"; filename=\"chart.png\"\r\n\
+        Content-Type: image/png\r\n\r\n\
+        \x89PNG\r\n--XyZ\r\n\
+        content-disposition: form-data; name=\"attachment\"; filename=\"a \\\"b\\\"; c.csv\"\r\n\r\n\
+        x,y\r\n1,2\r\n--XyZ--\r\n";
+
+    #[tokio::test]
+    async fn test_parse() -> Result<(), Error> {
+        let parts = parse(BODY, "multipart/form-data; boundary=XyZ").await?;
+        assert_eq!(parts.len(), 6);
+        assert_eq!(parts[1].name, "subject");
+        assert_eq!(parts[1].data, b"Report");
+        assert_eq!(parts[4].filename.as_ref().unwrap(), "chart.png");
+        assert_eq!(parts[4].content_type.as_ref().unwrap(), "image/png");
+        assert_eq!(parts[4].data, b"\x89PNG");
+        assert_eq!(parts[5].filename.as_ref().unwrap(), "a \"b\"; c.csv");
+        assert_eq!(parts[5].data, b"x,y\r\n1,2");
+
+        assert!(parse(BODY, "application/json").await.is_err());
+        assert!(parse(BODY, "multipart/form-data").await.is_err());
+        let no_headers = b"--XyZ\r\nno headers";
+        assert!(parse(no_headers, "multipart/form-data; boundary=XyZ")
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_email_request() -> Result<(), Error> {
+        let templates = Templates::from_raw([("invoice.email", "<p>Total {{ total }}</p>")])?;
+        let sender = EmailIdentity::default();
+        let request = email_request(
+            parse(BODY, "multipart/form-data; boundary=XyZ").await?,
+            "bot@example.com".parse()?,
+            &templates,
+            &sender,
+        )?;
+        assert_eq!(request.to.len(), 2);
+        assert_eq!(request.to[1].name.as_ref().unwrap(), "B");
+        assert_eq!(request.subject, "Report");
+        assert_eq!(request.headers, [("X-Report-Id".into(), "7".into())]);
+        assert_eq!(request.attachments.len(), 2);
+        assert_eq!(
+            request.attachments[0].content_id.as_ref().unwrap(),
+            "chart.png"
+        );
+        assert_eq!(request.attachments[1].content_type, "text/csv");
+        assert!(request.is_raw());
+
+        let unknown = vec![Part {
+            name: "priority".into(),
+            data: b"high".to_vec(),
+            ..Part::default()
+        }];
+        assert!(email_request(unknown, "bot@example.com".parse()?, &templates, &sender).is_err());
+        let not_a_file = vec![Part {
+            name: "attachment".into(),
+            data: b"x".to_vec(),
+            ..Part::default()
+        }];
+        assert!(
+            email_request(not_a_file, "bot@example.com".parse()?, &templates, &sender).is_err()
+        );
+        let file = |name: &str, filename: &str, content_type: &str| Part {
+            name: name.into(),
+            filename: Some(filename.into()),
+            content_type: Some(content_type.into()),
+            data: b"x".to_vec(),
+        };
+        let to = Part {
+            name: "to".into(),
+            data: b"a@example.com".to_vec(),
+            ..Part::default()
+        };
+        let body = Part {
+            name: "text".into(),
+            data: b"hello".to_vec(),
+            ..Part::default()
+        };
+        let injected = vec![
+            to.clone(),
+            body.clone(),
+            file("attachment", "a.csv\r\nBcc: victim@example.com", "text/csv"),
+        ];
+        assert!(email_request(injected, "bot@example.com".parse()?, &templates, &sender).is_err());
+        let types = vec![
+            to,
+            body,
+            file("attachment", "a.csv", "text/csv\r\nBcc: victim@example.com"),
+            file("inline", "b.png", "not a type"),
+            file("attachment", "c.bin", "text/plain; charset=utf-8"),
+        ];
+        let request = email_request(types, "bot@example.com".parse()?, &templates, &sender)?;
+        let content_types: Vec<_> = request
+            .attachments
+            .iter()
+            .map(|a| a.content_type.as_str())
+            .collect();
+        assert_eq!(
+            content_types,
+            ["text/csv", "image/png", "text/plain; charset=utf-8"]
+        );
+
+        let field = |name: &str, data: &str| Part {
+            name: name.into(),
+            data: data.as_bytes().to_vec(),
+            ..Part::default()
+        };
+        let templated = vec![
+            field("to", "a@example.com"),
+            field("template", "invoice"),
+            field("vars", r#"{"total": "$5"}"#),
+        ];
+        let request = email_request(templated, "bot@example.com".parse()?, &templates, &sender)?;
+        assert_eq!(request.html.as_ref().unwrap(), "<p>Total $5</p>");
+        assert!(request.text.is_none());
+        assert_eq!(request.parts()?.0, "Total $5");
+        assert_eq!(request.template.as_ref().unwrap(), "invoice");
+        assert_eq!(request.from.address, "bot@example.com");
+        assert_eq!(request.subject, "Notification from bot@example.com");
+
+        let invoice = EmailIdentity {
+            subject: Some("Your invoice".into()),
+            ..EmailIdentity::default()
+        };
+        let billing = EmailIdentity {
+            address: Some("billing@example.com".into()),
+            name: Some("Billing".into()),
+            templates: vec![("invoice".into(), invoice)].into_iter().collect(),
+            ..EmailIdentity::default()
+        };
+        let templated = vec![
+            field("to", "a@example.com"),
+            field("template", "invoice"),
+            field("vars", r#"{"total": "$5"}"#),
+        ];
+        let request = email_request(templated, "bot@example.com".parse()?, &templates, &billing)?;
+        assert_eq!(
+            request.from.to_string(),
+            "\"Billing\" <billing@example.com>"
+        );
+        assert_eq!(request.subject, "Your invoice");
+        let plain = vec![field("to", "a@example.com"), field("text", "hi")];
+        let request = email_request(plain, "bot@example.com".parse()?, &templates, &billing)?;
+        assert_eq!(request.subject, "Notification from billing@example.com");
+        let both = vec![
+            field("to", "a@example.com"),
+            field("template", "invoice"),
+            field("text", "hello"),
+        ];
+        assert!(email_request(both, "bot@example.com".parse()?, &templates, &sender).is_err());
+        let bad_vars = vec![field("template", "invoice"), field("vars", "[1]")];
+        assert!(email_request(bad_vars, "bot@example.com".parse()?, &templates, &sender).is_err());
+
+        let alert = vec![
+            field("to", "a@example.com"),
+            field("title", "Disk full"),
+            field("severity", "Warning"),
+            field("field", "host: db1"),
+            field("footer", "cron"),
+        ];
+        let request = email_request(alert, "bot@example.com".parse()?, &templates, &sender)?;
+        assert_eq!(request.subject, "[WARNING] Disk full");
+        assert!(request
+            .html
+            .as_ref()
+            .unwrap()
+            .contains("<th align=\"left\">host</th><td>db1</td>"));
+        assert_eq!(
+            request.text.as_ref().unwrap(),
+            "[WARNING] Disk full\nhost: db1\n\ncron"
+        );
+        let mixed = vec![
+            field("to", "a@example.com"),
+            field("title", "Disk full"),
+            field("text", "hi"),
+        ];
+        assert!(email_request(mixed, "bot@example.com".parse()?, &templates, &sender).is_err());
+        let untitled = vec![field("to", "a@example.com"), field("severity", "error")];
+        assert!(email_request(untitled, "bot@example.com".parse()?, &templates, &sender).is_err());
+        let bad_severity = vec![field("title", "x"), field("severity", "loud")];
+        assert!(email_request(
+            bad_severity,
+            "bot@example.com".parse()?,
+            &templates,
+            &sender
+        )
+        .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,305 @@
+use anyhow::{format_err, Error};
+use log::{error, info};
+use stack_string::StackString;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, PoisonError, RwLock},
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+use notification_app_bot::flood_control::FloodControl;
+use notification_app_lib::{
+    circuit_breaker::CIRCUITS,
+    config::{ApiTokenConfig, Config, EmailIdentity},
+    email_sender::EmailSender,
+    templates::Templates,
+};
+
+#[derive(Default, Debug)]
+struct TokenNames {
+    names: HashMap<StackString, StackString>,
+    admins: HashSet<StackString>,
+    email_senders: HashMap<StackString, EmailIdentity>,
+}
+
+/// Map of api token to entry name, replaced when the token file is reloaded
+#[derive(Default, Debug)]
+pub struct ApiTokens(RwLock<Arc<TokenNames>>);
+
+impl ApiTokens {
+    #[must_use]
+    pub fn new(config: &ApiTokenConfig) -> Self {
+        let tokens = Self::default();
+        tokens.replace(config);
+        tokens
+    }
+
+    fn current(&self) -> Arc<TokenNames> {
+        self.0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub fn replace(&self, config: &ApiTokenConfig) {
+        let names = TokenNames {
+            names: config.api_token_names(),
+            admins: config.admin_names(),
+            email_senders: config.email_senders(),
+        };
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(names);
+    }
+
+    /// Name of the entry owning `token`
+    #[must_use]
+    pub fn get(&self, token: &str) -> Option<StackString> {
+        self.current().names.get(token).cloned()
+    }
+
+    /// Email sender settings of the entry `name`
+    #[must_use]
+    pub fn email_sender(&self, name: &str) -> Option<EmailIdentity> {
+        self.current().email_senders.get(name).cloned()
+    }
+
+    #[must_use]
+    pub fn is_admin(&self, token: &str) -> bool {
+        let current = self.current();
+        current
+            .names
+            .get(token)
+            .is_some_and(|name| current.admins.contains(name))
+    }
+}
+
+impl From<HashMap<StackString, StackString>> for ApiTokens {
+    fn from(names: HashMap<StackString, StackString>) -> Self {
+        Self(RwLock::new(Arc::new(TokenNames {
+            names,
+            ..TokenNames::default()
+        })))
+    }
+}
+
+/// Holds the live configuration and applies a new one to every component
+/// that can change without a restart
+pub struct ConfigReloader {
+    config: RwLock<Config>,
+    api_tokens: Arc<ApiTokens>,
+    flood: Arc<FloodControl>,
+    templates: RwLock<Templates>,
+    email: RwLock<Option<EmailSender>>,
+    bot_restart: Notify,
+}
+
+impl ConfigReloader {
+    #[must_use]
+    pub fn new(config: Config, api_tokens: Arc<ApiTokens>, flood: Arc<FloodControl>) -> Self {
+        Self {
+            config: RwLock::new(config),
+            api_tokens,
+            flood,
+            templates: RwLock::new(Templates::default()),
+            email: RwLock::new(None),
+            bot_restart: Notify::new(),
+        }
+    }
+
+    #[must_use]
+    pub fn with_templates(self, templates: Templates) -> Self {
+        *self
+            .templates
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = templates;
+        self
+    }
+
+    #[must_use]
+    pub fn with_email(self, email: Option<EmailSender>) -> Self {
+        *self.email.write().unwrap_or_else(PoisonError::into_inner) = email;
+        self
+    }
+
+    /// The configuration currently in use
+    #[must_use]
+    pub fn config(&self) -> Config {
+        self.config
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// The templates loaded from `TEMPLATES_PATH`
+    #[must_use]
+    pub fn templates(&self) -> Templates {
+        self.templates
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// The email backend built from the current configuration, a send in
+    /// progress keeps the sender it started with
+    #[must_use]
+    pub fn email(&self) -> Option<EmailSender> {
+        self.email
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Resolves when the bot has to be restarted to pick up a new
+    /// configuration
+    pub async fn bot_restart(&self) {
+        self.bot_restart.notified().await;
+    }
+
+    /// Re-read the env file and the token file, the running configuration is
+    /// only replaced if both are valid
+    /// # Errors
+    /// Return error if the new configuration is invalid
+    pub async fn reload(&self) -> Result<(), Error> {
+        let config = self.config().reload()?;
+        self.apply(config).await
+    }
+
+    /// Sender addresses in the token file are only checked for syntax here,
+    /// SES identities are checked at startup. The email backend and the
+    /// templates are rebuilt from `config`
+    /// # Errors
+    /// Return error if `config` is invalid, its token file cannot be read,
+    /// its templates fail to parse or its email backend cannot be built
+    pub async fn apply(&self, config: Config) -> Result<(), Error> {
+        config.validate()?;
+        let api_tokens_path = config
+            .api_tokens_path
+            .as_ref()
+            .ok_or_else(|| format_err!("No api token path set"))?;
+        let token_config = ApiTokenConfig::new(api_tokens_path).await?;
+        token_config.validate_senders(None)?;
+        let templates = match &config.templates_path {
+            Some(templates_path) => Templates::new(templates_path).await?,
+            None => Templates::default(),
+        };
+        let email = EmailSender::from_config(&config).await?;
+        email.validate_senders(&token_config).await?;
+
+        let old = self.config();
+        self.api_tokens.replace(&token_config);
+        *self
+            .templates
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = templates;
+        *self.email.write().unwrap_or_else(PoisonError::into_inner) = Some(email);
+        self.flood.set_limits(
+            config.flood_threshold,
+            Duration::from_secs(config.flood_window_minutes * 60),
+        );
+        CIRCUITS.configure((&config).into());
+
+        if old.host != config.host
+            || old.port != config.port
+            || old.tls_cert_path != config.tls_cert_path
+            || old.tls_key_path != config.tls_key_path
+            || old.unix_socket_path != config.unix_socket_path
+        {
+            error!("Listen address changes only take effect after a restart");
+        }
+        let restart_bot = old.telegram_bot_token != config.telegram_bot_token
+            || old.api_tokens_path != config.api_tokens_path
+            || old.crash_loop_threshold != config.crash_loop_threshold
+            || old.crash_loop_window_minutes != config.crash_loop_window_minutes;
+
+        *self.config.write().unwrap_or_else(PoisonError::into_inner) = config;
+        if restart_bot {
+            self.bot_restart.notify_one();
+        }
+        info!("configuration reloaded");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+    use std::{sync::Arc, time::Duration};
+    use tempfile::{NamedTempFile, TempDir};
+
+    use notification_app_bot::flood_control::FloodControl;
+    use notification_app_lib::{
+        config::{ApiTokenConfig, Config, ConfigInner},
+        email_sender::EmailBackend,
+        templates::Channel,
+    };
+
+    use crate::reload::{ApiTokens, ConfigReloader};
+
+    fn test_config(api_tokens_path: &std::path::Path, bot_token: &str) -> Config {
+        ConfigInner {
+            telegram_bot_token: Some(bot_token.into()),
+            api_tokens_path: Some(api_tokens_path.into()),
+            host: "127.0.0.1".into(),
+            port: 4083,
+            flood_threshold: 20,
+            flood_window_minutes: 10,
+            circuit_failure_threshold: 5,
+            circuit_failure_rate: 0.5,
+            circuit_window_seconds: 60,
+            circuit_open_seconds: 30,
+            email_backend: Some("smtp".into()),
+            smtp_host: Some("localhost".into()),
+            ..ConfigInner::default()
+        }
+        .into()
+    }
+
+    #[tokio::test]
+    async fn test_config_reloader() -> Result<(), Error> {
+        let tokens_file = NamedTempFile::new()?;
+        std::fs::write(tokens_file.path(), "[user]\napi_token = \"old\"\n")?;
+        let api_tokens = Arc::new(ApiTokens::new(
+            &ApiTokenConfig::new(tokens_file.path()).await?,
+        ));
+        let flood = Arc::new(FloodControl::new(20, Duration::from_secs(600)));
+        let reloader = ConfigReloader::new(
+            test_config(tokens_file.path(), "bot"),
+            api_tokens.clone(),
+            flood,
+        );
+        assert_eq!(api_tokens.get("old").unwrap(), "user");
+        assert!(!api_tokens.is_admin("old"));
+        assert!(reloader.email().is_none());
+
+        std::fs::write(
+            tokens_file.path(),
+            "[user]\napi_token = \"new\"\n[ops]\napi_token = \"admin\"\nadmin = true\n",
+        )?;
+        let mut invalid = ConfigInner::clone(&test_config(tokens_file.path(), "bot"));
+        invalid.port = 0;
+        assert!(reloader.apply(invalid.into()).await.is_err());
+        assert_eq!(api_tokens.get("old").unwrap(), "user");
+
+        let templates_dir = TempDir::new()?;
+        std::fs::write(templates_dir.path().join("greeting.tera"), "hi {{ name }}")?;
+        let mut config = ConfigInner::clone(&test_config(tokens_file.path(), "new bot"));
+        config.templates_path = Some(templates_dir.path().into());
+        reloader.apply(config.into()).await?;
+        assert_eq!(reloader.email().unwrap().backend(), EmailBackend::Smtp);
+        let mut vars = serde_json::Map::new();
+        vars.insert("name".into(), "user".into());
+        let greeting = reloader
+            .templates()
+            .render("greeting", Channel::Telegram, &vars)?;
+        assert_eq!(greeting, "hi user");
+        assert!(api_tokens.get("old").is_none());
+        assert_eq!(api_tokens.get("new").unwrap(), "user");
+        assert!(api_tokens.is_admin("admin"));
+        assert_eq!(
+            reloader.config().telegram_bot_token.as_ref().unwrap(),
+            "new bot"
+        );
+        tokio::time::timeout(Duration::from_secs(1), reloader.bot_restart()).await?;
+        Ok(())
+    }
+}
@@ -1,21 +1,50 @@
 use axum::{
-    extract::{FromRequestParts, Json, State},
-    http::{header::AUTHORIZATION, request::Parts},
+    body::Bytes,
+    extract::{
+        multipart::MultipartRejection, DefaultBodyLimit, FromRequestParts, Json, Multipart, Path,
+        State,
+    },
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        request::Parts,
+        StatusCode,
+    },
+    response::IntoResponse,
 };
-use stack_string::StackString;
-use std::{str::FromStr, sync::Arc};
+use stack_string::{format_sstr, StackString};
+use std::{convert::TryFrom, str::FromStr, sync::Arc, time::Instant};
+use time::OffsetDateTime;
 use utoipa::{OpenApi, PartialSchema};
 use utoipa_axum::{router::OpenApiRouter, routes};
-use utoipa_helper::{html_response::HtmlResponse as HtmlBase, UtoipaResponse};
+use utoipa_helper::{
+    html_response::HtmlResponse as HtmlBase, json_response::JsonResponse as JsonBase,
+    UtoipaResponse,
+};
+use uuid::Uuid;
+
+use notification_app_bot::health::BOT_HEALTH;
+use notification_app_lib::{
+    circuit_breaker::CIRCUITS,
+    metrics,
+    sns::{SnsMessage, SnsMessageType},
+    suppression::SesNotification,
+    templates::Channel,
+};
 
-use crate::{app::AppState, errors::ServiceError as Error, TelegramMessageWrapper};
+use crate::{
+    app::AppState, errors::ServiceError as Error, multipart, CircuitStatusWrapper, EmailForm,
+    HealthStatusWrapper, HeldMessageWrapper, NotifyRequest, ReminderRequestWrapper,
+    ReminderWrapper, ScheduledMessageWrapper, SesStatisticsWrapper, StructuredMessageWrapper,
+    SuppressionEntryWrapper, TelegramMessageWrapper, TemplateMessageWrapper,
+    TemplatePreviewWrapper,
+};
 
 type WarpResult<T> = Result<T, Error>;
 
 #[derive(UtoipaResponse)]
 #[response(description = "Send Notification", status = "CREATED")]
 #[rustfmt::skip]
-struct NotifyResponse(HtmlBase::<&'static str>);
+struct NotifyResponse(HtmlBase::<String>);
 
 #[utoipa::path(
     post,
@@ -23,29 +52,650 @@ struct NotifyResponse(HtmlBase::<&'static str>);
     params(
         ("authorization" = inline(StackString), Header, description = "Bearer Authorization"),
     ),
-    request_body = TelegramMessageWrapper,
+    request_body = NotifyRequest,
     responses(NotifyResponse, Error),
 )]
 async fn notify_telegram(
     data: State<Arc<AppState>>,
     credentials: BearerAuth,
-    payload: Json<TelegramMessageWrapper>,
+    payload: Json<NotifyRequest>,
 ) -> WarpResult<NotifyResponse> {
-    if data.api_tokens.contains(credentials.token()) {
-        let Json(payload) = payload;
-        data.queue.push(payload.into());
-        Ok(HtmlBase::new("message sent").into())
+    if let Some(name) = data.api_tokens.get(credentials.token()) {
+        let Json(request) = payload;
+        let send_at = request.send_at()?;
+        let mut message = request.payload.into_message(&data.reloader.templates())?;
+        message.sender = Some(name.clone());
+        metrics::MESSAGES_ACCEPTED
+            .with_label_values(&[Channel::Telegram.as_str(), name.as_str()])
+            .inc();
+        if let Some(send_at) = send_at {
+            let id = data.scheduler.schedule(message, send_at).await?;
+            Ok(HtmlBase::new(format!("message scheduled {id}")).into())
+        } else {
+            message.queued = Some(Instant::now());
+            data.queue.push(message);
+            Ok(HtmlBase::new("message sent".into()).into())
+        }
     } else {
         Err(Error::Unauthorized)
     }
 }
 
-pub fn notify_telegram_router(app: &AppState) -> OpenApiRouter {
-    let app = Arc::new(app.clone());
+/// Largest `/notify/email` body, attachments included
+const MAX_EMAIL_BODY: usize = 10 * 1024 * 1024;
+
+#[derive(UtoipaResponse)]
+#[response(description = "Email Queued", status = "202")]
+#[rustfmt::skip]
+struct EmailResponse(HtmlBase::<String>);
+
+#[utoipa::path(
+    post,
+    path = "/notify/email",
+    params(
+        ("authorization" = inline(StackString), Header, description = "Bearer Authorization"),
+    ),
+    request_body(content = EmailForm, content_type = "multipart/form-data"),
+    responses(EmailResponse, Error),
+)]
+async fn notify_email(
+    data: State<Arc<AppState>>,
+    credentials: BearerAuth,
+    form: Result<Multipart, MultipartRejection>,
+) -> WarpResult<EmailResponse> {
+    let name = data
+        .api_tokens
+        .get(credentials.token())
+        .ok_or(Error::Unauthorized)?;
+    let parts = multipart::read_parts(form).await?;
+    let from = data
+        .reloader
+        .config()
+        .sender()
+        .map_err(|e| Error::BadRequest(format_sstr!("{e}")))?;
+    let sender = data.api_tokens.email_sender(&name).unwrap_or_default();
+    let mut request = multipart::email_request(parts, from, &data.reloader.templates(), &sender)?;
+    request.token = Some(name.clone());
+    request.queued = Some(Instant::now());
+    let suppressed: Vec<_> = data
+        .suppression
+        .filter(&mut request)
+        .await
+        .into_iter()
+        .map(|address| address.address)
+        .collect();
+    if request.recipient_count() == 0 {
+        let message = format_sstr!("Every recipient is suppressed: {}", suppressed.join(", "));
+        return Err(Error::BadRequest(message));
+    }
+    let Some(email) = data.reloader.email() else {
+        return Err(Error::BadRequest("Email is not configured".into()));
+    };
+    email
+        .check_quota(&request)
+        .map_err(|e| Error::TooManyRequests(format_sstr!("{e}")))?;
+    // sent in the background, waiting for SES quota there
+    if !data.email_queue.try_push(request) {
+        return Err(Error::TooManyRequests("Email queue is full".into()));
+    }
+    metrics::MESSAGES_ACCEPTED
+        .with_label_values(&[Channel::Email.as_str(), name.as_str()])
+        .inc();
+    if suppressed.is_empty() {
+        Ok(HtmlBase::new("email queued".into()).into())
+    } else {
+        let message = format!("email queued, skipped suppressed {}", suppressed.join(", "));
+        Ok(HtmlBase::new(message).into())
+    }
+}
+
+#[derive(UtoipaResponse)]
+#[response(description = "SNS Message Handled")]
+#[rustfmt::skip]
+struct SnsResponse(HtmlBase::<&'static str>);
+
+/// Receives SES bounce and complaint notifications from the SNS topics in
+/// `SNS_TOPIC_ARNS`, authenticated by the SNS message signature
+#[utoipa::path(
+    post,
+    path = "/notify/sns",
+    request_body(content = String, content_type = "text/plain", description = "SNS message"),
+    responses(SnsResponse, Error),
+)]
+async fn sns_notification(data: State<Arc<AppState>>, body: Bytes) -> WarpResult<SnsResponse> {
+    let bad_request = |e: anyhow::Error| Error::BadRequest(format_sstr!("{e}"));
+    let message = SnsMessage::from_json(&body).map_err(bad_request)?;
+    data.sns.verify(&message).await.map_err(bad_request)?;
+    let response = match message.message_type {
+        SnsMessageType::SubscriptionConfirmation => {
+            data.sns.confirm_subscription(&message).await?;
+            "subscription confirmed"
+        }
+        SnsMessageType::Notification => {
+            let notification = SesNotification::from_json(&message.message).map_err(bad_request)?;
+            let entries = notification.suppressions(OffsetDateTime::now_utc());
+            data.suppression.add(entries).await?;
+            "notification recorded"
+        }
+        SnsMessageType::UnsubscribeConfirmation => "unsubscribed",
+    };
+    Ok(HtmlBase::new(response).into())
+}
+
+#[derive(UtoipaResponse)]
+#[response(description = "SES Statistics", content = "application/json")]
+#[rustfmt::skip]
+struct EmailStatsResponse(JsonBase::<SesStatisticsWrapper>);
+
+#[utoipa::path(
+    get,
+    path = "/notify/email/stats",
+    params(
+        ("authorization" = inline(StackString), Header, description = "Bearer Authorization"),
+    ),
+    responses(EmailStatsResponse, Error),
+)]
+async fn email_stats(
+    data: State<Arc<AppState>>,
+    credentials: BearerAuth,
+) -> WarpResult<EmailStatsResponse> {
+    if data.api_tokens.get(credentials.token()).is_none() {
+        return Err(Error::Unauthorized);
+    }
+    let ses = data
+        .reloader
+        .email()
+        .and_then(|email| email.ses().cloned())
+        .ok_or_else(|| Error::BadRequest("Statistics are only available from SES".into()))?;
+    let (quota, stats) = ses.get_statistics().await?;
+    Ok(JsonBase::new(SesStatisticsWrapper::new(quota, stats)).into())
+}
+
+#[derive(UtoipaResponse)]
+#[response(description = "Scheduled Messages", content = "application/json")]
+#[rustfmt::skip]
+struct ScheduledResponse(JsonBase::<Vec<ScheduledMessageWrapper>>);
+
+#[utoipa::path(
+    get,
+    path = "/notify/scheduled",
+    params(
+        ("authorization" = inline(StackString), Header, description = "Bearer Authorization"),
+    ),
+    responses(ScheduledResponse, Error),
+)]
+async fn scheduled_messages(
+    data: State<Arc<AppState>>,
+    credentials: BearerAuth,
+) -> WarpResult<ScheduledResponse> {
+    let name = data
+        .api_tokens
+        .get(credentials.token())
+        .ok_or(Error::Unauthorized)?;
+    let scheduled = data
+        .scheduler
+        .list(&name)
+        .await
+        .into_iter()
+        .map(Into::into)
+        .collect();
+    Ok(JsonBase::new(scheduled).into())
+}
+
+#[derive(UtoipaResponse)]
+#[response(description = "Cancelled", status = "NO_CONTENT")]
+#[rustfmt::skip]
+struct CancelResponse(HtmlBase::<&'static str>);
+
+#[utoipa::path(
+    delete,
+    path = "/notify/scheduled/{id}",
+    params(
+        ("authorization" = inline(StackString), Header, description = "Bearer Authorization"),
+        ("id" = Uuid, Path, description = "Scheduled Message ID"),
+    ),
+    responses(CancelResponse, Error),
+)]
+async fn cancel_scheduled_message(
+    data: State<Arc<AppState>>,
+    credentials: BearerAuth,
+    id: Path<Uuid>,
+) -> WarpResult<CancelResponse> {
+    let name = data
+        .api_tokens
+        .get(credentials.token())
+        .ok_or(Error::Unauthorized)?;
+    let Path(id) = id;
+    if data.scheduler.cancel(id, &name).await? {
+        Ok(HtmlBase::new("").into())
+    } else {
+        Err(Error::NotFound(format_sstr!(
+            "Scheduled message {id} not found"
+        )))
+    }
+}
+
+#[derive(UtoipaResponse)]
+#[response(description = "Rendered Template", content = "application/json")]
+#[rustfmt::skip]
+struct PreviewResponse(JsonBase::<TemplatePreviewWrapper>);
+
+#[utoipa::path(
+    post,
+    path = "/notify/preview",
+    params(
+        ("authorization" = inline(StackString), Header, description = "Bearer Authorization"),
+    ),
+    request_body = TemplateMessageWrapper,
+    responses(PreviewResponse, Error),
+)]
+async fn preview_template(
+    data: State<Arc<AppState>>,
+    credentials: BearerAuth,
+    payload: Json<TemplateMessageWrapper>,
+) -> WarpResult<PreviewResponse> {
+    if data.api_tokens.get(credentials.token()).is_none() {
+        return Err(Error::Unauthorized);
+    }
+    let Json(payload) = payload;
+    let templates = data.reloader.templates();
+    let preview = TemplatePreviewWrapper {
+        telegram: payload.render(&templates, Channel::Telegram)?,
+        email: payload.render(&templates, Channel::Email)?,
+    };
+    Ok(JsonBase::new(preview).into())
+}
+
+#[derive(UtoipaResponse)]
+#[response(description = "Reminder Created", content = "application/json", status = "CREATED")]
+#[rustfmt::skip]
+struct CreateReminderResponse(JsonBase::<ReminderWrapper>);
+
+#[utoipa::path(
+    post,
+    path = "/notify/reminders",
+    params(
+        ("authorization" = inline(StackString), Header, description = "Bearer Authorization"),
+    ),
+    request_body = ReminderRequestWrapper,
+    responses(CreateReminderResponse, Error),
+)]
+async fn create_reminder(
+    data: State<Arc<AppState>>,
+    credentials: BearerAuth,
+    payload: Json<ReminderRequestWrapper>,
+) -> WarpResult<CreateReminderResponse> {
+    let name = data
+        .api_tokens
+        .get(credentials.token())
+        .ok_or(Error::Unauthorized)?;
+    let Json(payload) = payload;
+    let reminder = data
+        .reminders
+        .create(
+            &name,
+            &payload.recipient,
+            &payload.message,
+            &payload.cron,
+            &payload.timezone,
+        )
+        .await
+        .map_err(|e| Error::BadRequest(format_sstr!("{e}")))?;
+    Ok(JsonBase::new(reminder.into()).into())
+}
+
+#[derive(UtoipaResponse)]
+#[response(description = "Reminders", content = "application/json")]
+#[rustfmt::skip]
+struct RemindersResponse(JsonBase::<Vec<ReminderWrapper>>);
+
+#[utoipa::path(
+    get,
+    path = "/notify/reminders",
+    params(
+        ("authorization" = inline(StackString), Header, description = "Bearer Authorization"),
+    ),
+    responses(RemindersResponse, Error),
+)]
+async fn list_reminders(
+    data: State<Arc<AppState>>,
+    credentials: BearerAuth,
+) -> WarpResult<RemindersResponse> {
+    let name = data
+        .api_tokens
+        .get(credentials.token())
+        .ok_or(Error::Unauthorized)?;
+    let reminders = data
+        .reminders
+        .list(&name)
+        .await
+        .into_iter()
+        .map(Into::into)
+        .collect();
+    Ok(JsonBase::new(reminders).into())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/notify/reminders/{id}",
+    params(
+        ("authorization" = inline(StackString), Header, description = "Bearer Authorization"),
+        ("id" = Uuid, Path, description = "Reminder ID"),
+    ),
+    responses(CancelResponse, Error),
+)]
+async fn delete_reminder(
+    data: State<Arc<AppState>>,
+    credentials: BearerAuth,
+    id: Path<Uuid>,
+) -> WarpResult<CancelResponse> {
+    let name = data
+        .api_tokens
+        .get(credentials.token())
+        .ok_or(Error::Unauthorized)?;
+    let Path(id) = id;
+    if data.reminders.delete(id, &name).await? {
+        Ok(HtmlBase::new("").into())
+    } else {
+        Err(Error::NotFound(format_sstr!("Reminder {id} not found")))
+    }
+}
+
+/// Admins act on every held message, other tokens only on the messages they
+/// sent
+fn held_sender(data: &AppState, token: &str) -> Result<Option<StackString>, Error> {
+    let name = data.api_tokens.get(token).ok_or(Error::Unauthorized)?;
+    if data.api_tokens.is_admin(token) {
+        Ok(None)
+    } else {
+        Ok(Some(name))
+    }
+}
+
+#[derive(UtoipaResponse)]
+#[response(description = "Held Messages", content = "application/json")]
+#[rustfmt::skip]
+struct HeldResponse(JsonBase::<Vec<HeldMessageWrapper>>);
+
+#[utoipa::path(
+    get,
+    path = "/notify/held",
+    params(
+        ("authorization" = inline(StackString), Header, description = "Bearer Authorization"),
+    ),
+    responses(HeldResponse, Error),
+)]
+async fn held_messages(
+    data: State<Arc<AppState>>,
+    credentials: BearerAuth,
+) -> WarpResult<HeldResponse> {
+    let sender = held_sender(&data, credentials.token())?;
+    let held = data
+        .flood
+        .held_messages(sender.as_ref().map(StackString::as_str))
+        .await
+        .into_iter()
+        .map(Into::into)
+        .collect();
+    Ok(JsonBase::new(held).into())
+}
+
+#[derive(UtoipaResponse)]
+#[response(description = "Held Messages Released")]
+#[rustfmt::skip]
+struct ReleaseHeldResponse(HtmlBase::<String>);
+
+#[utoipa::path(
+    post,
+    path = "/notify/held/release",
+    params(
+        ("authorization" = inline(StackString), Header, description = "Bearer Authorization"),
+    ),
+    responses(ReleaseHeldResponse, Error),
+)]
+async fn release_held_messages(
+    data: State<Arc<AppState>>,
+    credentials: BearerAuth,
+) -> WarpResult<ReleaseHeldResponse> {
+    let sender = held_sender(&data, credentials.token())?;
+    let held = data
+        .flood
+        .take_held(sender.as_ref().map(StackString::as_str))
+        .await;
+    let count = held.len();
+    for held in held {
+        let mut message = held.into_message();
+        message.queued = Some(Instant::now());
+        data.queue.push(message);
+    }
+    Ok(HtmlBase::new(format!("released {count} held messages")).into())
+}
+
+#[derive(UtoipaResponse)]
+#[response(description = "Held Messages Cleared")]
+#[rustfmt::skip]
+struct ClearHeldResponse(HtmlBase::<String>);
+
+#[utoipa::path(
+    delete,
+    path = "/notify/held",
+    params(
+        ("authorization" = inline(StackString), Header, description = "Bearer Authorization"),
+    ),
+    responses(ClearHeldResponse, Error),
+)]
+async fn clear_held_messages(
+    data: State<Arc<AppState>>,
+    credentials: BearerAuth,
+) -> WarpResult<ClearHeldResponse> {
+    let sender = held_sender(&data, credentials.token())?;
+    let count = data
+        .flood
+        .take_held(sender.as_ref().map(StackString::as_str))
+        .await
+        .len();
+    Ok(HtmlBase::new(format!("cleared {count} held messages")).into())
+}
+
+#[derive(UtoipaResponse)]
+#[response(description = "Configuration Reloaded")]
+#[rustfmt::skip]
+struct ReloadResponse(HtmlBase::<&'static str>);
+
+#[utoipa::path(
+    post,
+    path = "/notify/admin/reload",
+    params(
+        ("authorization" = inline(StackString), Header, description = "Bearer Authorization"),
+    ),
+    responses(ReloadResponse, Error),
+)]
+async fn reload_config(
+    data: State<Arc<AppState>>,
+    credentials: BearerAuth,
+) -> WarpResult<ReloadResponse> {
+    if !data.api_tokens.is_admin(credentials.token()) {
+        return Err(Error::Unauthorized);
+    }
+    data.reloader
+        .reload()
+        .await
+        .map_err(|e| Error::BadRequest(format_sstr!("{e}")))?;
+    Ok(HtmlBase::new("configuration reloaded").into())
+}
+
+#[derive(UtoipaResponse)]
+#[response(description = "Suppressed Addresses", content = "application/json")]
+#[rustfmt::skip]
+struct SuppressionsResponse(JsonBase::<Vec<SuppressionEntryWrapper>>);
+
+#[utoipa::path(
+    get,
+    path = "/notify/admin/suppressions",
+    params(
+        ("authorization" = inline(StackString), Header, description = "Bearer Authorization"),
+    ),
+    responses(SuppressionsResponse, Error),
+)]
+async fn list_suppressions(
+    data: State<Arc<AppState>>,
+    credentials: BearerAuth,
+) -> WarpResult<SuppressionsResponse> {
+    if !data.api_tokens.is_admin(credentials.token()) {
+        return Err(Error::Unauthorized);
+    }
+    let entries = data
+        .suppression
+        .list()
+        .await
+        .into_iter()
+        .map(Into::into)
+        .collect();
+    Ok(JsonBase::new(entries).into())
+}
+
+#[derive(UtoipaResponse)]
+#[response(description = "Suppression List Cleared")]
+#[rustfmt::skip]
+struct ClearSuppressionsResponse(HtmlBase::<String>);
+
+#[utoipa::path(
+    delete,
+    path = "/notify/admin/suppressions",
+    params(
+        ("authorization" = inline(StackString), Header, description = "Bearer Authorization"),
+    ),
+    responses(ClearSuppressionsResponse, Error),
+)]
+async fn clear_suppressions(
+    data: State<Arc<AppState>>,
+    credentials: BearerAuth,
+) -> WarpResult<ClearSuppressionsResponse> {
+    if !data.api_tokens.is_admin(credentials.token()) {
+        return Err(Error::Unauthorized);
+    }
+    let count = data.suppression.clear().await?;
+    Ok(HtmlBase::new(format!("removed {count} suppressed addresses")).into())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/notify/admin/suppressions/{address}",
+    params(
+        ("authorization" = inline(StackString), Header, description = "Bearer Authorization"),
+        ("address" = inline(StackString), Path, description = "Suppressed Address"),
+    ),
+    responses(CancelResponse, Error),
+)]
+async fn delete_suppression(
+    data: State<Arc<AppState>>,
+    credentials: BearerAuth,
+    address: Path<StackString>,
+) -> WarpResult<CancelResponse> {
+    if !data.api_tokens.is_admin(credentials.token()) {
+        return Err(Error::Unauthorized);
+    }
+    let Path(address) = address;
+    if data.suppression.remove(&address).await? {
+        Ok(HtmlBase::new("").into())
+    } else {
+        Err(Error::NotFound(format_sstr!("{address} is not suppressed")))
+    }
+}
+
+/// Prometheus metrics, labelled with token names so only token holders may
+/// scrape them
+async fn notify_metrics(
+    data: State<Arc<AppState>>,
+    credentials: BearerAuth,
+) -> WarpResult<impl IntoResponse> {
+    if data.api_tokens.get(credentials.token()).is_none() {
+        return Err(Error::Unauthorized);
+    }
+    metrics::QUEUE_DEPTH.set(i64::try_from(data.queue.len()).unwrap_or(i64::MAX));
+    CIRCUITS.update_metrics();
+    let body = metrics::encode_metrics()?;
+    Ok(([(CONTENT_TYPE, "text/plain; version=0.0.4")], body))
+}
+
+fn health_status(data: &AppState) -> HealthStatusWrapper {
+    HealthStatusWrapper::new(BOT_HEALTH.status(), data.queue.len(), CIRCUITS.statuses())
+}
+
+#[utoipa::path(
+    get,
+    path = "/notify/health",
+    responses(
+        (status = OK, description = "Bot task is running", body = HealthStatusWrapper),
+        (status = SERVICE_UNAVAILABLE, description = "Bot task has stopped", body = HealthStatusWrapper),
+    ),
+)]
+async fn notify_health(data: State<Arc<AppState>>) -> impl IntoResponse {
+    let status = health_status(&data);
+    let code = if status.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(status))
+}
 
+#[utoipa::path(
+    get,
+    path = "/notify/ready",
+    responses(
+        (status = OK, description = "Messages can be delivered", body = HealthStatusWrapper),
+        (status = SERVICE_UNAVAILABLE, description = "Messages cannot currently be delivered", body = HealthStatusWrapper),
+    ),
+)]
+async fn notify_ready(data: State<Arc<AppState>>) -> impl IntoResponse {
+    let status = health_status(&data);
+    let code = if status.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(status))
+}
+
+fn notify_telegram_routes() -> OpenApiRouter<Arc<AppState>> {
     OpenApiRouter::new()
         .routes(routes!(notify_telegram))
-        .with_state(app)
+        .routes({
+            let (schemas, paths, router) = routes!(notify_email);
+            (
+                schemas,
+                paths,
+                router.layer(DefaultBodyLimit::max(MAX_EMAIL_BODY)),
+            )
+        })
+        .routes(routes!(email_stats))
+        .routes(routes!(sns_notification))
+        .routes(routes!(preview_template))
+        .routes(routes!(scheduled_messages))
+        .routes(routes!(cancel_scheduled_message))
+        .routes(routes!(create_reminder, list_reminders))
+        .routes(routes!(delete_reminder))
+        .routes(routes!(held_messages, clear_held_messages))
+        .routes(routes!(release_held_messages))
+        .routes(routes!(notify_health))
+        .routes(routes!(notify_ready))
+        .routes(routes!(reload_config))
+        .routes(routes!(list_suppressions, clear_suppressions))
+        .routes(routes!(delete_suppression))
+        .route("/notify/metrics", axum::routing::get(notify_metrics))
+}
+
+pub fn notify_telegram_router(app: &AppState) -> OpenApiRouter {
+    notify_telegram_routes().with_state(Arc::new(app.clone()))
+}
+
+/// The spec served at `/notify/openapi/*`, built without starting the server
+#[must_use]
+pub fn api_spec() -> utoipa::openapi::OpenApi {
+    OpenApiRouter::with_openapi(ApiDoc::openapi())
+        .merge(notify_telegram_routes())
+        .into_openapi()
 }
 
 #[derive(OpenApi)]
@@ -54,7 +704,20 @@ pub fn notify_telegram_router(app: &AppState) -> OpenApiRouter {
         title = "Notification API",
         description = "Simple Notification Service",
     ),
-    components(schemas(TelegramMessageWrapper))
+    components(schemas(
+        TelegramMessageWrapper,
+        StructuredMessageWrapper,
+        TemplateMessageWrapper,
+        TemplatePreviewWrapper,
+        ScheduledMessageWrapper,
+        ReminderRequestWrapper,
+        ReminderWrapper,
+        HeldMessageWrapper,
+        HealthStatusWrapper,
+        CircuitStatusWrapper,
+        SesStatisticsWrapper,
+        SuppressionEntryWrapper
+    ))
 )]
 pub struct ApiDoc;
 
@@ -0,0 +1,179 @@
+use anyhow::{format_err, Error};
+use socket2::Socket;
+use std::{
+    env,
+    ffi::OsStr,
+    net::TcpListener,
+    os::unix::{
+        io::{FromRawFd, RawFd},
+        net::{UnixDatagram, UnixListener},
+    },
+    time::Duration,
+};
+
+/// First file descriptor passed by systemd socket activation
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Socket passed to the process by systemd
+#[derive(Debug)]
+pub enum ActivatedListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+fn parse_listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>) -> Result<usize, Error> {
+    let (Some(listen_pid), Some(listen_fds)) = (listen_pid, listen_fds) else {
+        return Ok(0);
+    };
+    let listen_pid: u32 = listen_pid
+        .parse()
+        .map_err(|e| format_err!("Invalid LISTEN_PID {listen_pid}: {e}"))?;
+    if listen_pid != std::process::id() {
+        return Ok(0);
+    }
+    listen_fds
+        .parse()
+        .map_err(|e| format_err!("Invalid LISTEN_FDS {listen_fds}: {e}"))
+}
+
+/// Number of sockets systemd passed to this process
+/// # Errors
+/// Return error if `LISTEN_PID` or `LISTEN_FDS` are malformed
+pub fn listen_fds() -> Result<usize, Error> {
+    parse_listen_fds(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+    )
+}
+
+/// Take ownership of the first socket passed by systemd, later sockets are
+/// ignored
+/// # Errors
+/// Return error if no socket was passed or it is neither a TCP nor a unix
+/// stream socket
+pub fn take_listener() -> Result<ActivatedListener, Error> {
+    if listen_fds()? == 0 {
+        return Err(format_err!("No socket passed by systemd"));
+    }
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    // Safety: systemd hands us ownership of the descriptors starting at 3
+    let socket = unsafe { Socket::from_raw_fd(SD_LISTEN_FDS_START) };
+    socket.set_nonblocking(true)?;
+    let addr = socket.local_addr()?;
+    if addr.as_socket().is_some() {
+        Ok(ActivatedListener::Tcp(socket.into()))
+    } else if addr.is_unix() {
+        Ok(ActivatedListener::Unix(socket.into()))
+    } else {
+        Err(format_err!("Unsupported socket passed by systemd"))
+    }
+}
+
+fn notify_socket(socket_path: &OsStr, state: &str) -> Result<(), Error> {
+    let socket = UnixDatagram::unbound()?;
+    match socket_path.to_str().and_then(|p| p.strip_prefix('@')) {
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        None => {
+            socket.send_to(state.as_bytes(), socket_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Send `state` (e.g. `READY=1`) to the service manager, returns false when
+/// not running under systemd
+/// # Errors
+/// Return error if sending to `NOTIFY_SOCKET` fails
+pub fn notify(state: &str) -> Result<bool, Error> {
+    match env::var_os("NOTIFY_SOCKET") {
+        Some(socket_path) => notify_socket(&socket_path, state).map(|()| true),
+        None => Ok(false),
+    }
+}
+
+fn parse_watchdog(watchdog_pid: Option<&str>, watchdog_usec: Option<&str>) -> Option<Duration> {
+    if let Some(pid) = watchdog_pid {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    let usec: u64 = watchdog_usec?.parse().ok()?;
+    if usec == 0 {
+        None
+    } else {
+        Some(Duration::from_micros(usec))
+    }
+}
+
+/// Watchdog timeout configured with `WatchdogSec=`, heartbeats should be
+/// sent at half this interval
+#[must_use]
+pub fn watchdog_interval() -> Option<Duration> {
+    parse_watchdog(
+        env::var("WATCHDOG_PID").ok().as_deref(),
+        env::var("WATCHDOG_USEC").ok().as_deref(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+    use std::{os::unix::net::UnixDatagram, time::Duration};
+    use tempfile::TempDir;
+
+    use crate::systemd::{notify_socket, parse_listen_fds, parse_watchdog};
+
+    #[test]
+    fn test_parse_listen_fds() -> Result<(), Error> {
+        let pid = std::process::id().to_string();
+        assert_eq!(parse_listen_fds(None, None)?, 0);
+        assert_eq!(parse_listen_fds(Some(&pid), Some("2"))?, 2);
+        assert_eq!(parse_listen_fds(Some("1"), Some("2"))?, 0);
+        assert!(parse_listen_fds(Some(&pid), Some("two")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_watchdog() {
+        let pid = std::process::id().to_string();
+        assert_eq!(
+            parse_watchdog(None, Some("30000000")),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_watchdog(Some(&pid), Some("500000")),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(parse_watchdog(Some("1"), Some("500000")), None);
+        assert_eq!(parse_watchdog(None, Some("0")), None);
+        assert_eq!(parse_watchdog(None, None), None);
+    }
+
+    #[test]
+    fn test_notify_socket() -> Result<(), Error> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("notify.sock");
+        let server = UnixDatagram::bind(&path)?;
+        notify_socket(path.as_os_str(), "READY=1")?;
+        let mut buf = [0; 64];
+        let n = server.recv(&mut buf)?;
+        assert_eq!(&buf[..n], b"READY=1");
+
+        let name = format!("notification-test-{}", std::process::id());
+        let addr = {
+            use std::os::linux::net::SocketAddrExt;
+            std::os::unix::net::SocketAddr::from_abstract_name(&name)?
+        };
+        let server = UnixDatagram::bind_addr(&addr)?;
+        notify_socket(format!("@{name}").as_ref(), "WATCHDOG=1")?;
+        let n = server.recv(&mut buf)?;
+        assert_eq!(&buf[..n], b"WATCHDOG=1");
+        Ok(())
+    }
+}
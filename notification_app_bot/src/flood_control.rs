@@ -0,0 +1,262 @@
+use stack_string::{format_sstr, StackString};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{PoisonError, RwLock},
+    time::{Duration, Instant},
+};
+use time::OffsetDateTime;
+use tokio::sync::Mutex;
+
+use notification_app_lib::config::{MessageFormat, TelegramMessage};
+
+const MAX_HELD_MESSAGES: usize = 1000;
+
+#[derive(Clone, Debug)]
+pub struct HeldMessage {
+    pub recipient: StackString,
+    pub sender: Option<StackString>,
+    pub message: StackString,
+    pub format: MessageFormat,
+    pub received: OffsetDateTime,
+}
+
+impl HeldMessage {
+    /// Every message matches a `sender` of `None`
+    fn sent_by(&self, sender: Option<&str>) -> bool {
+        sender.is_none_or(|name| self.sender.as_ref().map(StackString::as_str) == Some(name))
+    }
+
+    /// The message to deliver when it is released, flood control does not
+    /// hold it a second time
+    #[must_use]
+    pub fn into_message(self) -> TelegramMessage {
+        TelegramMessage {
+            recipient: self.recipient,
+            message: self.message,
+            sender: self.sender,
+            format: self.format,
+            released: true,
+            ..TelegramMessage::default()
+        }
+    }
+}
+
+#[derive(Default)]
+struct RecipientState {
+    recent: VecDeque<Instant>,
+    suppressed_since: Option<Instant>,
+    suppressed: HashMap<Option<StackString>, usize>,
+    held: VecDeque<HeldMessage>,
+}
+
+impl RecipientState {
+    fn expire(&mut self, now: Instant, window: Duration) {
+        while let Some(t) = self.recent.front() {
+            if now.duration_since(*t) > window {
+                self.recent.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+pub struct FloodControl {
+    limits: RwLock<(usize, Duration)>,
+    state: Mutex<HashMap<StackString, RecipientState>>,
+}
+
+impl FloodControl {
+    /// A `threshold` of zero disables flood protection
+    #[must_use]
+    pub fn new(threshold: usize, window: Duration) -> Self {
+        Self {
+            limits: RwLock::new((threshold, window)),
+            state: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn limits(&self) -> (usize, Duration) {
+        *self.limits.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Replace the threshold and window, applies to the next message
+    pub fn set_limits(&self, threshold: usize, window: Duration) {
+        *self.limits.write().unwrap_or_else(PoisonError::into_inner) = (threshold, window);
+    }
+
+    /// Returns true if the message should be delivered, otherwise the message
+    /// is held and counted towards the next summary for its recipient
+    pub async fn check(&self, message: &TelegramMessage) -> bool {
+        self.check_at(message, Instant::now()).await
+    }
+
+    async fn check_at(&self, message: &TelegramMessage, now: Instant) -> bool {
+        let (threshold, window) = self.limits();
+        if threshold == 0 || message.released {
+            return true;
+        }
+        let mut state = self.state.lock().await;
+        let entry = state.entry(message.recipient.clone()).or_default();
+        entry.expire(now, window);
+        if entry.recent.len() < threshold {
+            entry.recent.push_back(now);
+            return true;
+        }
+        entry.suppressed_since.get_or_insert(now);
+        *entry.suppressed.entry(message.sender.clone()).or_default() += 1;
+        if entry.held.len() >= MAX_HELD_MESSAGES {
+            entry.held.pop_front();
+        }
+        entry.held.push_back(HeldMessage {
+            recipient: message.recipient.clone(),
+            sender: message.sender.clone(),
+            message: message.message.clone(),
+            format: message.format,
+            received: OffsetDateTime::now_utc(),
+        });
+        false
+    }
+
+    /// Returns one summary message per recipient and sender for every flood
+    /// whose window has elapsed
+    pub async fn take_summaries(&self) -> Vec<TelegramMessage> {
+        self.take_summaries_at(Instant::now()).await
+    }
+
+    async fn take_summaries_at(&self, now: Instant) -> Vec<TelegramMessage> {
+        let (_, window) = self.limits();
+        let minutes = window.as_secs() / 60;
+        let mut summaries = Vec::new();
+        let mut state = self.state.lock().await;
+        for (recipient, entry) in state.iter_mut() {
+            let Some(since) = entry.suppressed_since else {
+                continue;
+            };
+            if now.duration_since(since) < window {
+                continue;
+            }
+            entry.suppressed_since = None;
+            for (sender, count) in entry.suppressed.drain() {
+                let sender = sender.as_ref().map_or("unknown", StackString::as_str);
+                summaries.push(TelegramMessage {
+                    recipient: recipient.clone(),
+                    message: format_sstr!(
+                        "{count} messages suppressed from token {sender} in the last {minutes} \
+                         minutes"
+                    ),
+                    ..TelegramMessage::default()
+                });
+            }
+        }
+        state.retain(|_, entry| {
+            !entry.recent.is_empty() || entry.suppressed_since.is_some() || !entry.held.is_empty()
+        });
+        summaries
+    }
+
+    /// Held messages sent by `sender`, every held message if it is `None`
+    pub async fn held_messages(&self, sender: Option<&str>) -> Vec<HeldMessage> {
+        self.state
+            .lock()
+            .await
+            .values()
+            .flat_map(|entry| entry.held.iter())
+            .filter(|held| held.sent_by(sender))
+            .cloned()
+            .collect()
+    }
+
+    /// Remove and return the held messages sent by `sender`, every held
+    /// message if it is `None`
+    pub async fn take_held(&self, sender: Option<&str>) -> Vec<HeldMessage> {
+        let mut state = self.state.lock().await;
+        let mut taken = Vec::new();
+        for entry in state.values_mut() {
+            let (matching, kept): (VecDeque<_>, _) =
+                entry.held.drain(..).partition(|held| held.sent_by(sender));
+            entry.held = kept;
+            taken.extend(matching);
+        }
+        state.retain(|_, entry| {
+            !entry.recent.is_empty() || entry.suppressed_since.is_some() || !entry.held.is_empty()
+        });
+        taken
+    }
+
+    /// Remove and return every held message, used to persist them on shutdown
+    pub async fn take_all_held(&self) -> Vec<HeldMessage> {
+        let mut state = self.state.lock().await;
+        let taken = state
+            .values_mut()
+            .flat_map(|entry| entry.held.drain(..))
+            .collect();
+        state.retain(|_, entry| !entry.recent.is_empty() || entry.suppressed_since.is_some());
+        taken
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use notification_app_lib::config::TelegramMessage;
+
+    use crate::flood_control::{FloodControl, HeldMessage};
+
+    #[tokio::test]
+    async fn test_flood_control() {
+        let flood = FloodControl::new(2, Duration::from_secs(600));
+        let message = TelegramMessage {
+            recipient: "user".into(),
+            message: "test message".into(),
+            sender: Some("runaway".into()),
+            ..TelegramMessage::default()
+        };
+        let start = Instant::now();
+        assert!(flood.check_at(&message, start).await);
+        assert!(flood.check_at(&message, start).await);
+        assert!(!flood.check_at(&message, start).await);
+        assert!(!flood.check_at(&message, start).await);
+
+        assert!(flood.take_summaries_at(start).await.is_empty());
+        // only the sender and admins see the held messages
+        assert!(flood.held_messages(Some("user")).await.is_empty());
+        assert_eq!(flood.held_messages(Some("runaway")).await.len(), 2);
+        assert_eq!(flood.held_messages(None).await.len(), 2);
+        assert!(flood.take_held(Some("user")).await.is_empty());
+
+        let later = start + Duration::from_secs(601);
+        let summaries = flood.take_summaries_at(later).await;
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].recipient, "user");
+        assert_eq!(
+            summaries[0].message,
+            "2 messages suppressed from token runaway in the last 10 minutes"
+        );
+        assert!(flood.take_summaries_at(later).await.is_empty());
+        assert!(flood.check_at(&message, later).await);
+
+        // held messages stay until they are released or cleared
+        assert_eq!(flood.held_messages(Some("runaway")).await.len(), 2);
+        let released: Vec<_> = flood
+            .take_held(Some("runaway"))
+            .await
+            .into_iter()
+            .map(HeldMessage::into_message)
+            .collect();
+        assert_eq!(released.len(), 2);
+        assert!(flood.held_messages(None).await.is_empty());
+        assert!(flood.take_held(None).await.is_empty());
+        assert!(flood.check_at(&message, later).await);
+        assert!(!flood.check_at(&message, later).await);
+        // a released message is delivered even while the recipient is flooded
+        assert!(flood.check_at(&released[0], later).await);
+        assert_eq!(flood.take_held(None).await.len(), 1);
+
+        flood.set_limits(0, Duration::from_secs(600));
+        for _ in 0..5 {
+            assert!(flood.check_at(&message, later).await);
+        }
+    }
+}
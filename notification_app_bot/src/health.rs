@@ -0,0 +1,106 @@
+use once_cell::sync::Lazy;
+use stack_string::StackString;
+use std::sync::Mutex;
+use time::OffsetDateTime;
+
+pub static BOT_HEALTH: Lazy<BotHealth> = Lazy::new(BotHealth::default);
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HealthStatus {
+    pub bot_running: bool,
+    pub bot_connected: bool,
+    pub last_update_poll: Option<OffsetDateTime>,
+    pub last_send: Option<OffsetDateTime>,
+    pub token_file_loaded: bool,
+    pub token_file_error: Option<StackString>,
+}
+
+impl HealthStatus {
+    /// The bot task is alive
+    #[must_use]
+    pub fn is_healthy(&self) -> bool {
+        self.bot_running
+    }
+
+    /// Messages put on the queue can currently be delivered
+    #[must_use]
+    pub fn is_ready(&self) -> bool {
+        self.bot_running && self.bot_connected && self.token_file_loaded
+    }
+}
+
+/// Delivery state reported by the bot loops
+#[derive(Default)]
+pub struct BotHealth(Mutex<HealthStatus>);
+
+impl BotHealth {
+    fn update(&self, f: impl FnOnce(&mut HealthStatus)) {
+        if let Ok(mut status) = self.0.lock() {
+            f(&mut status);
+        }
+    }
+
+    #[must_use]
+    pub fn status(&self) -> HealthStatus {
+        self.0.lock().map(|s| s.clone()).unwrap_or_default()
+    }
+
+    pub fn set_running(&self, running: bool) {
+        self.update(|s| {
+            s.bot_running = running;
+            if !running {
+                s.bot_connected = false;
+            }
+        });
+    }
+
+    pub fn set_connected(&self, connected: bool) {
+        self.update(|s| s.bot_connected = connected);
+    }
+
+    pub fn record_update_poll(&self) {
+        self.update(|s| {
+            s.bot_connected = true;
+            s.last_update_poll = Some(OffsetDateTime::now_utc());
+        });
+    }
+
+    pub fn record_send(&self) {
+        self.update(|s| s.last_send = Some(OffsetDateTime::now_utc()));
+    }
+
+    pub fn record_token_file(&self, error: Option<StackString>) {
+        self.update(|s| {
+            s.token_file_loaded = error.is_none();
+            s.token_file_error = error;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::health::BotHealth;
+
+    #[test]
+    fn test_bot_health() {
+        let health = BotHealth::default();
+        assert!(!health.status().is_healthy());
+        health.set_running(true);
+        health.record_token_file(None);
+        assert!(health.status().is_healthy());
+        assert!(!health.status().is_ready());
+        health.record_update_poll();
+        assert!(health.status().is_ready());
+        assert!(health.status().last_update_poll.is_some());
+
+        health.record_token_file(Some("missing file".into()));
+        let status = health.status();
+        assert!(!status.is_ready());
+        assert_eq!(status.token_file_error.as_ref().unwrap(), "missing file");
+
+        health.record_token_file(None);
+        health.set_running(false);
+        assert!(!health.status().is_healthy());
+        assert!(!health.status().bot_connected);
+    }
+}
@@ -5,5 +5,9 @@
 #![allow(clippy::cast_possible_truncation)]
 #![allow(clippy::cognitive_complexity)]
 
-pub mod failure_count;
+pub mod flood_control;
+pub mod health;
+pub mod reminders;
+pub mod scheduler;
+pub mod supervisor;
 pub mod telegram_bot;
@@ -0,0 +1,325 @@
+use anyhow::{format_err, Error};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use croner::Cron;
+use deadqueue::unlimited::Queue;
+use log::error;
+use serde::{Deserialize, Serialize};
+use stack_string::{format_sstr, StackString};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Instant,
+};
+use time::OffsetDateTime;
+use tokio::{
+    fs,
+    sync::Mutex,
+    time::{sleep, Duration},
+};
+use uuid::Uuid;
+
+use notification_app_lib::config::TelegramMessage;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Reminder {
+    pub id: Uuid,
+    pub owner: StackString,
+    pub recipient: StackString,
+    pub message: StackString,
+    pub cron: StackString,
+    pub timezone: StackString,
+    #[serde(with = "time::serde::rfc3339")]
+    pub next_run: OffsetDateTime,
+}
+
+fn next_occurrence(
+    cron: &str,
+    timezone: &str,
+    after: OffsetDateTime,
+) -> Result<OffsetDateTime, Error> {
+    let schedule =
+        Cron::from_str(cron).map_err(|e| format_err!("Invalid cron expression {cron}: {e}"))?;
+    let tz: Tz = timezone
+        .parse()
+        .map_err(|e| format_err!("Invalid timezone {timezone}: {e}"))?;
+    let after = DateTime::<Utc>::from_timestamp(after.unix_timestamp(), 0)
+        .ok_or_else(|| format_err!("Invalid timestamp {after}"))?
+        .with_timezone(&tz);
+    let next = schedule
+        .find_next_occurrence(&after, false)
+        .map_err(|e| format_err!("No next occurrence for {cron}: {e}"))?;
+    OffsetDateTime::from_unix_timestamp(next.timestamp()).map_err(Into::into)
+}
+
+/// Recurring messages defined by a cron expression evaluated in `timezone`,
+/// persisted to `path` after every change
+pub struct ReminderStore {
+    path: Option<PathBuf>,
+    reminders: Mutex<BTreeMap<Uuid, Reminder>>,
+}
+
+impl ReminderStore {
+    /// # Errors
+    /// Return error if an existing file cannot be read or parsed
+    pub async fn new(path: Option<&Path>) -> Result<Self, Error> {
+        let mut reminders = BTreeMap::new();
+        if let Some(path) = path {
+            if path.exists() {
+                let data = fs::read(path).await?;
+                let stored: Vec<Reminder> = serde_json::from_slice(&data)?;
+                reminders.extend(stored.into_iter().map(|r| (r.id, r)));
+            }
+        }
+        Ok(Self {
+            path: path.map(Into::into),
+            reminders: Mutex::new(reminders),
+        })
+    }
+
+    async fn persist(&self, reminders: &BTreeMap<Uuid, Reminder>) -> Result<(), Error> {
+        if let Some(path) = &self.path {
+            let data = serde_json::to_vec_pretty(&reminders.values().collect::<Vec<_>>())?;
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, &data).await?;
+            fs::rename(&tmp, path).await?;
+        }
+        Ok(())
+    }
+
+    /// # Errors
+    /// Return error if `cron` or `timezone` is invalid or persisting fails
+    pub async fn create(
+        &self,
+        owner: &str,
+        recipient: &str,
+        message: &str,
+        cron: &str,
+        timezone: &str,
+    ) -> Result<Reminder, Error> {
+        let next_run = next_occurrence(cron, timezone, OffsetDateTime::now_utc())?;
+        let reminder = Reminder {
+            id: Uuid::new_v4(),
+            owner: owner.into(),
+            recipient: recipient.into(),
+            message: message.into(),
+            cron: cron.into(),
+            timezone: timezone.into(),
+            next_run,
+        };
+        let mut reminders = self.reminders.lock().await;
+        reminders.insert(reminder.id, reminder.clone());
+        self.persist(&reminders).await?;
+        Ok(reminder)
+    }
+
+    /// Reminders created by `owner`
+    pub async fn list(&self, owner: &str) -> Vec<Reminder> {
+        self.reminders
+            .lock()
+            .await
+            .values()
+            .filter(|r| r.owner == owner)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns false if no reminder with `id` was created by `owner`
+    /// # Errors
+    /// Return error if persisting fails
+    pub async fn delete(&self, id: Uuid, owner: &str) -> Result<bool, Error> {
+        let mut reminders = self.reminders.lock().await;
+        match reminders.get(&id) {
+            Some(r) if r.owner == owner => {
+                reminders.remove(&id);
+                self.persist(&reminders).await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Messages for every reminder due at `now`, each reminder is advanced to
+    /// its next occurrence after `now` so a missed run fires only once
+    /// # Errors
+    /// Return error if persisting fails
+    pub async fn take_due(&self, now: OffsetDateTime) -> Result<Vec<TelegramMessage>, Error> {
+        let mut reminders = self.reminders.lock().await;
+        let mut due = Vec::new();
+        for reminder in reminders.values_mut() {
+            if reminder.next_run > now {
+                continue;
+            }
+            due.push(TelegramMessage {
+                recipient: reminder.recipient.clone(),
+                message: reminder.message.clone(),
+                sender: Some(reminder.owner.clone()),
+                ..TelegramMessage::default()
+            });
+            match next_occurrence(&reminder.cron, &reminder.timezone, now) {
+                Ok(next_run) => reminder.next_run = next_run,
+                Err(e) => error!("reminder {} {e}", reminder.id),
+            }
+        }
+        if !due.is_empty() {
+            self.persist(&reminders).await?;
+        }
+        Ok(due)
+    }
+
+    /// # Errors
+    /// Never returns under normal operation
+    pub async fn run(&self, queue: &Queue<TelegramMessage>) -> Result<(), Error> {
+        loop {
+            match self.take_due(OffsetDateTime::now_utc()).await {
+                Ok(due) => {
+                    for mut message in due {
+                        message.queued = Some(Instant::now());
+                        queue.push(message);
+                    }
+                }
+                Err(e) => error!("{e}"),
+            }
+            sleep(Duration::from_secs(1)).await;
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RemindCommand {
+    List,
+    Delete(Uuid),
+    Create {
+        cron: StackString,
+        timezone: StackString,
+        message: StackString,
+    },
+}
+
+impl RemindCommand {
+    pub const USAGE: &'static str = "Usage: /remind <min> <hour> <day> <month> <weekday> \
+                                     [tz=Area/City] <message>, /remind list, /remind delete <id>";
+
+    /// Parse the text of a `/remind` bot command
+    /// # Errors
+    /// Return error if the command is malformed
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut words = text.split_whitespace();
+        if words.next() != Some("/remind") {
+            return Err(format_err!("{}", Self::USAGE));
+        }
+        let words: Vec<_> = words.collect();
+        match words.as_slice() {
+            ["list"] => Ok(Self::List),
+            ["delete", id] => Ok(Self::Delete(id.parse()?)),
+            words if words.len() > 5 => {
+                let cron = words[..5].join(" ");
+                let (timezone, rest) = match words[5].strip_prefix("tz=") {
+                    Some(timezone) => (timezone, &words[6..]),
+                    None => ("UTC", &words[5..]),
+                };
+                if rest.is_empty() {
+                    return Err(format_err!("{}", Self::USAGE));
+                }
+                Ok(Self::Create {
+                    cron: cron.into(),
+                    timezone: timezone.into(),
+                    message: rest.join(" ").into(),
+                })
+            }
+            _ => Err(format_err!("{}", Self::USAGE)),
+        }
+    }
+}
+
+#[must_use]
+pub fn format_reminder(reminder: &Reminder) -> StackString {
+    format_sstr!(
+        "{id} '{cron}' {tz} next {next}: {message}",
+        id = reminder.id,
+        cron = reminder.cron,
+        tz = reminder.timezone,
+        next = reminder.next_run,
+        message = reminder.message,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+    use tempfile::TempDir;
+    use time::{macros::datetime, Duration};
+
+    use crate::reminders::{next_occurrence, RemindCommand, ReminderStore};
+
+    #[test]
+    fn test_next_occurrence() -> Result<(), Error> {
+        let after = datetime!(2024-01-15 12:00 UTC);
+        let next = next_occurrence("0 9 * * *", "America/New_York", after)?;
+        assert_eq!(next, datetime!(2024-01-15 14:00 UTC));
+        assert!(next_occurrence("0 9 * *", "UTC", after).is_err());
+        assert!(next_occurrence("0 9 * * *", "Mars/Olympus", after).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_remind_command() -> Result<(), Error> {
+        assert_eq!(RemindCommand::parse("/remind list")?, RemindCommand::List);
+        assert_eq!(
+            RemindCommand::parse("/remind 0 9 * * 1-5 tz=Europe/Berlin standup now")?,
+            RemindCommand::Create {
+                cron: "0 9 * * 1-5".into(),
+                timezone: "Europe/Berlin".into(),
+                message: "standup now".into(),
+            }
+        );
+        assert_eq!(
+            RemindCommand::parse("/remind */5 * * * * ping")?,
+            RemindCommand::Create {
+                cron: "*/5 * * * *".into(),
+                timezone: "UTC".into(),
+                message: "ping".into(),
+            }
+        );
+        assert!(RemindCommand::parse("/remind 0 9 * * *").is_err());
+        assert!(RemindCommand::parse("/remind delete not-a-uuid").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reminder_store() -> Result<(), Error> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("reminders.json");
+
+        let store = ReminderStore::new(Some(&path)).await?;
+        assert!(store
+            .create("user", "user", "hello", "bad cron", "UTC")
+            .await
+            .is_err());
+        let reminder = store
+            .create("user", "user", "standup", "*/5 * * * *", "UTC")
+            .await?;
+        assert!(store
+            .take_due(reminder.next_run - Duration::seconds(1))
+            .await?
+            .is_empty());
+
+        let store = ReminderStore::new(Some(&path)).await?;
+        assert_eq!(store.list("user").await.len(), 1);
+        assert!(store.list("other").await.is_empty());
+        let due = store
+            .take_due(reminder.next_run + Duration::hours(1))
+            .await?;
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].message, "standup");
+        let next_run = store.list("user").await[0].next_run;
+        assert!(next_run > reminder.next_run + Duration::hours(1));
+
+        assert!(!store.delete(reminder.id, "other").await?);
+        assert!(store.delete(reminder.id, "user").await?);
+        let store = ReminderStore::new(Some(&path)).await?;
+        assert!(store.list("user").await.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,331 @@
+use anyhow::Error;
+use deadqueue::unlimited::Queue;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use stack_string::StackString;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+use time::OffsetDateTime;
+use tokio::{
+    fs,
+    sync::Mutex,
+    time::{sleep, Duration},
+};
+use uuid::Uuid;
+
+use notification_app_lib::config::TelegramMessage;
+
+use crate::flood_control::{FloodControl, HeldMessage};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ScheduledMessage {
+    pub id: Uuid,
+    #[serde(with = "time::serde::rfc3339")]
+    pub send_at: OffsetDateTime,
+    pub message: TelegramMessage,
+}
+
+/// Messages waiting for their delivery time, persisted to `path` after every
+/// change so that they survive a restart
+pub struct MessageScheduler {
+    path: Option<PathBuf>,
+    messages: Mutex<BTreeMap<Uuid, ScheduledMessage>>,
+}
+
+impl MessageScheduler {
+    /// # Errors
+    /// Return error if an existing file cannot be read or parsed
+    pub async fn new(path: Option<&Path>) -> Result<Self, Error> {
+        let mut messages = BTreeMap::new();
+        if let Some(path) = path {
+            if path.exists() {
+                let data = fs::read(path).await?;
+                let scheduled: Vec<ScheduledMessage> = serde_json::from_slice(&data)?;
+                messages.extend(scheduled.into_iter().map(|m| (m.id, m)));
+            }
+        }
+        Ok(Self {
+            path: path.map(Into::into),
+            messages: Mutex::new(messages),
+        })
+    }
+
+    async fn persist(&self, messages: &BTreeMap<Uuid, ScheduledMessage>) -> Result<(), Error> {
+        if let Some(path) = &self.path {
+            let data = serde_json::to_vec_pretty(&messages.values().collect::<Vec<_>>())?;
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, &data).await?;
+            fs::rename(&tmp, path).await?;
+        }
+        Ok(())
+    }
+
+    /// # Errors
+    /// Return error if persisting the schedule fails
+    pub async fn schedule(
+        &self,
+        message: TelegramMessage,
+        send_at: OffsetDateTime,
+    ) -> Result<Uuid, Error> {
+        let id = Uuid::new_v4();
+        let mut messages = self.messages.lock().await;
+        messages.insert(
+            id,
+            ScheduledMessage {
+                id,
+                send_at,
+                message,
+            },
+        );
+        self.persist(&messages).await?;
+        Ok(id)
+    }
+
+    /// Scheduled messages created by `sender`
+    pub async fn list(&self, sender: &str) -> Vec<ScheduledMessage> {
+        self.messages
+            .lock()
+            .await
+            .values()
+            .filter(|m| m.message.sender.as_ref().map(StackString::as_str) == Some(sender))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns false if no message with `id` was created by `sender`
+    /// # Errors
+    /// Return error if persisting the schedule fails
+    pub async fn cancel(&self, id: Uuid, sender: &str) -> Result<bool, Error> {
+        let mut messages = self.messages.lock().await;
+        match messages.get(&id) {
+            Some(m) if m.message.sender.as_ref().map(StackString::as_str) == Some(sender) => {
+                messages.remove(&id);
+                self.persist(&messages).await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Push every message due at `now` onto `queue`, they are removed from
+    /// the schedule only after the hand-off so that a failure in between
+    /// delivers a message twice rather than losing it, returns the number of
+    /// messages pushed
+    /// # Errors
+    /// Return error if persisting the schedule fails
+    pub async fn dispatch_due(
+        &self,
+        queue: &Queue<TelegramMessage>,
+        now: OffsetDateTime,
+    ) -> Result<usize, Error> {
+        let mut messages = self.messages.lock().await;
+        let due: Vec<Uuid> = messages
+            .values()
+            .filter(|m| m.send_at <= now)
+            .map(|m| m.id)
+            .collect();
+        if due.is_empty() {
+            return Ok(0);
+        }
+        for id in &due {
+            let mut message = messages[id].message.clone();
+            message.queued = Some(Instant::now());
+            queue.push(message);
+        }
+        for id in &due {
+            messages.remove(id);
+        }
+        self.persist(&messages).await?;
+        Ok(due.len())
+    }
+
+    /// Wait up to `deadline` for the bot to empty `queue`, returns false on
+    /// timeout
+    pub async fn wait_delivered(queue: &Queue<TelegramMessage>, deadline: Duration) -> bool {
+        let start = Instant::now();
+        while !queue.is_empty() {
+            if start.elapsed() >= deadline {
+                return false;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        true
+    }
+
+    /// Persist anything still queued or held by `flood` so that it is
+    /// delivered after the next start, call once the bot has stopped so that
+    /// a message it was sending is back on `queue`, returns the number of
+    /// persisted messages
+    /// # Errors
+    /// Return error if persisting the schedule fails
+    pub async fn drain(
+        &self,
+        queue: &Queue<TelegramMessage>,
+        flood: &FloodControl,
+    ) -> Result<usize, Error> {
+        let mut pending = Vec::new();
+        while let Some(message) = queue.try_pop() {
+            pending.push(message);
+        }
+        pending.extend(
+            flood
+                .take_all_held()
+                .await
+                .into_iter()
+                .map(HeldMessage::into_message),
+        );
+        if pending.is_empty() {
+            return Ok(0);
+        }
+        if self.path.is_none() {
+            warn!(
+                "No scheduled_messages_path, dropping {} undelivered messages",
+                pending.len()
+            );
+            return Ok(0);
+        }
+        let now = OffsetDateTime::now_utc();
+        let mut messages = self.messages.lock().await;
+        let count = pending.len();
+        for message in pending {
+            let id = Uuid::new_v4();
+            messages.insert(
+                id,
+                ScheduledMessage {
+                    id,
+                    send_at: now,
+                    message,
+                },
+            );
+        }
+        self.persist(&messages).await?;
+        Ok(count)
+    }
+
+    /// # Errors
+    /// Never returns under normal operation
+    pub async fn run(&self, queue: &Queue<TelegramMessage>) -> Result<(), Error> {
+        loop {
+            if let Err(e) = self.dispatch_due(queue, OffsetDateTime::now_utc()).await {
+                error!("{e}");
+            }
+            sleep(Duration::from_secs(1)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+    use deadqueue::unlimited::Queue;
+    use std::time::Duration as StdDuration;
+    use tempfile::TempDir;
+    use time::{Duration, OffsetDateTime};
+
+    use notification_app_lib::config::TelegramMessage;
+
+    use crate::{flood_control::FloodControl, scheduler::MessageScheduler};
+
+    #[tokio::test]
+    async fn test_message_scheduler() -> Result<(), Error> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("scheduled_messages.json");
+        let now = OffsetDateTime::now_utc();
+        let queue = Queue::new();
+
+        let scheduler = MessageScheduler::new(Some(&path)).await?;
+        let message = TelegramMessage {
+            recipient: "user".into(),
+            message: "wake up".into(),
+            sender: Some("user".into()),
+            ..TelegramMessage::default()
+        };
+        let id = scheduler
+            .schedule(message, now + Duration::hours(1))
+            .await?;
+        assert!(path.exists());
+        assert_eq!(scheduler.dispatch_due(&queue, now).await?, 0);
+        assert!(!scheduler.cancel(id, "other").await?);
+
+        let scheduler = MessageScheduler::new(Some(&path)).await?;
+        assert_eq!(scheduler.list("user").await.len(), 1);
+        assert!(scheduler.list("other").await.is_empty());
+        let dispatched = scheduler
+            .dispatch_due(&queue, now + Duration::hours(2))
+            .await?;
+        assert_eq!(dispatched, 1);
+        let message = queue.try_pop().unwrap();
+        assert_eq!(message.message, "wake up");
+        assert!(message.queued.is_some());
+
+        let scheduler = MessageScheduler::new(Some(&path)).await?;
+        assert!(scheduler.list("user").await.is_empty());
+        let id = scheduler
+            .schedule(TelegramMessage::default(), now + Duration::hours(1))
+            .await?;
+        assert!(!scheduler.cancel(id, "user").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_drain() -> Result<(), Error> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("scheduled_messages.json");
+        let scheduler = MessageScheduler::new(Some(&path)).await?;
+        let flood = FloodControl::new(1, StdDuration::from_secs(600));
+        let queue = Queue::new();
+
+        assert!(MessageScheduler::wait_delivered(&queue, StdDuration::from_secs(1)).await);
+        let drained = scheduler.drain(&queue, &flood).await?;
+        assert_eq!(drained, 0);
+        assert!(!path.exists());
+
+        for message in ["first", "second", "held"] {
+            let message = TelegramMessage {
+                recipient: "user".into(),
+                message: message.into(),
+                sender: Some("user".into()),
+                ..TelegramMessage::default()
+            };
+            if message.message == "held" {
+                assert!(flood.check(&message).await);
+                assert!(!flood.check(&message).await);
+            } else {
+                queue.push(message);
+            }
+        }
+        assert!(!MessageScheduler::wait_delivered(&queue, StdDuration::from_millis(200)).await);
+        let persisted = scheduler.drain(&queue, &flood).await?;
+        assert_eq!(persisted, 3);
+        assert!(queue.is_empty());
+        assert!(flood.held_messages(None).await.is_empty());
+
+        let scheduler = MessageScheduler::new(Some(&path)).await?;
+        scheduler
+            .dispatch_due(&queue, OffsetDateTime::now_utc())
+            .await?;
+        let mut messages = Vec::new();
+        while let Some(message) = queue.try_pop() {
+            messages.push((message.message, message.released));
+        }
+        messages.sort_unstable();
+        // the held message is not held a second time after the restart
+        assert_eq!(
+            messages,
+            vec![
+                ("first".into(), false),
+                ("held".into(), true),
+                ("second".into(), false)
+            ]
+        );
+
+        let scheduler = MessageScheduler::new(None).await?;
+        queue.push(TelegramMessage::default());
+        let drained = scheduler.drain(&queue, &flood).await?;
+        assert_eq!(drained, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+use anyhow::{format_err, Error};
+use futures::FutureExt;
+use log::error;
+use std::{
+    collections::VecDeque, convert::TryFrom, future::Future, panic::AssertUnwindSafe, time::Instant,
+};
+use tokio::time::{sleep, Duration};
+
+use notification_app_lib::metrics;
+
+/// Restarts a task whenever it exits or panics, waiting with an exponential
+/// backoff between attempts, and gives up once more than `max_restarts`
+/// restarts happen within `window`
+#[derive(Clone, Copy, Debug)]
+pub struct Supervisor {
+    max_restarts: usize,
+    window: Duration,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Supervisor {
+    #[must_use]
+    pub fn new(max_restarts: usize, window: Duration) -> Self {
+        Self {
+            max_restarts,
+            window,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+
+    #[must_use]
+    pub fn with_backoff(mut self, initial_backoff: Duration, max_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    fn backoff(&self, recent_restarts: usize) -> Duration {
+        let exponent = u32::try_from(recent_restarts.saturating_sub(1))
+            .unwrap_or(u32::MAX)
+            .min(16);
+        self.initial_backoff
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff)
+    }
+
+    /// # Errors
+    /// Return error once `name` has been restarted more than `max_restarts`
+    /// times within `window`
+    pub async fn supervise<F, T>(&self, name: &str, mut task: F) -> Result<(), Error>
+    where
+        F: FnMut() -> T,
+        T: Future<Output = Result<(), Error>>,
+    {
+        let mut restarts: VecDeque<Instant> = VecDeque::new();
+        loop {
+            match AssertUnwindSafe(task()).catch_unwind().await {
+                Ok(Ok(())) => error!("task {name} exited"),
+                Ok(Err(e)) => error!("task {name} failed: {e}"),
+                Err(_) => error!("task {name} panicked"),
+            }
+            metrics::TASK_RESTARTS.with_label_values(&[name]).inc();
+
+            let now = Instant::now();
+            restarts.push_back(now);
+            while restarts
+                .front()
+                .is_some_and(|t| now.duration_since(*t) > self.window)
+            {
+                restarts.pop_front();
+            }
+            if restarts.len() > self.max_restarts {
+                return Err(format_err!(
+                    "task {name} restarted {} times in {:?}, giving up",
+                    restarts.len(),
+                    self.window
+                ));
+            }
+            let backoff = self.backoff(restarts.len());
+            error!("restarting task {name} in {backoff:?}");
+            sleep(backoff).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{format_err, Error};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::time::Duration;
+
+    use crate::supervisor::Supervisor;
+
+    #[test]
+    fn test_backoff() {
+        let supervisor = Supervisor::new(5, Duration::from_secs(60))
+            .with_backoff(Duration::from_secs(1), Duration::from_secs(10));
+        assert_eq!(supervisor.backoff(1), Duration::from_secs(1));
+        assert_eq!(supervisor.backoff(2), Duration::from_secs(2));
+        assert_eq!(supervisor.backoff(4), Duration::from_secs(8));
+        assert_eq!(supervisor.backoff(5), Duration::from_secs(10));
+        assert_eq!(supervisor.backoff(100), Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn test_supervise() -> Result<(), Error> {
+        let supervisor = Supervisor::new(3, Duration::from_secs(60))
+            .with_backoff(Duration::from_millis(1), Duration::from_millis(5));
+        let attempts = AtomicUsize::new(0);
+
+        let result = supervisor
+            .supervise("test", || async {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(format_err!("failed")),
+                    1 => panic!("panicked"),
+                    _ => Ok(()),
+                }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 4);
+
+        let supervisor = Supervisor::new(1, Duration::from_millis(1))
+            .with_backoff(Duration::from_millis(5), Duration::from_millis(5));
+        attempts.store(0, Ordering::SeqCst);
+        let result = tokio::time::timeout(
+            Duration::from_millis(200),
+            supervisor.supervise("test", || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(format_err!("failed"))
+            }),
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(attempts.load(Ordering::SeqCst) > 2);
+        Ok(())
+    }
+}
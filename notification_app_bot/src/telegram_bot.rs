@@ -3,11 +3,11 @@ use deadqueue::unlimited::Queue;
 use futures::try_join;
 use log::error;
 use once_cell::sync::Lazy;
-use stack_string::format_sstr;
-use std::{collections::HashMap, sync::Arc};
+use stack_string::{format_sstr, StackString};
+use std::{collections::HashMap, mem, sync::Arc};
 use telegram_bot::{
-    Api, CanReplySendMessage, CanSendMessage, ChatId, ChatRef, MessageKind, ToChatRef, UpdateKind,
-    UserId,
+    Api, CanReplySendMessage, CanSendMessage, ChatId, ChatRef, GetMe, MessageKind, ParseMode,
+    ToChatRef, UpdateKind, UserId,
 };
 use tokio::{
     fs,
@@ -16,69 +16,150 @@ use tokio::{
 };
 use tokio_stream::StreamExt;
 
-use crate::failure_count::FailureCount;
+use crate::{
+    flood_control::FloodControl,
+    health::BOT_HEALTH,
+    reminders::{format_reminder, RemindCommand, ReminderStore},
+    supervisor::Supervisor,
+};
 
-use notification_app_lib::config::{ApiTokenConfig, Config, TelegramMessage};
+use notification_app_lib::{
+    circuit_breaker::{Admission, CIRCUITS},
+    config::{ApiTokenConfig, Config, MessageFormat, TelegramMessage},
+    metrics,
+    templates::Channel,
+};
 
 type UserIds = RwLock<HashMap<UserId, Option<ChatId>>>;
 
+/// A message popped from the queue, pushed back if the handler is dropped
+/// before it finishes, e.g. when the bot restarts on a configuration reload
+struct InFlight<'a> {
+    queue: &'a Queue<TelegramMessage>,
+    message: TelegramMessage,
+    done: bool,
+}
+
+impl InFlight<'_> {
+    fn finish(mut self) {
+        self.done = true;
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.queue.push(mem::take(&mut self.message));
+        }
+    }
+}
+
 static TELEGRAM_USERIDS: Lazy<UserIds> = Lazy::new(|| RwLock::new(HashMap::new()));
 static API_TOKEN_CONFIG: Lazy<RwLock<ApiTokenConfig>> =
     Lazy::new(|| RwLock::new(ApiTokenConfig::default()));
-static FAILURE_COUNT: Lazy<FailureCount> = Lazy::new(|| FailureCount::new(5));
+
+/// Send a single message without starting the bot, used to check the bot
+/// token and a recipient's chat id
+/// # Errors
+/// Return error if the telegram api request fails
+pub async fn send_test_message(bot_token: &str, chatid: i64, msg: &str) -> Result<(), Error> {
+    let api = Api::new(bot_token);
+    api.send(ChatId::new(chatid).text(msg)).await?;
+    Ok(())
+}
 
 pub struct TelegramBot {
     api: Arc<Api>,
     config: Config,
     queue: Arc<Queue<TelegramMessage>>,
+    flood: Arc<FloodControl>,
+    reminders: Arc<ReminderStore>,
 }
 
 impl TelegramBot {
     #[must_use]
-    pub fn new(bot_token: &str, config: &Config, queue: Arc<Queue<TelegramMessage>>) -> Self {
+    pub fn new(
+        bot_token: &str,
+        config: &Config,
+        queue: Arc<Queue<TelegramMessage>>,
+        flood: Arc<FloodControl>,
+        reminders: Arc<ReminderStore>,
+    ) -> Self {
         Self {
             api: Arc::new(Api::new(bot_token)),
             config: config.clone(),
             queue,
+            flood,
+            reminders,
         }
     }
 
+    /// Run the bot tasks, each one is restarted independently when it fails
     /// # Errors
-    /// Return error if `fill_task` `notification_task` or `bot_task` fails
+    /// Return error if any task exceeds the crash loop threshold
     pub async fn run(&self) -> Result<(), Error> {
-        let fill_task = self.fill_telegram_user_ids();
-        let notification_task = self.notification_handler();
-        let bot_task = self.telegram_worker();
-        try_join!(fill_task, notification_task, bot_task).map(|_| ())
+        let supervisor = Supervisor::new(
+            self.config.crash_loop_threshold,
+            time::Duration::from_secs(self.config.crash_loop_window_minutes * 60),
+        );
+        let fill_task =
+            supervisor.supervise("fill_telegram_user_ids", || self.fill_telegram_user_ids());
+        let notification_task =
+            supervisor.supervise("notification_handler", || self.notification_handler());
+        let flood_task =
+            supervisor.supervise("flood_summary_handler", || self.flood_summary_handler());
+        let bot_task = supervisor.supervise("telegram_worker", || self.telegram_worker());
+        BOT_HEALTH.set_running(true);
+        let result = try_join!(fill_task, notification_task, flood_task, bot_task).map(|_| ());
+        BOT_HEALTH.set_running(false);
+        result
+    }
+
+    /// # Errors
+    /// Return error if the telegram api request fails
+    pub async fn send_message(&self, chat: ChatId, msg: &str) -> Result<(), Error> {
+        self.api.send(chat.text(msg)).await?;
+        Ok(())
     }
 
     /// # Errors
-    /// Return error if `api.spawn` fails
-    pub fn send_message(&self, chat: ChatId, msg: &str) -> Result<(), Error> {
-        self.api.spawn(chat.text(msg));
+    /// Return error if the telegram api request fails
+    pub async fn send_html_message(&self, chat: ChatId, msg: &str) -> Result<(), Error> {
+        let mut request = chat.text(msg);
+        request.parse_mode(ParseMode::Html);
+        self.api.send(request).await?;
         Ok(())
     }
 
     async fn telegram_worker(&self) -> Result<(), Error> {
         loop {
-            FAILURE_COUNT.check()?;
-            match timeout(time::Duration::from_secs(3600), self.bot_handler()).await {
-                Ok(Ok(())) | Err(_) => FAILURE_COUNT.reset()?,
-                Ok(Err(_)) => FAILURE_COUNT.increment()?,
+            let admission = CIRCUITS.telegram.wait().await;
+            // the outcome of connecting is recorded by bot_handler, the
+            // hourly timeout is a routine reconnect, not a restart
+            match timeout(time::Duration::from_secs(3600), self.bot_handler(admission)).await {
+                Err(_) => {}
+                Ok(Ok(())) => metrics::BOT_RESTARTS.inc(),
+                Ok(Err(e)) => {
+                    error!("{e}");
+                    BOT_HEALTH.set_connected(false);
+                    metrics::BOT_RESTARTS.inc();
+                }
             }
         }
     }
 
-    async fn bot_handler(&self) -> Result<(), Error> {
+    async fn bot_handler(&self, admission: Admission) -> Result<(), Error> {
+        let result = self.api.send(GetMe).await;
+        CIRCUITS.telegram.record(admission, &result);
+        result?;
+        BOT_HEALTH.record_update_poll();
         let mut stream = self.api.stream();
         while let Some(update) = stream.next().await {
-            FAILURE_COUNT.check()?;
-            if let UpdateKind::Message(message) = update?.kind {
-                FAILURE_COUNT.check()?;
+            let update = update?;
+            BOT_HEALTH.record_update_poll();
+            if let UpdateKind::Message(message) = update.kind {
                 if let MessageKind::Text { ref data, .. } = message.kind {
-                    FAILURE_COUNT.check()?;
                     if TELEGRAM_USERIDS.read().await.contains_key(&message.from.id) {
-                        FAILURE_COUNT.check()?;
                         if let ChatRef::Id(chat_id) = message.chat.to_chat_ref() {
                             if data.starts_with("/init") {
                                 self.update_telegram_chat_id(message.from.id, chat_id)
@@ -97,6 +178,9 @@ impl TelegramBot {
                                         "No chatid set, please entry '/init' to initialize",
                                     ))
                                     .await?;
+                            } else if data.starts_with("/remind") {
+                                let reply = self.handle_remind(message.from.id, data).await;
+                                self.api.send(message.text_reply(reply.as_str())).await?;
                             }
                         }
                     }
@@ -106,44 +190,138 @@ impl TelegramBot {
         Ok(())
     }
 
+    async fn handle_remind(&self, userid: UserId, data: &str) -> StackString {
+        let userid: i64 = userid.into();
+        let name = API_TOKEN_CONFIG
+            .read()
+            .await
+            .iter()
+            .find(|(_, entry)| entry.telegram_userid == Some(userid))
+            .map(|(name, _)| name.clone());
+        let Some(name) = name else {
+            return "No api token entry for this user".into();
+        };
+        let result = match RemindCommand::parse(data) {
+            Ok(RemindCommand::List) => {
+                let reminders = self.reminders.list(&name).await;
+                if reminders.is_empty() {
+                    Ok("No reminders".into())
+                } else {
+                    let lines: Vec<_> = reminders.iter().map(format_reminder).collect();
+                    Ok(lines.join("\n").into())
+                }
+            }
+            Ok(RemindCommand::Delete(id)) => match self.reminders.delete(id, &name).await {
+                Ok(true) => Ok(format_sstr!("Deleted reminder {id}")),
+                Ok(false) => Ok(format_sstr!("Reminder {id} not found")),
+                Err(e) => Err(e),
+            },
+            Ok(RemindCommand::Create {
+                cron,
+                timezone,
+                message,
+            }) => self
+                .reminders
+                .create(&name, &name, &message, &cron, &timezone)
+                .await
+                .map(|r| format_sstr!("Created reminder {}", format_reminder(&r))),
+            Err(e) => Err(e),
+        };
+        result.unwrap_or_else(|e| format_sstr!("{e}"))
+    }
+
     async fn notification_handler(&self) -> Result<(), Error> {
         loop {
-            FAILURE_COUNT.check()?;
-            match timeout(time::Duration::from_secs(3600), self.queue.pop()).await {
-                Ok(message) => {
-                    FAILURE_COUNT.reset()?;
-                    match self.process_message(&message).await {
-                        Ok(()) => FAILURE_COUNT.reset()?,
-                        Err(e) => {
-                            error!("{e}",);
-                            FAILURE_COUNT.increment()?;
+            if let Ok(message) = timeout(time::Duration::from_secs(3600), self.queue.pop()).await {
+                let in_flight = InFlight {
+                    queue: &self.queue,
+                    message,
+                    done: false,
+                };
+                let message = &in_flight.message;
+                if !self.flood.check(message).await {
+                    in_flight.finish();
+                    continue;
+                }
+                let admission = CIRCUITS.telegram.wait().await;
+                let labels = [
+                    Channel::Telegram.as_str(),
+                    metrics::token_label(message.sender.as_ref()),
+                ];
+                let result = self.process_message(message).await;
+                match result {
+                    Ok(true) => {
+                        CIRCUITS.telegram.record_success(admission);
+                        BOT_HEALTH.record_send();
+                        metrics::MESSAGES_DELIVERED.with_label_values(&labels).inc();
+                        if let Some(queued) = message.queued {
+                            metrics::latency_histogram(Channel::Telegram.as_str())
+                                .observe(queued.elapsed().as_secs_f64());
                         }
                     }
+                    Ok(false) => {
+                        // no chat is known, telegram was not called
+                        CIRCUITS.telegram.record_skipped(admission);
+                        metrics::MESSAGES_UNDELIVERABLE
+                            .with_label_values(&labels)
+                            .inc();
+                    }
+                    Err(e) => {
+                        error!("{e}",);
+                        CIRCUITS.telegram.record_failure(admission);
+                        metrics::MESSAGES_FAILED.with_label_values(&labels).inc();
+                    }
+                }
+                in_flight.finish();
+            }
+        }
+    }
+
+    async fn flood_summary_handler(&self) -> Result<(), Error> {
+        loop {
+            for summary in self.flood.take_summaries().await {
+                if let Err(e) = self.process_message(&summary).await {
+                    error!("{e}");
                 }
-                Err(_) => FAILURE_COUNT.increment()?,
             }
+            time::sleep(time::Duration::from_secs(10)).await;
         }
     }
 
-    async fn process_message(&self, message: &TelegramMessage) -> Result<(), Error> {
-        if let Some(entry) = API_TOKEN_CONFIG
+    /// Returns false if the recipient has no known chat
+    async fn process_message(&self, message: &TelegramMessage) -> Result<bool, Error> {
+        let userid = API_TOKEN_CONFIG
             .read()
             .await
             .get(message.recipient.as_str())
-        {
-            if let Some(userid) = entry.telegram_userid {
-                let userid = UserId::new(userid);
-                if let Some(Some(chatid)) = TELEGRAM_USERIDS.read().await.get(&userid) {
-                    self.send_message(*chatid, message.message.as_str())?;
-                }
+            .and_then(|entry| entry.telegram_userid)
+            .map(UserId::new);
+        let Some(userid) = userid else {
+            return Ok(false);
+        };
+        // don't hold the user id lock while waiting on the telegram api
+        let chatid = TELEGRAM_USERIDS
+            .read()
+            .await
+            .get(&userid)
+            .copied()
+            .flatten();
+        let Some(chatid) = chatid else {
+            return Ok(false);
+        };
+        match message.format {
+            MessageFormat::Text => self.send_message(chatid, message.message.as_str()).await?,
+            MessageFormat::Html => {
+                self.send_html_message(chatid, message.message.as_str())
+                    .await?;
             }
         }
-        Ok(())
+        Ok(true)
     }
 
     async fn fill_telegram_user_ids(&self) -> Result<(), Error> {
         loop {
-            FAILURE_COUNT.check()?;
+            let admission = CIRCUITS.token_file.wait().await;
             let mut modified = None;
             if let Some(api_tokens_path) = &self.config.api_tokens_path {
                 if let Some(new_modified) = fs::metadata(api_tokens_path)
@@ -153,12 +331,33 @@ impl TelegramBot {
                 {
                     let old_modified = modified.replace(new_modified);
                     if old_modified.is_none() || modified > old_modified {
-                        let config = ApiTokenConfig::new(api_tokens_path).await?;
-                        let userid_map = Self::get_userid_chatid_dict(&config);
-                        *TELEGRAM_USERIDS.write().await = userid_map;
-                        *API_TOKEN_CONFIG.write().await = config;
+                        match ApiTokenConfig::new(api_tokens_path).await {
+                            Ok(config) => {
+                                CIRCUITS.token_file.record_success(admission);
+                                BOT_HEALTH.record_token_file(None);
+                                let userid_map = Self::get_userid_chatid_dict(&config);
+                                *TELEGRAM_USERIDS.write().await = userid_map;
+                                *API_TOKEN_CONFIG.write().await = config;
+                            }
+                            Err(e) => {
+                                error!("{e}");
+                                CIRCUITS.token_file.record_failure(admission);
+                                BOT_HEALTH.record_token_file(Some(format_sstr!("{e}")));
+                            }
+                        }
+                    } else {
+                        CIRCUITS.token_file.record_skipped(admission);
                     }
+                } else {
+                    CIRCUITS.token_file.record_failure(admission);
+                    BOT_HEALTH.record_token_file(Some(format_sstr!(
+                        "Cannot read {}",
+                        api_tokens_path.display()
+                    )));
                 }
+            } else {
+                CIRCUITS.token_file.record_skipped(admission);
+                BOT_HEALTH.record_token_file(Some("No API_TOKENS_PATH".into()));
             }
             time::sleep(time::Duration::from_secs(1)).await;
         }
@@ -0,0 +1,520 @@
+use anyhow::Error;
+use once_cell::sync::Lazy;
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+
+use crate::{config::Config, metrics};
+
+pub static CIRCUITS: Lazy<CircuitBreakers> = Lazy::new(CircuitBreakers::default);
+
+/// Returned instead of calling a dependency whose circuit is open
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitOpen {
+    pub name: &'static str,
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Circuit {} is open", self.name)
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl CircuitState {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Closed => "closed",
+            Self::Open => "open",
+            Self::HalfOpen => "half_open",
+        }
+    }
+
+    fn gauge_value(self) -> i64 {
+        match self {
+            Self::Closed => 0,
+            Self::HalfOpen => 1,
+            Self::Open => 2,
+        }
+    }
+}
+
+/// The circuit opens once at least `failure_threshold` calls failed within
+/// `window` and they make up at least `failure_rate` of all calls in that
+/// window, it stays open for `open_duration` before allowing a single trial
+/// call, the probe, whose outcome closes or reopens it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CircuitSettings {
+    pub failure_threshold: usize,
+    pub failure_rate: f64,
+    pub window: Duration,
+    pub open_duration: Duration,
+}
+
+impl Default for CircuitSettings {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            failure_rate: 0.5,
+            window: Duration::from_secs(60),
+            open_duration: Duration::from_secs(30),
+        }
+    }
+}
+
+impl From<&Config> for CircuitSettings {
+    fn from(config: &Config) -> Self {
+        Self {
+            failure_threshold: config.circuit_failure_threshold,
+            failure_rate: config.circuit_failure_rate,
+            window: Duration::from_secs(config.circuit_window_seconds),
+            open_duration: Duration::from_secs(config.circuit_open_seconds),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CircuitStatus {
+    pub name: &'static str,
+    /// Whether an open circuit makes the service not ready
+    pub required_for_ready: bool,
+    pub state: CircuitState,
+    pub failures: usize,
+    pub calls: usize,
+}
+
+/// A call let through the circuit, its outcome is recorded with it so that
+/// only the probe of a half open circuit closes or reopens it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[must_use]
+pub struct Admission {
+    probe: Option<u64>,
+}
+
+#[derive(Debug)]
+struct CircuitInner {
+    settings: CircuitSettings,
+    state: CircuitState,
+    opened_at: Option<Instant>,
+    /// Id and start of the call probing a half open circuit
+    probe: Option<(u64, Instant)>,
+    probes: u64,
+    outcomes: VecDeque<(Instant, bool)>,
+}
+
+impl CircuitInner {
+    fn expire(&mut self, now: Instant) {
+        let window = self.settings.window;
+        while self
+            .outcomes
+            .front()
+            .is_some_and(|(t, _)| now.duration_since(*t) > window)
+        {
+            self.outcomes.pop_front();
+        }
+    }
+
+    fn failures(&self) -> usize {
+        self.outcomes.iter().filter(|(_, ok)| !ok).count()
+    }
+
+    fn refresh(&mut self, now: Instant) {
+        if self.state == CircuitState::Open
+            && self
+                .opened_at
+                .is_some_and(|t| now.duration_since(t) >= self.settings.open_duration)
+        {
+            self.state = CircuitState::HalfOpen;
+            self.probe = None;
+        }
+    }
+
+    /// Admits the call or returns the time left before one may proceed, in
+    /// half open state only the probe is admitted, a probe that never
+    /// reports back is replaced after `open_duration`
+    fn admit(&mut self, now: Instant) -> Result<Admission, Duration> {
+        let open_duration = self.settings.open_duration;
+        match (self.state, self.opened_at) {
+            (CircuitState::Open, Some(opened_at)) => {
+                Err(open_duration.saturating_sub(now.duration_since(opened_at)))
+            }
+            (CircuitState::HalfOpen, _) => match self.probe {
+                Some((_, started)) if now.duration_since(started) < open_duration => {
+                    Err(open_duration - now.duration_since(started))
+                }
+                _ => {
+                    self.probes += 1;
+                    self.probe = Some((self.probes, now));
+                    Ok(Admission {
+                        probe: Some(self.probes),
+                    })
+                }
+            },
+            _ => Ok(Admission::default()),
+        }
+    }
+
+    fn is_probe(&self, admission: Admission) -> bool {
+        admission.probe.is_some() && admission.probe == self.probe.map(|(id, _)| id)
+    }
+}
+
+/// Tracks the outcome of calls to a single dependency
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: &'static str,
+    required_for_ready: bool,
+    inner: Mutex<CircuitInner>,
+    probe_done: Notify,
+}
+
+impl CircuitBreaker {
+    #[must_use]
+    pub fn new(name: &'static str, settings: CircuitSettings) -> Self {
+        Self {
+            name,
+            required_for_ready: true,
+            inner: Mutex::new(CircuitInner {
+                settings,
+                state: CircuitState::Closed,
+                opened_at: None,
+                probe: None,
+                probes: 0,
+                outcomes: VecDeque::new(),
+            }),
+            probe_done: Notify::new(),
+        }
+    }
+
+    /// An open circuit for an optional dependency doesn't affect readiness
+    #[must_use]
+    pub fn not_required_for_ready(mut self) -> Self {
+        self.required_for_ready = false;
+        self
+    }
+
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn with_inner<T>(&self, f: impl FnOnce(&mut CircuitInner) -> T) -> T {
+        let mut inner = match self.inner.lock() {
+            Ok(inner) => inner,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        inner.refresh(Instant::now());
+        let result = f(&mut inner);
+        metrics::CIRCUIT_STATE
+            .with_label_values(&[self.name])
+            .set(inner.state.gauge_value());
+        result
+    }
+
+    pub fn configure(&self, settings: CircuitSettings) {
+        self.with_inner(|inner| inner.settings = settings);
+    }
+
+    #[must_use]
+    pub fn state(&self) -> CircuitState {
+        self.with_inner(|inner| inner.state)
+    }
+
+    #[must_use]
+    pub fn status(&self) -> CircuitStatus {
+        self.with_inner(|inner| {
+            inner.expire(Instant::now());
+            CircuitStatus {
+                name: self.name,
+                required_for_ready: self.required_for_ready,
+                state: inner.state,
+                failures: inner.failures(),
+                calls: inner.outcomes.len(),
+            }
+        })
+    }
+
+    /// Admit a call without waiting, in half open state only the probe
+    /// gets through
+    /// # Errors
+    /// Return [`CircuitOpen`] if the circuit is open or another call is
+    /// probing it
+    pub fn check(&self) -> Result<Admission, Error> {
+        self.with_inner(|inner| inner.admit(Instant::now()))
+            .map_err(|_| CircuitOpen { name: self.name }.into())
+    }
+
+    /// Wait until the circuit admits a call, in half open state only one
+    /// caller proceeds and the others wait for its outcome
+    pub async fn wait(&self) -> Admission {
+        loop {
+            let probe_done = self.probe_done.notified();
+            tokio::pin!(probe_done);
+            probe_done.as_mut().enable();
+            match self.with_inner(|inner| inner.admit(Instant::now())) {
+                Err(remaining) => {
+                    let sleep = tokio::time::sleep(remaining.max(Duration::from_millis(10)));
+                    tokio::select! {
+                        () = sleep => {},
+                        () = probe_done => {},
+                    }
+                }
+                Ok(admission) => return admission,
+            }
+        }
+    }
+
+    /// A successful probe closes a half open circuit
+    pub fn record_success(&self, admission: Admission) {
+        self.with_inner(|inner| {
+            let now = Instant::now();
+            if inner.is_probe(admission) {
+                inner.state = CircuitState::Closed;
+                inner.opened_at = None;
+                inner.probe = None;
+                inner.outcomes.clear();
+            }
+            inner.outcomes.push_back((now, true));
+            inner.expire(now);
+        });
+        self.probe_done.notify_waiters();
+    }
+
+    /// A failed probe reopens a half open circuit, a closed one opens once
+    /// failures pass the threshold and rate
+    pub fn record_failure(&self, admission: Admission) {
+        self.with_inner(|inner| {
+            let now = Instant::now();
+            inner.outcomes.push_back((now, false));
+            inner.expire(now);
+            let failures = inner.failures();
+            let rate = failures as f64 / inner.outcomes.len() as f64;
+            let trip = inner.is_probe(admission)
+                || (inner.state == CircuitState::Closed
+                    && failures >= inner.settings.failure_threshold
+                    && rate >= inner.settings.failure_rate);
+            if trip {
+                inner.state = CircuitState::Open;
+                inner.opened_at = Some(now);
+                inner.probe = None;
+            }
+        });
+        self.probe_done.notify_waiters();
+    }
+
+    /// The admitted call was not made, a probe hands over to the next caller
+    pub fn record_skipped(&self, admission: Admission) {
+        self.with_inner(|inner| {
+            if inner.is_probe(admission) {
+                inner.probe = None;
+            }
+        });
+        self.probe_done.notify_waiters();
+    }
+
+    /// Record a result without consuming it
+    pub fn record<T, E>(&self, admission: Admission, result: &Result<T, E>) {
+        if result.is_ok() {
+            self.record_success(admission);
+        } else {
+            self.record_failure(admission);
+        }
+    }
+}
+
+/// One circuit per external dependency, the email circuits only affect
+/// email delivery and not readiness
+#[derive(Debug)]
+pub struct CircuitBreakers {
+    pub telegram: CircuitBreaker,
+    pub ses: CircuitBreaker,
+    pub smtp: CircuitBreaker,
+    pub token_file: CircuitBreaker,
+}
+
+impl Default for CircuitBreakers {
+    fn default() -> Self {
+        let settings = CircuitSettings::default();
+        Self {
+            telegram: CircuitBreaker::new("telegram", settings),
+            ses: CircuitBreaker::new("ses", settings).not_required_for_ready(),
+            smtp: CircuitBreaker::new("smtp", settings).not_required_for_ready(),
+            token_file: CircuitBreaker::new("token_file", settings),
+        }
+    }
+}
+
+impl CircuitBreakers {
+    pub fn configure(&self, settings: CircuitSettings) {
+        for circuit in self.all() {
+            circuit.configure(settings);
+        }
+    }
+
+    #[must_use]
+    pub fn all(&self) -> [&CircuitBreaker; 4] {
+        [&self.telegram, &self.ses, &self.smtp, &self.token_file]
+    }
+
+    /// Publish the current state of every circuit, an open circuit whose
+    /// timeout has passed moves to half open
+    pub fn update_metrics(&self) {
+        for circuit in self.all() {
+            circuit.with_inner(|_| ());
+        }
+    }
+
+    #[must_use]
+    pub fn statuses(&self) -> Vec<CircuitStatus> {
+        self.all().iter().map(|c| c.status()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use crate::circuit_breaker::{
+        Admission, CircuitBreaker, CircuitBreakers, CircuitSettings, CircuitState,
+    };
+
+    #[tokio::test]
+    async fn test_circuit_breaker() {
+        let circuit = CircuitBreaker::new(
+            "test",
+            CircuitSettings {
+                failure_threshold: 3,
+                failure_rate: 0.5,
+                window: Duration::from_secs(60),
+                open_duration: Duration::from_millis(50),
+            },
+        );
+        let call = circuit.check().unwrap();
+        for _ in 0..4 {
+            circuit.record_success(call);
+        }
+        circuit.record_failure(call);
+        circuit.record_failure(call);
+        circuit.record_failure(call);
+        assert_eq!(circuit.state(), CircuitState::Closed);
+        circuit.record_failure(call);
+        assert_eq!(circuit.state(), CircuitState::Open);
+        assert!(circuit.check().is_err());
+        let status = circuit.status();
+        assert_eq!(status.failures, 4);
+        assert_eq!(status.calls, 8);
+        assert!(status.required_for_ready);
+
+        let probe = circuit.wait().await;
+        assert_eq!(circuit.state(), CircuitState::HalfOpen);
+        // only the probe is let through
+        assert!(circuit.check().is_err());
+        // a call admitted before the circuit opened neither closes nor
+        // reopens it
+        circuit.record_success(call);
+        circuit.record_failure(call);
+        assert_eq!(circuit.state(), CircuitState::HalfOpen);
+        circuit.record_failure(probe);
+        assert_eq!(circuit.state(), CircuitState::Open);
+
+        // a probe that makes no call hands over to the next caller
+        let probe = circuit.wait().await;
+        circuit.record_skipped(probe);
+        assert_eq!(circuit.state(), CircuitState::HalfOpen);
+        let probe = circuit.check().unwrap();
+        circuit.record_success(probe);
+        assert_eq!(circuit.state(), CircuitState::Closed);
+        assert_eq!(circuit.status().failures, 0);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_single_probe() {
+        let circuit = Arc::new(CircuitBreaker::new(
+            "probe",
+            CircuitSettings {
+                failure_threshold: 1,
+                failure_rate: 0.5,
+                window: Duration::from_secs(60),
+                open_duration: Duration::from_millis(50),
+            },
+        ));
+        circuit.record_failure(Admission::default());
+        assert_eq!(circuit.state(), CircuitState::Open);
+
+        let admitted = Arc::new(Mutex::new(Vec::new()));
+        let waiters: Vec<_> = (0..5)
+            .map(|_| {
+                let circuit = circuit.clone();
+                let admitted = admitted.clone();
+                tokio::spawn(async move {
+                    let admission = circuit.wait().await;
+                    admitted.lock().unwrap().push(admission);
+                })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        assert_eq!(circuit.state(), CircuitState::HalfOpen);
+        let probe = {
+            let admitted = admitted.lock().unwrap();
+            assert_eq!(admitted.len(), 1);
+            admitted[0]
+        };
+
+        circuit.record_success(probe);
+        for waiter in waiters {
+            tokio::time::timeout(Duration::from_secs(1), waiter)
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        assert_eq!(admitted.lock().unwrap().len(), 5);
+        assert_eq!(circuit.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_circuit_window() {
+        let circuit = CircuitBreaker::new(
+            "window",
+            CircuitSettings {
+                failure_threshold: 2,
+                failure_rate: 1.0,
+                window: Duration::from_millis(20),
+                open_duration: Duration::from_secs(60),
+            },
+        );
+        let call = Admission::default();
+        circuit.record_failure(call);
+        std::thread::sleep(Duration::from_millis(30));
+        circuit.record_failure(call);
+        assert_eq!(circuit.state(), CircuitState::Closed);
+        circuit.record_failure(call);
+        assert_eq!(circuit.state(), CircuitState::Open);
+    }
+
+    #[test]
+    fn test_circuit_readiness() {
+        let required: Vec<_> = CircuitBreakers::default()
+            .statuses()
+            .into_iter()
+            .filter(|status| status.required_for_ready)
+            .map(|status| status.name)
+            .collect();
+        assert_eq!(required, ["telegram", "token_file"]);
+    }
+}
@@ -1,30 +1,157 @@
 use anyhow::{format_err, Error};
 use derive_more::{Deref, FromStr, Into};
 use serde::{Deserialize, Serialize};
-use stack_string::StackString;
+use stack_string::{format_sstr, StackString};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     convert::TryFrom,
+    fs::Permissions,
+    net::IpAddr,
+    os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
     sync::Arc,
+    time::Instant,
 };
-use tokio::fs;
+use tokio::{fs, io::AsyncWriteExt};
 use url::Url;
 
-#[derive(Default, Debug, Deserialize, Serialize)]
+use crate::{
+    config_file::{ConfigProblem, ConfigSources},
+    email::EmailAddress,
+    email_sender::EmailBackend,
+    smtp_client::SmtpSecurity,
+    sns::parse_topic_arns,
+};
+
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
 pub struct ConfigInner {
     pub telegram_bot_token: Option<StackString>,
     pub remote_url: Option<UrlWrapper>,
     pub remote_token: Option<StackString>,
     pub api_tokens_path: Option<PathBuf>,
     pub sending_email_address: Option<StackString>,
+    /// Display name shown with `sending_email_address`
+    pub sending_email_name: Option<StackString>,
+    /// AWS region for SES, overrides `AWS_REGION`
+    pub ses_region: Option<StackString>,
+    /// SES endpoint, e.g. a LocalStack or mock server
+    pub ses_endpoint_url: Option<UrlWrapper>,
+    /// AWS credentials profile for SES, overrides `AWS_PROFILE`
+    pub ses_profile: Option<StackString>,
+    /// Fraction of the SES daily quota held back, sends that would use it
+    /// wait for quota instead
+    #[serde(default = "default_ses_quota_reserve")]
+    pub ses_quota_reserve: f64,
+    /// How long a send waits for daily quota before failing
+    #[serde(default = "default_ses_quota_wait_seconds")]
+    pub ses_quota_wait_seconds: u64,
+    /// How often the SES quota is fetched while sending
+    #[serde(default = "default_ses_quota_refresh_seconds")]
+    pub ses_quota_refresh_seconds: u64,
+    /// SESv2 configuration set for event publishing
+    pub ses_configuration_set: Option<StackString>,
+    /// SESv2 contact list used for unsubscribe links
+    pub ses_contact_list: Option<StackString>,
+    /// Topic of `ses_contact_list` the emails belong to
+    pub ses_contact_list_topic: Option<StackString>,
+    /// Comma separated SNS topics allowed to post SES bounces and complaints
+    pub sns_topic_arns: Option<StackString>,
+    /// Addresses that bounced or complained, never emailed
+    pub suppression_list_path: Option<PathBuf>,
+    /// Email still queued on shutdown, sent after the next start
+    pub email_queue_path: Option<PathBuf>,
+    /// `ses` (the default), `sesv2` or `smtp`
+    pub email_backend: Option<StackString>,
+    pub smtp_host: Option<StackString>,
+    /// Defaults to the usual port for `smtp_security`
+    pub smtp_port: Option<u16>,
+    /// `starttls` (the default), `tls` or `none`
+    pub smtp_security: Option<StackString>,
+    pub smtp_username: Option<StackString>,
+    pub smtp_password: Option<StackString>,
+    /// Extra CA certificates trusted for the SMTP relay
+    pub smtp_ca_path: Option<PathBuf>,
+    pub templates_path: Option<PathBuf>,
+    pub scheduled_messages_path: Option<PathBuf>,
+    pub reminders_path: Option<PathBuf>,
+    #[serde(default = "default_host")]
+    pub host: StackString,
     #[serde(default = "default_port")]
     pub port: u32,
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+    /// Listen on this unix socket instead of `host:port`
+    pub unix_socket_path: Option<PathBuf>,
+    /// Octal permissions for `unix_socket_path`, e.g. `660`
+    pub unix_socket_mode: Option<StackString>,
+    #[serde(default = "default_flood_threshold")]
+    pub flood_threshold: usize,
+    #[serde(default = "default_flood_window_minutes")]
+    pub flood_window_minutes: u64,
+    #[serde(default = "default_crash_loop_threshold")]
+    pub crash_loop_threshold: usize,
+    #[serde(default = "default_crash_loop_window_minutes")]
+    pub crash_loop_window_minutes: u64,
+    #[serde(default = "default_shutdown_timeout_seconds")]
+    pub shutdown_timeout_seconds: u64,
+    #[serde(default = "default_circuit_failure_threshold")]
+    pub circuit_failure_threshold: usize,
+    #[serde(default = "default_circuit_failure_rate")]
+    pub circuit_failure_rate: f64,
+    #[serde(default = "default_circuit_window_seconds")]
+    pub circuit_window_seconds: u64,
+    #[serde(default = "default_circuit_open_seconds")]
+    pub circuit_open_seconds: u64,
+    /// Structured config file the settings were read from
+    #[serde(skip)]
+    pub config_path: Option<PathBuf>,
+    /// Port given on the command line, it replaces `port` on every reload
+    #[serde(skip)]
+    pub port_override: Option<u32>,
 }
 
+fn default_host() -> StackString {
+    "127.0.0.1".into()
+}
 fn default_port() -> u32 {
     4083
 }
+fn default_flood_threshold() -> usize {
+    20
+}
+fn default_flood_window_minutes() -> u64 {
+    10
+}
+fn default_crash_loop_threshold() -> usize {
+    5
+}
+fn default_crash_loop_window_minutes() -> u64 {
+    10
+}
+fn default_shutdown_timeout_seconds() -> u64 {
+    30
+}
+fn default_circuit_failure_threshold() -> usize {
+    5
+}
+fn default_circuit_failure_rate() -> f64 {
+    0.5
+}
+fn default_circuit_window_seconds() -> u64 {
+    60
+}
+fn default_circuit_open_seconds() -> u64 {
+    30
+}
+fn default_ses_quota_reserve() -> f64 {
+    0.05
+}
+fn default_ses_quota_wait_seconds() -> u64 {
+    120
+}
+fn default_ses_quota_refresh_seconds() -> u64 {
+    60
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug, Into, PartialEq, Deref, FromStr, Eq)]
 #[serde(into = "String", try_from = "String")]
@@ -54,29 +181,294 @@ impl TryFrom<String> for UrlWrapper {
 pub struct Config(Arc<ConfigInner>);
 
 impl Config {
+    /// Read the structured config file (if any) with environment variables,
+    /// including those set in `config.env`, taking precedence
     /// # Errors
-    /// Return error if deserializing environment variables fails
+    /// Return error if the config file or any setting is invalid
     pub fn init_config() -> Result<Self, Error> {
-        let fname = Path::new("config.env");
+        Self::load(None, false)
+    }
+
+    /// Like `init_config` but reading the config file at `path`
+    /// # Errors
+    /// Return error if the config file or any setting is invalid
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        Self::load(Some(path), false)
+    }
+
+    /// Re-read the env file and the config file this configuration was
+    /// loaded from, values in the env file replace variables set by a
+    /// previous load
+    /// # Errors
+    /// Return error if the config file or any setting is invalid
+    pub fn reload(&self) -> Result<Self, Error> {
+        Self::load(self.config_path.as_deref(), true)
+            .map(|config| config.with_port_override(self.port_override))
+    }
+
+    /// Listen on `port` instead of the configured port, also after a reload
+    #[must_use]
+    pub fn with_port_override(self, port: Option<u32>) -> Self {
+        let Some(port) = port else {
+            return self;
+        };
+        let mut inner = (*self.0).clone();
+        inner.port = port;
+        inner.port_override = Some(port);
+        inner.into()
+    }
+
+    fn config_dir() -> Result<PathBuf, Error> {
         let config_dir = dirs::config_dir().ok_or_else(|| format_err!("No CONFIG directory"))?;
-        let default_fname = config_dir.join("notification_app_rust").join("config.env");
+        Ok(config_dir.join("notification_app_rust"))
+    }
 
+    fn load_env_file(override_env: bool) -> Result<PathBuf, Error> {
+        let fname = Path::new("config.env");
         let env_file = if fname.exists() {
-            fname
+            fname.to_path_buf()
         } else {
-            &default_fname
+            Self::config_dir()?.join("config.env")
         };
 
-        dotenvy::dotenv().ok();
+        if override_env {
+            dotenvy::dotenv_override().ok();
+            if env_file.exists() {
+                dotenvy::from_path_override(&env_file)?;
+            }
+        } else {
+            dotenvy::dotenv().ok();
+            if env_file.exists() {
+                dotenvy::from_path(&env_file).ok();
+            }
+        }
+        Ok(env_file)
+    }
+
+    fn sources(path: Option<&Path>, override_env: bool) -> Result<(ConfigSources, PathBuf), Error> {
+        let env_file = Self::load_env_file(override_env)?;
+        let path = match path {
+            Some(path) => Some(path.to_path_buf()),
+            None => ConfigSources::find_config_file(Some(&Self::config_dir()?)),
+        };
+        let sources = ConfigSources::collect(path.as_deref(), std::env::vars());
+        Ok((sources, env_file))
+    }
 
-        if env_file.exists() {
-            dotenvy::from_path(env_file).ok();
+    fn load(path: Option<&Path>, override_env: bool) -> Result<Self, Error> {
+        let (sources, env_file) = Self::sources(path, override_env)?;
+        if !sources.problems().is_empty() {
+            let problems: Vec<_> = sources.problems().iter().map(ToString::to_string).collect();
+            return Err(format_err!("{}", problems.join(", ")));
         }
 
-        let conf: ConfigInner = envy::from_env()?;
+        let mut conf: ConfigInner = envy::from_iter(sources.vars())?;
+        let base_dir = sources
+            .path
+            .as_deref()
+            .or(Some(&env_file))
+            .and_then(Path::parent);
+        if conf.templates_path.is_none() {
+            conf.templates_path = base_dir.map(|d| d.join("templates"));
+        }
+        if conf.scheduled_messages_path.is_none() {
+            conf.scheduled_messages_path = base_dir.map(|d| d.join("scheduled_messages.json"));
+        }
+        if conf.reminders_path.is_none() {
+            conf.reminders_path = base_dir.map(|d| d.join("reminders.json"));
+        }
+        if conf.suppression_list_path.is_none() {
+            conf.suppression_list_path = base_dir.map(|d| d.join("suppression_list.json"));
+        }
+        if conf.email_queue_path.is_none() {
+            conf.email_queue_path = base_dir.map(|d| d.join("email_queue.json"));
+        }
+        conf.config_path = sources.path;
 
         Ok(Self(Arc::new(conf)))
     }
+
+    /// Every problem with the configuration that would be loaded from `path`
+    /// (or the default config file), each with the file key or environment
+    /// variable it came from
+    /// # Errors
+    /// Return error if the config directory cannot be determined
+    pub fn check(path: Option<&Path>) -> Result<Vec<ConfigProblem>, Error> {
+        let (sources, _) = Self::sources(path, false)?;
+        let mut problems = sources.problems().to_vec();
+        match envy::from_iter::<_, ConfigInner>(sources.vars()) {
+            Ok(conf) => {
+                for (env, message) in Self::from(conf).problems() {
+                    problems.push(sources.problem(env, message));
+                }
+            }
+            Err(e) => problems.push(ConfigProblem::new(None, format_sstr!("{e}"))),
+        }
+        Ok(problems)
+    }
+
+    /// `sending_email_address` with `sending_email_name` as its display name
+    /// # Errors
+    /// Return error if no sending address is set or it is invalid
+    pub fn sender(&self) -> Result<EmailAddress, Error> {
+        let address = self
+            .sending_email_address
+            .as_ref()
+            .ok_or_else(|| format_err!("No sending email address"))?;
+        let mut sender: EmailAddress = address.parse()?;
+        if let Some(name) = &self.sending_email_name {
+            sender.name = Some(name.clone());
+        }
+        Ok(sender)
+    }
+
+    /// # Errors
+    /// Return error if `EMAIL_BACKEND` is not `ses`, `sesv2` or `smtp`
+    pub fn email_backend(&self) -> Result<EmailBackend, Error> {
+        self.email_backend
+            .as_ref()
+            .map_or(Ok(EmailBackend::Ses), |backend| backend.parse())
+    }
+
+    /// Topics accepted by the SNS endpoint, empty when `SNS_TOPIC_ARNS` is
+    /// unset
+    #[must_use]
+    pub fn sns_topic_arns(&self) -> HashSet<StackString> {
+        self.sns_topic_arns
+            .as_ref()
+            .map(|arns| parse_topic_arns(arns))
+            .unwrap_or_default()
+    }
+
+    fn problems(&self) -> Vec<(&'static str, StackString)> {
+        let mut problems = Vec::new();
+        if self.api_tokens_path.is_none() {
+            problems.push(("API_TOKENS_PATH", "API_TOKENS_PATH is not set".into()));
+        }
+        if self.host.parse::<IpAddr>().is_err() {
+            problems.push((
+                "HOST",
+                format_sstr!("HOST {} is not an ip address", self.host),
+            ));
+        }
+        if self.port == 0 || u16::try_from(self.port).is_err() {
+            problems.push(("PORT", format_sstr!("PORT {} is out of range", self.port)));
+        }
+        if self.tls_cert_path.is_some() != self.tls_key_path.is_some() {
+            problems.push((
+                "TLS_CERT_PATH",
+                "TLS_CERT_PATH and TLS_KEY_PATH must be set together".into(),
+            ));
+        }
+        if let Some(mode) = &self.unix_socket_mode {
+            if u32::from_str_radix(mode, 8).is_err() {
+                problems.push((
+                    "UNIX_SOCKET_MODE",
+                    format_sstr!("UNIX_SOCKET_MODE {mode} is not an octal mode"),
+                ));
+            }
+        }
+        if let Some(address) = &self.sending_email_address {
+            if let Err(e) = address.parse::<EmailAddress>() {
+                problems.push((
+                    "SENDING_EMAIL_ADDRESS",
+                    format_sstr!("SENDING_EMAIL_ADDRESS {e}"),
+                ));
+            }
+        }
+        match self.email_backend() {
+            Ok(EmailBackend::Smtp) if self.smtp_host.is_none() => {
+                problems.push((
+                    "SMTP_HOST",
+                    "SMTP_HOST must be set when EMAIL_BACKEND is smtp".into(),
+                ));
+            }
+            Ok(_) => {}
+            Err(e) => problems.push(("EMAIL_BACKEND", format_sstr!("EMAIL_BACKEND {e}"))),
+        }
+        if let Some(security) = &self.smtp_security {
+            if let Err(e) = security.parse::<SmtpSecurity>() {
+                problems.push(("SMTP_SECURITY", format_sstr!("SMTP_SECURITY {e}")));
+            }
+        }
+        let smtp_security = self.smtp_security.as_ref().map(|s| s.parse());
+        if self.smtp_username.is_some() && matches!(smtp_security, Some(Ok(SmtpSecurity::None))) {
+            problems.push((
+                "SMTP_USERNAME",
+                "SMTP_USERNAME requires SMTP_SECURITY starttls or tls".into(),
+            ));
+        }
+        if self.smtp_username.is_some() != self.smtp_password.is_some() {
+            problems.push((
+                "SMTP_USERNAME",
+                "SMTP_USERNAME and SMTP_PASSWORD must be set together".into(),
+            ));
+        }
+        if !(self.circuit_failure_rate > 0.0 && self.circuit_failure_rate <= 1.0) {
+            problems.push((
+                "CIRCUIT_FAILURE_RATE",
+                format_sstr!(
+                    "CIRCUIT_FAILURE_RATE {} must be in (0, 1]",
+                    self.circuit_failure_rate
+                ),
+            ));
+        }
+        if let Some(arn) = self
+            .sns_topic_arns()
+            .into_iter()
+            .find(|arn| !arn.starts_with("arn:"))
+        {
+            problems.push((
+                "SNS_TOPIC_ARNS",
+                format_sstr!("SNS_TOPIC_ARNS {arn} is not an arn"),
+            ));
+        }
+        if self.ses_contact_list_topic.is_some() && self.ses_contact_list.is_none() {
+            problems.push((
+                "SES_CONTACT_LIST_TOPIC",
+                "SES_CONTACT_LIST_TOPIC requires SES_CONTACT_LIST".into(),
+            ));
+        }
+        if (self.ses_configuration_set.is_some() || self.ses_contact_list.is_some())
+            && !matches!(self.email_backend(), Ok(EmailBackend::SesV2))
+        {
+            problems.push((
+                "SES_CONFIGURATION_SET",
+                "SES_CONFIGURATION_SET and SES_CONTACT_LIST require EMAIL_BACKEND sesv2".into(),
+            ));
+        }
+        if !(0.0..1.0).contains(&self.ses_quota_reserve) {
+            problems.push((
+                "SES_QUOTA_RESERVE",
+                format_sstr!(
+                    "SES_QUOTA_RESERVE {} must be in [0, 1)",
+                    self.ses_quota_reserve
+                ),
+            ));
+        }
+        problems
+    }
+
+    /// # Errors
+    /// Return error listing every invalid setting
+    pub fn validate(&self) -> Result<(), Error> {
+        let problems: Vec<_> = self
+            .problems()
+            .into_iter()
+            .map(|(_, message)| message)
+            .collect();
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(format_err!("{}", problems.join(", ")))
+        }
+    }
+}
+
+impl From<ConfigInner> for Config {
+    fn from(item: ConfigInner) -> Self {
+        Self(Arc::new(item))
+    }
 }
 
 impl std::ops::Deref for Config {
@@ -109,6 +501,116 @@ impl ApiTokenConfig {
             .collect()
     }
 
+    /// Map of api token to the name of its entry
+    #[must_use]
+    pub fn api_token_names(&self) -> HashMap<StackString, StackString> {
+        self.0
+            .iter()
+            .filter_map(|(name, token)| {
+                token
+                    .api_token
+                    .as_ref()
+                    .map(|api_token| (api_token.clone(), name.clone()))
+            })
+            .collect()
+    }
+
+    /// Email sender settings of the entries that have any, keyed by entry
+    /// name
+    #[must_use]
+    pub fn email_senders(&self) -> HashMap<StackString, EmailIdentity> {
+        self.0
+            .iter()
+            .filter_map(|(name, token)| Some((name.clone(), token.email_sender.clone()?)))
+            .collect()
+    }
+
+    /// Check every sender address parses and, when `identities` is given,
+    /// is one of them or belongs to one of its domains
+    /// # Errors
+    /// Return error listing every invalid sender address
+    pub fn validate_senders(&self, identities: Option<&HashSet<StackString>>) -> Result<(), Error> {
+        let mut problems = Vec::new();
+        let mut entries: Vec<_> = self.0.iter().collect();
+        entries.sort_by_key(|(name, _)| *name);
+        for (name, token) in entries {
+            let Some(sender) = &token.email_sender else {
+                continue;
+            };
+            for (template, identity) in sender.identities() {
+                let Some(address) = &identity.address else {
+                    continue;
+                };
+                let context = template.map_or_else(
+                    || format_sstr!("{name}"),
+                    |template| format_sstr!("{name} template {template}"),
+                );
+                match address.parse::<EmailAddress>() {
+                    Ok(address) => {
+                        if identities.is_some_and(|ids| !address.is_verified(ids)) {
+                            problems.push(format_sstr!(
+                                "{context}: {} is not a verified SES identity",
+                                address.address
+                            ));
+                        }
+                    }
+                    Err(e) => problems.push(format_sstr!("{context}: {e}")),
+                }
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(format_err!("{}", problems.join(", ")))
+        }
+    }
+
+    /// Names of the entries allowed to use admin endpoints
+    #[must_use]
+    pub fn admin_names(&self) -> HashSet<StackString> {
+        self.0
+            .iter()
+            .filter(|(_, token)| token.admin)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    pub fn insert(&mut self, name: impl Into<StackString>, entry: ApiTokenEntry) {
+        self.0.insert(name.into(), entry);
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<ApiTokenEntry> {
+        self.0.remove(name)
+    }
+
+    /// Write the entries back as toml, replacing `p` atomically. The file
+    /// keeps the mode of `p`, a new one is only readable by its owner.
+    /// # Errors
+    /// Return error if serializing or writing the file fails
+    pub async fn write(&self, p: &Path) -> Result<(), Error> {
+        let entries: BTreeMap<_, _> = self.0.iter().collect();
+        let data = toml::to_string(&entries)?;
+        let mode = match fs::metadata(p).await {
+            Ok(metadata) => metadata.permissions().mode() & 0o7777,
+            Err(_) => 0o600,
+        };
+        let tmp = p.with_extension("tmp");
+        // the tokens must never be readable under the umask, not even briefly
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)
+            .await?;
+        fs::set_permissions(&tmp, Permissions::from_mode(0o600)).await?;
+        file.write_all(data.as_bytes()).await?;
+        file.sync_all().await?;
+        fs::set_permissions(&tmp, Permissions::from_mode(mode)).await?;
+        fs::rename(&tmp, p).await?;
+        Ok(())
+    }
+
     /// # Errors
     /// Return error if userid not found
     pub fn add_chatid(&mut self, userid: i64, chatid: i64) -> Result<(), Error> {
@@ -135,21 +637,118 @@ pub struct ApiTokenEntry {
     pub telegram_userid: Option<i64>,
     pub telegram_chatid: Option<i64>,
     pub api_token: Option<StackString>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub admin: bool,
+    /// From address, display name and subject of emails sent with the token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email_sender: Option<EmailIdentity>,
+}
+
+/// Sender settings of an api token, unset values fall back to
+/// `sending_email_address`, `sending_email_name` and the default subject
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct EmailIdentity {
+    /// From address, either `user@example.com` or `Name <user@example.com>`
+    pub address: Option<StackString>,
+    /// Display name, overrides a name given with `address`
+    pub name: Option<StackString>,
+    /// Subject of emails that do not set one
+    pub subject: Option<StackString>,
+    /// Settings for emails rendered from a template, keyed by template name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub templates: BTreeMap<StackString, EmailIdentity>,
+}
+
+impl EmailIdentity {
+    /// The token wide settings followed by those of each template
+    fn identities(&self) -> impl Iterator<Item = (Option<&StackString>, &Self)> {
+        std::iter::once((None, self)).chain(self.templates.iter().map(|(t, i)| (Some(t), i)))
+    }
+
+    /// Settings for an email rendered from `template`, the template's own
+    /// settings take precedence. A template that sets its own address keeps
+    /// that address's display name unless it also sets `name`.
+    #[must_use]
+    pub fn for_template(&self, template: Option<&str>) -> Self {
+        let overrides = template.and_then(|t| self.templates.get(t));
+        let pick = |f: fn(&Self) -> &Option<StackString>| {
+            overrides
+                .and_then(|o| f(o).clone())
+                .or_else(|| f(self).clone())
+        };
+        let name = match overrides {
+            Some(o) if o.address.is_some() => o.name.clone(),
+            _ => pick(|i| &i.name),
+        };
+        Self {
+            address: pick(|i| &i.address),
+            name,
+            subject: pick(|i| &i.subject),
+            templates: BTreeMap::new(),
+        }
+    }
+
+    /// `default` with the address and display name replaced by those set
+    /// here
+    /// # Errors
+    /// Return error if `address` is invalid
+    pub fn sender(&self, default: EmailAddress) -> Result<EmailAddress, Error> {
+        let mut sender = match &self.address {
+            Some(address) => address.parse()?,
+            None => default,
+        };
+        if let Some(name) = &self.name {
+            sender.name = Some(name.clone());
+        }
+        Ok(sender)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageFormat {
+    #[default]
+    Text,
+    Html,
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+impl MessageFormat {
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    fn is_text(&self) -> bool {
+        *self == Self::Text
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
 pub struct TelegramMessage {
     pub recipient: StackString,
     pub message: StackString,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<StackString>,
+    #[serde(default, skip_serializing_if = "MessageFormat::is_text")]
+    pub format: MessageFormat,
+    /// When the message was put on the delivery queue
+    #[serde(skip)]
+    pub queued: Option<Instant>,
+    /// Released after flood control held it, not held again, also after it
+    /// was persisted
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub released: bool,
 }
 
 #[cfg(test)]
 mod tests {
     use anyhow::Error;
-    use std::{env::var_os, io::Write};
-    use tempfile::NamedTempFile;
+    use stack_string::StackString;
+    use std::{
+        collections::HashSet, env::var_os, fs::Permissions, io::Write, os::unix::fs::PermissionsExt,
+    };
+    use tempfile::{Builder, NamedTempFile, TempDir};
 
-    use crate::config::{ApiTokenConfig, Config};
+    use crate::{
+        config::{ApiTokenConfig, ApiTokenEntry, Config, ConfigInner, EmailIdentity},
+        email_sender::EmailBackend,
+    };
 
     #[test]
     fn test_config() -> Result<(), Error> {
@@ -164,6 +763,127 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_api_token_config_write_mode() -> Result<(), Error> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("api_tokens.toml");
+        let mut config = ApiTokenConfig::default();
+        config.insert(
+            "user",
+            ApiTokenEntry {
+                api_token: Some("secret".into()),
+                ..ApiTokenEntry::default()
+            },
+        );
+        let mode = |path: &std::path::Path| -> Result<u32, Error> {
+            Ok(std::fs::metadata(path)?.permissions().mode() & 0o777)
+        };
+
+        config.write(&path).await?;
+        assert_eq!(mode(&path)?, 0o600);
+
+        std::fs::set_permissions(&path, Permissions::from_mode(0o640))?;
+        config.write(&path).await?;
+        assert_eq!(mode(&path)?, 0o640);
+        assert!(!path.with_extension("tmp").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate() {
+        let config: Config = ConfigInner {
+            api_tokens_path: Some("api_tokens.toml".into()),
+            host: "::".into(),
+            port: 4083,
+            circuit_failure_rate: 0.5,
+            sending_email_address: Some("alerts@example.com".into()),
+            sending_email_name: Some("Alerts".into()),
+            ..ConfigInner::default()
+        }
+        .into();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.email_backend().unwrap(), EmailBackend::Ses);
+        assert_eq!(
+            config.sender().unwrap().to_string(),
+            "\"Alerts\" <alerts@example.com>"
+        );
+
+        let config: Config = ConfigInner {
+            host: "localhost".into(),
+            port: 70000,
+            tls_cert_path: Some("cert.pem".into()),
+            unix_socket_mode: Some("rw".into()),
+            sending_email_address: Some("noreply".into()),
+            circuit_failure_rate: 2.0,
+            email_backend: Some("smtp".into()),
+            smtp_security: Some("ssl".into()),
+            smtp_username: Some("relay".into()),
+            ses_quota_reserve: 1.5,
+            sns_topic_arns: Some("arn:aws:sns:us-east-1:1:feedback,feedback".into()),
+            ses_configuration_set: Some("tracked".into()),
+            ses_contact_list_topic: Some("weekly".into()),
+            ..ConfigInner::default()
+        }
+        .into();
+        let error = config.validate().unwrap_err().to_string();
+        for problem in [
+            "API_TOKENS_PATH",
+            "HOST",
+            "PORT",
+            "TLS_CERT_PATH",
+            "UNIX_SOCKET_MODE",
+            "SENDING_EMAIL_ADDRESS",
+            "CIRCUIT_FAILURE_RATE",
+            "SMTP_HOST",
+            "SMTP_SECURITY",
+            "SMTP_USERNAME",
+            "SES_QUOTA_RESERVE",
+            "SNS_TOPIC_ARNS",
+            "SES_CONFIGURATION_SET",
+            "SES_CONTACT_LIST_TOPIC",
+        ] {
+            assert!(error.contains(problem), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_config_file() -> Result<(), Error> {
+        let mut temp = Builder::new().suffix(".toml").tempfile()?;
+        temp.write_all(b"[server]\nport = 8080\n\n[limits]\nflood_threshold = 3\n")?;
+        let config = Config::from_file(temp.path())?;
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.flood_threshold, 3);
+        assert_eq!(config.config_path.as_deref(), Some(temp.path()));
+        assert!(Config::check(Some(temp.path()))?.is_empty());
+        let config = config.with_port_override(Some(9090));
+        assert_eq!(config.port, 9090);
+        assert_eq!(config.reload()?.port, 9090);
+
+        let mut temp = Builder::new().suffix(".toml").tempfile()?;
+        temp.write_all(
+            b"[server]\nhost = \"localhost\"\nport = 70000\n\n[limits]\nflood_threshold = \
+              \"many\"\n",
+        )?;
+        assert!(Config::from_file(temp.path()).is_err());
+        let problems: Vec<String> = Config::check(Some(temp.path()))?
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        let path = temp.path().display();
+        assert_eq!(
+            problems,
+            vec![
+                format!(
+                    "{path} [limits.flood_threshold]: invalid value \"many\" for \
+                     FLOOD_THRESHOLD: invalid digit found in string"
+                ),
+                format!("{path} [server.host]: HOST localhost is not an ip address"),
+                format!("{path} [server.port]: PORT 70000 is out of range"),
+            ]
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_api_token_config() -> Result<(), Error> {
         let mut temp = NamedTempFile::new()?;
@@ -180,6 +900,77 @@ mod tests {
         assert_eq!(api_tokens.telegram_userid, Some(8675309));
         assert_eq!(api_tokens.telegram_chatid, Some(8675310));
 
+        let names = config.api_token_names();
+        assert_eq!(names.get("MTg0OWRhNDQ5NDNi").unwrap(), "user");
+        assert!(config.admin_names().is_empty());
+
+        let mut config = config;
+        config.insert(
+            "ops",
+            ApiTokenEntry {
+                api_token: Some("admin".into()),
+                admin: true,
+                ..ApiTokenEntry::default()
+            },
+        );
+        config.write(temp.path()).await?;
+        let mut config = ApiTokenConfig::new(temp.path()).await?;
+        assert_eq!(config.len(), 2);
+        assert!(config.admin_names().contains("ops"));
+        assert_eq!(config.get("user").unwrap().telegram_chatid, Some(8675310));
+        assert!(config.remove("ops").is_some());
+        assert!(config.remove("ops").is_none());
+
+        let mut temp = NamedTempFile::new()?;
+        temp.write_all(
+            br#"
+            [billing]
+            api_token = "billing-token"
+            [billing.email_sender]
+            address = "billing@example.com"
+            name = "Billing"
+            [billing.email_sender.templates.invoice]
+            address = "Invoices <invoices@mail.example.com>"
+            subject = "Your invoice"
+            "#,
+        )?;
+        let config = ApiTokenConfig::new(temp.path()).await?;
+        let senders = config.email_senders();
+        let billing = &senders["billing"];
+        let invoice = billing.for_template(Some("invoice"));
+        assert!(invoice.name.is_none());
+        assert_eq!(invoice.subject.as_ref().unwrap(), "Your invoice");
+        assert_eq!(
+            invoice.sender("bot@example.com".parse()?)?.to_string(),
+            "\"Invoices\" <invoices@mail.example.com>"
+        );
+        let other = billing.for_template(Some("receipt"));
+        assert_eq!(other.address.as_ref().unwrap(), "billing@example.com");
+        assert!(other.subject.is_none());
+        assert_eq!(
+            EmailIdentity::default()
+                .sender("bot@example.com".parse()?)?
+                .address,
+            "bot@example.com"
+        );
+
+        assert!(config.validate_senders(None).is_ok());
+        let identities: HashSet<StackString> =
+            vec!["example.com".into(), "Invoices@Mail.Example.com".into()]
+                .into_iter()
+                .collect();
+        assert!(config.validate_senders(Some(&identities)).is_ok());
+        let identities: HashSet<StackString> =
+            vec!["billing@example.com".into()].into_iter().collect();
+        let error = config
+            .validate_senders(Some(&identities))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            "billing template invoice: invoices@mail.example.com is not a verified SES identity"
+        );
+
         Ok(())
     }
 }
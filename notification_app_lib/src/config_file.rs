@@ -0,0 +1,597 @@
+use anyhow::{format_err, Error};
+use serde_json::Value;
+use stack_string::{format_sstr, StackString};
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+use url::Url;
+
+/// Names searched for in the working directory and the config directory
+pub const CONFIG_FILE_NAMES: [&str; 3] = ["config.toml", "config.yaml", "config.yml"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Text,
+    Path,
+    Url,
+    Integer,
+    Float,
+}
+
+/// A setting that can be given as `key` in `[section]` of the config file or
+/// as the environment variable `env`, secrets may also be read from the file
+/// named by `key_file` / `ENV_FILE`
+struct Setting {
+    section: &'static str,
+    key: &'static str,
+    env: &'static str,
+    kind: Kind,
+    secret: bool,
+}
+
+const fn setting(
+    section: &'static str,
+    key: &'static str,
+    env: &'static str,
+    kind: Kind,
+) -> Setting {
+    Setting {
+        section,
+        key,
+        env,
+        kind,
+        secret: false,
+    }
+}
+
+const fn secret(section: &'static str, key: &'static str, env: &'static str) -> Setting {
+    Setting {
+        section,
+        key,
+        env,
+        kind: Kind::Text,
+        secret: true,
+    }
+}
+
+const SETTINGS: &[Setting] = &[
+    setting("server", "host", "HOST", Kind::Text),
+    setting("server", "port", "PORT", Kind::Integer),
+    setting("server", "tls_cert_path", "TLS_CERT_PATH", Kind::Path),
+    setting("server", "tls_key_path", "TLS_KEY_PATH", Kind::Path),
+    setting("server", "unix_socket_path", "UNIX_SOCKET_PATH", Kind::Path),
+    setting("server", "unix_socket_mode", "UNIX_SOCKET_MODE", Kind::Text),
+    setting("server", "api_tokens_path", "API_TOKENS_PATH", Kind::Path),
+    setting(
+        "server",
+        "shutdown_timeout_seconds",
+        "SHUTDOWN_TIMEOUT_SECONDS",
+        Kind::Integer,
+    ),
+    secret("telegram", "bot_token", "TELEGRAM_BOT_TOKEN"),
+    setting("telegram", "remote_url", "REMOTE_URL", Kind::Url),
+    secret("telegram", "remote_token", "REMOTE_TOKEN"),
+    setting(
+        "telegram",
+        "scheduled_messages_path",
+        "SCHEDULED_MESSAGES_PATH",
+        Kind::Path,
+    ),
+    setting("telegram", "reminders_path", "REMINDERS_PATH", Kind::Path),
+    setting(
+        "ses",
+        "sending_email_address",
+        "SENDING_EMAIL_ADDRESS",
+        Kind::Text,
+    ),
+    setting(
+        "ses",
+        "sending_email_name",
+        "SENDING_EMAIL_NAME",
+        Kind::Text,
+    ),
+    setting(
+        "limits",
+        "flood_threshold",
+        "FLOOD_THRESHOLD",
+        Kind::Integer,
+    ),
+    setting(
+        "limits",
+        "flood_window_minutes",
+        "FLOOD_WINDOW_MINUTES",
+        Kind::Integer,
+    ),
+    setting(
+        "limits",
+        "crash_loop_threshold",
+        "CRASH_LOOP_THRESHOLD",
+        Kind::Integer,
+    ),
+    setting(
+        "limits",
+        "crash_loop_window_minutes",
+        "CRASH_LOOP_WINDOW_MINUTES",
+        Kind::Integer,
+    ),
+    setting(
+        "limits",
+        "circuit_failure_threshold",
+        "CIRCUIT_FAILURE_THRESHOLD",
+        Kind::Integer,
+    ),
+    setting(
+        "limits",
+        "circuit_failure_rate",
+        "CIRCUIT_FAILURE_RATE",
+        Kind::Float,
+    ),
+    setting(
+        "limits",
+        "circuit_window_seconds",
+        "CIRCUIT_WINDOW_SECONDS",
+        Kind::Integer,
+    ),
+    setting(
+        "limits",
+        "circuit_open_seconds",
+        "CIRCUIT_OPEN_SECONDS",
+        Kind::Integer,
+    ),
+    setting("ses", "region", "SES_REGION", Kind::Text),
+    setting("ses", "endpoint_url", "SES_ENDPOINT_URL", Kind::Url),
+    setting("ses", "profile", "SES_PROFILE", Kind::Text),
+    setting("ses", "quota_reserve", "SES_QUOTA_RESERVE", Kind::Float),
+    setting(
+        "ses",
+        "quota_wait_seconds",
+        "SES_QUOTA_WAIT_SECONDS",
+        Kind::Integer,
+    ),
+    setting(
+        "ses",
+        "quota_refresh_seconds",
+        "SES_QUOTA_REFRESH_SECONDS",
+        Kind::Integer,
+    ),
+    setting(
+        "ses",
+        "configuration_set",
+        "SES_CONFIGURATION_SET",
+        Kind::Text,
+    ),
+    setting("ses", "contact_list", "SES_CONTACT_LIST", Kind::Text),
+    setting(
+        "ses",
+        "contact_list_topic",
+        "SES_CONTACT_LIST_TOPIC",
+        Kind::Text,
+    ),
+    setting("ses", "sns_topic_arns", "SNS_TOPIC_ARNS", Kind::Text),
+    setting(
+        "ses",
+        "suppression_list_path",
+        "SUPPRESSION_LIST_PATH",
+        Kind::Path,
+    ),
+    setting("ses", "email_queue_path", "EMAIL_QUEUE_PATH", Kind::Path),
+    setting("smtp", "host", "SMTP_HOST", Kind::Text),
+    setting("smtp", "port", "SMTP_PORT", Kind::Integer),
+    setting("smtp", "security", "SMTP_SECURITY", Kind::Text),
+    setting("smtp", "username", "SMTP_USERNAME", Kind::Text),
+    secret("smtp", "password", "SMTP_PASSWORD"),
+    setting("smtp", "ca_path", "SMTP_CA_PATH", Kind::Path),
+    setting("channels", "templates_path", "TEMPLATES_PATH", Kind::Path),
+    setting("channels", "email_backend", "EMAIL_BACKEND", Kind::Text),
+];
+
+/// Where a setting was read from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    File {
+        path: PathBuf,
+        key: StackString,
+    },
+    Env(StackString),
+    /// Secret read from the file named by a `*_file` key or `*_FILE` variable
+    SecretFile {
+        from: Box<Source>,
+        path: PathBuf,
+    },
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::File { path, key } if key.is_empty() => write!(f, "{}", path.display()),
+            Self::File { path, key } => write!(f, "{} [{key}]", path.display()),
+            Self::Env(var) => write!(f, "environment variable {var}"),
+            Self::SecretFile { from, path } => write!(f, "{} ({from})", path.display()),
+        }
+    }
+}
+
+/// A single invalid setting and where it came from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigProblem {
+    pub location: Option<StackString>,
+    pub message: StackString,
+}
+
+impl ConfigProblem {
+    #[must_use]
+    pub fn new(location: Option<StackString>, message: impl Into<StackString>) -> Self {
+        Self {
+            location,
+            message: message.into(),
+        }
+    }
+
+    fn at(source: &Source, message: impl Into<StackString>) -> Self {
+        Self::new(Some(format_sstr!("{source}")), message)
+    }
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{location}: {}", self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Settings merged from the config file and the environment, keyed by
+/// environment variable name
+#[derive(Debug, Default)]
+pub struct ConfigSources {
+    pub path: Option<PathBuf>,
+    values: BTreeMap<&'static str, (StackString, Source)>,
+    problems: Vec<ConfigProblem>,
+}
+
+impl ConfigSources {
+    /// Read `path` (toml or yaml, chosen by extension) and overlay every
+    /// known variable in `env`, problems are collected rather than returned
+    /// so all of them can be reported at once
+    pub fn collect(path: Option<&Path>, env: impl IntoIterator<Item = (String, String)>) -> Self {
+        let mut sources = Self {
+            path: path.map(Path::to_path_buf),
+            ..Self::default()
+        };
+        if let Some(path) = path {
+            sources.read_file(path);
+        }
+        sources.read_env(env);
+        sources.check_kinds();
+        sources
+    }
+
+    /// The file that should be used when none is given explicitly
+    #[must_use]
+    pub fn find_config_file(config_dir: Option<&Path>) -> Option<PathBuf> {
+        if let Some(path) = std::env::var_os("CONFIG_PATH") {
+            return Some(path.into());
+        }
+        let dirs = [Some(Path::new(".")), config_dir];
+        dirs.iter()
+            .flatten()
+            .flat_map(|dir| CONFIG_FILE_NAMES.iter().map(move |name| dir.join(name)))
+            .find(|path| path.exists())
+    }
+
+    fn read_file(&mut self, path: &Path) {
+        let source = Source::File {
+            path: path.to_path_buf(),
+            key: "".into(),
+        };
+        let document = match parse_document(path) {
+            Ok(document) => document,
+            Err(e) => {
+                self.problems
+                    .push(ConfigProblem::at(&source, format_sstr!("{e}")));
+                return;
+            }
+        };
+        let Value::Object(sections) = document else {
+            self.problems
+                .push(ConfigProblem::at(&source, "expected a table of sections"));
+            return;
+        };
+        for (section, keys) in sections {
+            let location = Source::File {
+                path: path.to_path_buf(),
+                key: section.as_str().into(),
+            };
+            if !SETTINGS.iter().any(|s| s.section == section) {
+                self.problems
+                    .push(ConfigProblem::at(&location, "unknown section"));
+                continue;
+            }
+            let Value::Object(keys) = keys else {
+                self.problems
+                    .push(ConfigProblem::at(&location, "expected a table"));
+                continue;
+            };
+            for (key, value) in keys {
+                let location = Source::File {
+                    path: path.to_path_buf(),
+                    key: format_sstr!("{section}.{key}"),
+                };
+                self.read_file_value(&section, &key, &value, location);
+            }
+        }
+    }
+
+    fn read_file_value(&mut self, section: &str, key: &str, value: &Value, location: Source) {
+        let (setting, from_file) = match find_setting(section, key) {
+            Some(setting) => (setting, false),
+            None => match key
+                .strip_suffix("_file")
+                .and_then(|key| find_setting(section, key))
+                .filter(|s| s.secret)
+            {
+                Some(setting) => (setting, true),
+                None => {
+                    self.problems
+                        .push(ConfigProblem::at(&location, "unknown key"));
+                    return;
+                }
+            },
+        };
+        let value = match value {
+            Value::String(s) => s.as_str().into(),
+            Value::Number(n) => format_sstr!("{n}"),
+            Value::Bool(b) => format_sstr!("{b}"),
+            _ => {
+                self.problems
+                    .push(ConfigProblem::at(&location, "expected a string or number"));
+                return;
+            }
+        };
+        if from_file {
+            self.insert_secret_file(setting.env, &value, location);
+        } else {
+            self.insert(setting.env, value, location);
+        }
+    }
+
+    fn read_env(&mut self, env: impl IntoIterator<Item = (String, String)>) {
+        let env: BTreeMap<String, String> = env.into_iter().collect();
+        for setting in SETTINGS {
+            let file_var = format_sstr!("{}_FILE", setting.env);
+            let value = env.get(setting.env);
+            let path = env.get(file_var.as_str()).filter(|_| setting.secret);
+            match (value, path) {
+                (Some(_), Some(_)) => {
+                    self.problems.push(ConfigProblem::at(
+                        &Source::Env(file_var.clone()),
+                        format_sstr!("both {} and {file_var} are set", setting.env),
+                    ));
+                }
+                (Some(value), None) => {
+                    self.insert(
+                        setting.env,
+                        value.as_str().into(),
+                        Source::Env(setting.env.into()),
+                    );
+                }
+                (None, Some(path)) => {
+                    self.insert_secret_file(setting.env, path, Source::Env(file_var));
+                }
+                (None, None) => {}
+            }
+        }
+    }
+
+    fn insert(&mut self, env: &'static str, value: StackString, source: Source) {
+        self.values.insert(env, (value, source));
+    }
+
+    fn insert_secret_file(&mut self, env: &'static str, path: &str, from: Source) {
+        let source = Source::SecretFile {
+            from: Box::new(from),
+            path: path.into(),
+        };
+        match fs::read_to_string(path) {
+            Ok(secret) => self.insert(env, secret.trim_end().into(), source),
+            Err(e) => self
+                .problems
+                .push(ConfigProblem::at(&source, format_sstr!("{e}"))),
+        }
+    }
+
+    /// Invalid values are dropped after being reported, so the remaining
+    /// settings can still be checked
+    fn check_kinds(&mut self) {
+        for setting in SETTINGS {
+            let Some((value, source)) = self.values.get(setting.env) else {
+                continue;
+            };
+            let error = match setting.kind {
+                Kind::Text | Kind::Path => None,
+                Kind::Url => Url::parse(value).err().map(|e| format_sstr!("{e}")),
+                Kind::Integer => value.parse::<u64>().err().map(|e| format_sstr!("{e}")),
+                Kind::Float => value.parse::<f64>().err().map(|e| format_sstr!("{e}")),
+            };
+            if let Some(error) = error {
+                let message = format_sstr!(
+                    "invalid value {:?} for {}: {error}",
+                    value.as_str(),
+                    setting.env
+                );
+                self.problems.push(ConfigProblem::at(source, message));
+                self.values.remove(setting.env);
+            }
+        }
+    }
+
+    /// Where the value for environment variable name `env` came from
+    #[must_use]
+    pub fn source(&self, env: &str) -> Option<&Source> {
+        self.values.get(env).map(|(_, source)| source)
+    }
+
+    #[must_use]
+    pub fn problems(&self) -> &[ConfigProblem] {
+        &self.problems
+    }
+
+    /// Attach the location of `env` to a problem found after deserializing
+    #[must_use]
+    pub fn problem(&self, env: &str, message: impl Into<StackString>) -> ConfigProblem {
+        match (self.source(env), &self.path) {
+            (Some(source), _) => ConfigProblem::at(source, message),
+            (None, Some(path)) => match SETTINGS.iter().find(|s| s.env == env) {
+                Some(setting) => ConfigProblem::at(
+                    &Source::File {
+                        path: path.clone(),
+                        key: format_sstr!("{}.{}", setting.section, setting.key),
+                    },
+                    message,
+                ),
+                None => ConfigProblem::new(None, message),
+            },
+            (None, None) => ConfigProblem::new(None, message),
+        }
+    }
+
+    /// Merged values in the form expected by `envy::from_iter`
+    pub fn vars(&self) -> impl Iterator<Item = (String, String)> + '_ {
+        self.values
+            .iter()
+            .map(|(env, (value, _))| ((*env).to_string(), value.to_string()))
+    }
+}
+
+fn find_setting(section: &str, key: &str) -> Option<&'static Setting> {
+    SETTINGS
+        .iter()
+        .find(|s| s.section == section && s.key == key)
+}
+
+fn parse_document(path: &Path) -> Result<Value, Error> {
+    let data = fs::read_to_string(path)?;
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("toml") => toml::from_str(&data).map_err(Into::into),
+        Some("yaml" | "yml") => serde_yml::from_str(&data).map_err(Into::into),
+        _ => Err(format_err!(
+            "unknown config file format, expected toml or yaml"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+    use std::io::Write;
+    use tempfile::{Builder, NamedTempFile};
+
+    use crate::config_file::{ConfigSources, Source};
+
+    fn config_file(suffix: &str, data: &str) -> Result<NamedTempFile, Error> {
+        let mut file = Builder::new().suffix(suffix).tempfile()?;
+        file.write_all(data.as_bytes())?;
+        Ok(file)
+    }
+
+    fn value<'a>(sources: &'a ConfigSources, env: &str) -> Option<&'a str> {
+        sources.values.get(env).map(|(value, _)| value.as_str())
+    }
+
+    #[test]
+    fn test_collect_toml() -> Result<(), Error> {
+        let secret = NamedTempFile::new()?;
+        std::fs::write(secret.path(), "secret token\n")?;
+        let file = config_file(
+            ".toml",
+            &format!(
+                "[server]\nhost = \"::\"\nport = 8080\n\n[telegram]\nbot_token_file = \"{}\"\n\n\
+                 [limits]\ncircuit_failure_rate = 0.25\n",
+                secret.path().display()
+            ),
+        )?;
+        let env = vec![
+            ("PORT".to_string(), "9090".to_string()),
+            ("UNRELATED".to_string(), "1".to_string()),
+        ];
+        let sources = ConfigSources::collect(Some(file.path()), env);
+        assert!(sources.problems().is_empty(), "{:?}", sources.problems());
+        assert_eq!(value(&sources, "HOST"), Some("::"));
+        assert_eq!(value(&sources, "PORT"), Some("9090"));
+        assert_eq!(sources.source("PORT"), Some(&Source::Env("PORT".into())));
+        assert_eq!(value(&sources, "TELEGRAM_BOT_TOKEN"), Some("secret token"));
+        assert_eq!(value(&sources, "CIRCUIT_FAILURE_RATE"), Some("0.25"));
+        assert_eq!(value(&sources, "UNRELATED"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_collect_yaml() -> Result<(), Error> {
+        let file = config_file(
+            ".yaml",
+            "server:\n  port: 8080\nses:\n  sending_email_address: noreply@localhost\n  region: \
+             us-west-2\nsmtp:\n  host: smtp.localhost\n",
+        )?;
+        let secret = NamedTempFile::new()?;
+        std::fs::write(secret.path(), "remote")?;
+        let env = vec![(
+            "REMOTE_TOKEN_FILE".to_string(),
+            secret.path().to_string_lossy().into_owned(),
+        )];
+        let sources = ConfigSources::collect(Some(file.path()), env);
+        assert!(sources.problems().is_empty(), "{:?}", sources.problems());
+        assert_eq!(value(&sources, "PORT"), Some("8080"));
+        assert_eq!(
+            value(&sources, "SENDING_EMAIL_ADDRESS"),
+            Some("noreply@localhost")
+        );
+        assert_eq!(value(&sources, "SES_REGION"), Some("us-west-2"));
+        assert_eq!(value(&sources, "SMTP_HOST"), Some("smtp.localhost"));
+        assert_eq!(value(&sources, "REMOTE_TOKEN"), Some("remote"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_collect_problems() -> Result<(), Error> {
+        let file = config_file(
+            ".toml",
+            "[server]\nport = \"eighty\"\nlisten = \"::\"\n\n[telegram]\nremote_url = \"not a \
+             url\"\nhost_file = \"/tmp\"\n\n[database]\nurl = \"x\"\n",
+        )?;
+        let env = vec![
+            ("REMOTE_TOKEN".to_string(), "token".to_string()),
+            ("REMOTE_TOKEN_FILE".to_string(), "/nonexistent".to_string()),
+            ("FLOOD_THRESHOLD".to_string(), "-1".to_string()),
+        ];
+        let sources = ConfigSources::collect(Some(file.path()), env);
+        let problems: Vec<String> = sources.problems().iter().map(ToString::to_string).collect();
+        let path = file.path().display();
+        for expected in [
+            format!("{path} [server.port]: invalid value \"eighty\" for PORT"),
+            format!("{path} [server.listen]: unknown key"),
+            format!("{path} [telegram.remote_url]: invalid value"),
+            format!("{path} [telegram.host_file]: unknown key"),
+            format!("{path} [database]: unknown section"),
+            "environment variable REMOTE_TOKEN_FILE: both REMOTE_TOKEN and REMOTE_TOKEN_FILE are \
+             set"
+            .to_string(),
+            "environment variable FLOOD_THRESHOLD: invalid value \"-1\"".to_string(),
+        ] {
+            assert!(
+                problems.iter().any(|p| p.starts_with(&expected)),
+                "{} not in {:?}",
+                expected,
+                problems
+            );
+        }
+        assert_eq!(problems.len(), 7);
+
+        let broken = config_file(".toml", "[server\nport = 1")?;
+        let sources = ConfigSources::collect(Some(broken.path()), Vec::new());
+        assert_eq!(sources.problems().len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,507 @@
+use anyhow::{format_err, Error};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use stack_string::{format_sstr, StackString};
+use std::{collections::HashSet, convert::TryFrom, fmt, str::FromStr, time::Instant};
+
+use crate::mime_message::{build_mime, validate_header, validate_header_value, Attachment};
+
+/// SES rejects messages with more than this many recipients in total
+pub const MAX_RECIPIENTS: usize = 50;
+
+/// An address with an optional display name, written as
+/// `Display Name <user@example.com>` or just `user@example.com`
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(into = "String", try_from = "String")]
+pub struct EmailAddress {
+    pub address: StackString,
+    pub name: Option<StackString>,
+}
+
+impl EmailAddress {
+    #[must_use]
+    pub fn new(address: impl Into<StackString>, name: Option<StackString>) -> Self {
+        Self {
+            address: address.into(),
+            name,
+        }
+    }
+
+    /// Whether SES may send from this address, given its verified
+    /// identities which are addresses or whole domains. A verified domain
+    /// also covers its subdomains.
+    #[must_use]
+    pub fn is_verified(&self, identities: &HashSet<StackString>) -> bool {
+        let domain = self
+            .address
+            .rsplit_once('@')
+            .map_or("", |(_, domain)| domain)
+            .to_ascii_lowercase();
+        identities.iter().any(|identity| {
+            let identity = identity.to_ascii_lowercase();
+            identity.eq_ignore_ascii_case(&self.address)
+                || (!domain.is_empty()
+                    && (domain == identity || domain.ends_with(&format!(".{identity}"))))
+        })
+    }
+
+    /// # Errors
+    /// Return error if the display name or the address contains a control
+    /// character, which could inject a header
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(name) = &self.name {
+            validate_header_value("display name", name)?;
+        }
+        validate_header_value("address", &self.address)
+    }
+
+    fn encoded_name(name: &str) -> StackString {
+        if name.is_ascii() {
+            let escaped = name.replace('\\', "\\\\").replace('"', "\\\"");
+            format_sstr!("\"{escaped}\"")
+        } else {
+            // RFC 2047 encoded word, required by SES for non-ascii names
+            format_sstr!("=?UTF-8?B?{}?=", STANDARD.encode(name))
+        }
+    }
+}
+
+impl fmt::Display for EmailAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} <{}>", Self::encoded_name(name), self.address),
+            None => write!(f, "{}", self.address),
+        }
+    }
+}
+
+impl FromStr for EmailAddress {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (name, address) = match s.strip_suffix('>').and_then(|s| s.rsplit_once('<')) {
+            Some((name, address)) => {
+                let name = name.trim().trim_matches('"').trim();
+                let name = if name.is_empty() {
+                    None
+                } else {
+                    Some(name.into())
+                };
+                (name, address.trim())
+            }
+            None => (None, s),
+        };
+        let valid = address
+            .split_once('@')
+            .is_some_and(|(user, domain)| !user.is_empty() && domain.contains('.'))
+            && !address.contains(char::is_whitespace);
+        if !valid {
+            return Err(format_err!("Invalid email address {s}"));
+        }
+        let address = Self::new(address, name);
+        address.validate()?;
+        Ok(address)
+    }
+}
+
+impl TryFrom<String> for EmailAddress {
+    type Error = Error;
+    fn try_from(item: String) -> Result<Self, Self::Error> {
+        item.parse()
+    }
+}
+
+impl From<EmailAddress> for String {
+    fn from(item: EmailAddress) -> String {
+        item.to_string()
+    }
+}
+
+/// A single email with every recipient list SES supports
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct EmailRequest {
+    pub from: EmailAddress,
+    #[serde(default)]
+    pub to: Vec<EmailAddress>,
+    #[serde(default)]
+    pub cc: Vec<EmailAddress>,
+    #[serde(default)]
+    pub bcc: Vec<EmailAddress>,
+    #[serde(default)]
+    pub reply_to: Vec<EmailAddress>,
+    pub subject: StackString,
+    /// Plain text part, generated from `html` when missing
+    #[serde(default)]
+    pub text: Option<StackString>,
+    #[serde(default)]
+    pub html: Option<StackString>,
+    /// Files attached to the message, sending any forces a raw MIME message
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    /// Custom headers such as `X-Campaign`, sent through a raw MIME message
+    #[serde(default)]
+    pub headers: Vec<(StackString, StackString)>,
+    /// Name of the api token that sent the email
+    #[serde(default)]
+    pub token: Option<StackString>,
+    /// Template the body was rendered from
+    #[serde(default)]
+    pub template: Option<StackString>,
+    /// When the email was accepted, for the delivery latency metric
+    #[serde(skip)]
+    pub queued: Option<Instant>,
+}
+
+impl EmailRequest {
+    /// The text part and optional html part to send
+    /// # Errors
+    /// Return error if neither a text nor an html body is set
+    pub fn parts(&self) -> Result<(StackString, Option<&StackString>), Error> {
+        match (&self.text, &self.html) {
+            (Some(text), html) => Ok((text.clone(), html.as_ref())),
+            (None, Some(html)) => Ok((html_to_text(html), Some(html))),
+            (None, None) => Err(format_err!("Email has no body")),
+        }
+    }
+
+    /// Number of to, cc and bcc addresses, each counts against SES quotas
+    #[must_use]
+    pub fn recipient_count(&self) -> usize {
+        self.to.len() + self.cc.len() + self.bcc.len()
+    }
+
+    /// Message tags identifying the sending token and template, with values
+    /// limited to the characters SES accepts
+    #[must_use]
+    pub fn tags(&self) -> Vec<(&'static str, StackString)> {
+        let tag = |value: &str| -> StackString {
+            value
+                .chars()
+                .take(256)
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect::<String>()
+                .into()
+        };
+        let mut tags = Vec::new();
+        if let Some(token) = &self.token {
+            tags.push(("token", tag(token)));
+        }
+        if let Some(template) = &self.template {
+            tags.push(("template", tag(template)));
+        }
+        tags
+    }
+
+    /// Whether the message needs to be sent as raw MIME
+    #[must_use]
+    pub fn is_raw(&self) -> bool {
+        !self.attachments.is_empty() || !self.headers.is_empty()
+    }
+
+    /// # Errors
+    /// Return error if the request has no body or an invalid custom header
+    pub fn to_mime(&self) -> Result<Vec<u8>, Error> {
+        build_mime(self)
+    }
+
+    /// The subject, every address, the custom headers and the attachment
+    /// metadata end up in message headers
+    /// # Errors
+    /// Return error if any of them contains a control character or a custom
+    /// header is invalid
+    pub fn validate_headers(&self) -> Result<(), Error> {
+        validate_header_value("Subject", &self.subject)?;
+        let addresses = [&self.to, &self.cc, &self.bcc, &self.reply_to];
+        for address in addresses.iter().flat_map(|a| a.iter()).chain([&self.from]) {
+            address.validate()?;
+        }
+        for (name, value) in &self.headers {
+            validate_header(name, value)?;
+        }
+        for attachment in &self.attachments {
+            attachment.validate()?;
+        }
+        Ok(())
+    }
+
+    /// # Errors
+    /// Return error if there is no body, no recipients, more recipients
+    /// than SES accepts, an invalid custom header or a control character in
+    /// the subject or an address
+    pub fn validate(&self) -> Result<(), Error> {
+        let recipients = self.to.len() + self.cc.len() + self.bcc.len();
+        self.validate_headers()?;
+        if self.text.is_none() && self.html.is_none() {
+            Err(format_err!("Email has no body"))
+        } else if recipients == 0 {
+            Err(format_err!("Email has no recipients"))
+        } else if recipients > MAX_RECIPIENTS {
+            Err(format_err!(
+                "Email has {recipients} recipients, at most {MAX_RECIPIENTS} are allowed"
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+const PARAGRAPH_TAGS: [&str; 10] = ["p", "h1", "h2", "h3", "h4", "h5", "h6", "ul", "ol", "table"];
+const LINE_TAGS: [&str; 5] = ["br", "div", "tr", "blockquote", "pre"];
+
+fn decode_entity(entity: &str) -> Option<char> {
+    match entity {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        _ => {
+            let code = entity.strip_prefix('#')?;
+            let code = match code.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => code.parse().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
+
+/// Append html text content, collapsing whitespace and decoding entities
+fn push_text(out: &mut String, text: &str) {
+    let mut rest = text;
+    while !rest.is_empty() {
+        let next = rest
+            .find(['&', ' ', '\t', '\n', '\r'])
+            .unwrap_or(rest.len());
+        out.push_str(&rest[..next]);
+        rest = &rest[next..];
+        if rest.starts_with('&') {
+            match rest
+                .find(';')
+                .and_then(|end| Some((decode_entity(&rest[1..end])?, end)))
+            {
+                Some((c, end)) => {
+                    out.push(c);
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    out.push('&');
+                    rest = &rest[1..];
+                }
+            }
+        } else if !rest.is_empty() {
+            if !out.is_empty() && !out.ends_with([' ', '\n']) {
+                out.push(' ');
+            }
+            rest = rest.trim_start();
+        }
+    }
+}
+
+fn href(attributes: &str) -> Option<&str> {
+    let start = attributes.find("href=")? + 5;
+    let value = &attributes[start..];
+    let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let value = &value[1..];
+    value.find(quote).map(|end| &value[..end])
+}
+
+/// Plain text rendering of an html body: tags are dropped, block elements
+/// become line breaks, list items get a leading `- ` and links keep their
+/// target in parentheses
+#[must_use]
+pub fn html_to_text(html: &str) -> StackString {
+    let mut out = String::new();
+    let mut skip: Option<String> = None;
+    let mut link: Option<&str> = None;
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        if skip.is_none() {
+            push_text(&mut out, &rest[..start]);
+        }
+        let Some(end) = rest[start..].find('>') else {
+            rest = &rest[start..];
+            break;
+        };
+        let tag = rest[start + 1..start + end].trim();
+        rest = &rest[start + end + 1..];
+
+        let closing = tag.starts_with('/');
+        let tag = tag.trim_start_matches('/');
+        let name_end = tag
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .unwrap_or(tag.len());
+        let name = tag[..name_end].to_ascii_lowercase();
+        if let Some(skipped) = &skip {
+            if closing && *skipped == name {
+                skip = None;
+            }
+            continue;
+        }
+        match name.as_str() {
+            "script" | "style" | "head" if !closing => skip = Some(name),
+            "li" if !closing => out.push_str("\n- "),
+            "a" if !closing => link = href(&tag[name_end..]),
+            "a" => {
+                if let Some(href) = link.take() {
+                    if !out.ends_with(href) && !href.starts_with('#') {
+                        out.push_str(&format!(" ({href})"));
+                    }
+                }
+            }
+            name if PARAGRAPH_TAGS.contains(&name) => out.push_str("\n\n"),
+            name if LINE_TAGS.contains(&name) => out.push('\n'),
+            _ => {}
+        }
+    }
+    if skip.is_none() {
+        push_text(&mut out, rest);
+    }
+
+    let mut text = String::new();
+    let mut blank_lines = 0;
+    for line in out.lines().map(str::trim) {
+        if line.is_empty() {
+            blank_lines += 1;
+            continue;
+        }
+        if !text.is_empty() {
+            text.push_str(if blank_lines > 1 { "\n\n" } else { "\n" });
+        }
+        text.push_str(line);
+        blank_lines = 0;
+    }
+    text.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+    use stack_string::StackString;
+    use std::collections::HashSet;
+
+    use crate::email::{html_to_text, EmailAddress, EmailRequest, MAX_RECIPIENTS};
+
+    #[test]
+    fn test_email_address() -> Result<(), Error> {
+        let address: EmailAddress = "user@example.com".parse()?;
+        assert_eq!(address, EmailAddress::new("user@example.com", None));
+        assert_eq!(address.to_string(), "user@example.com");
+
+        let address: EmailAddress = "\"Alerts Bot\" <alerts@example.com>".parse()?;
+        assert_eq!(address.name.as_ref().unwrap(), "Alerts Bot");
+        assert_eq!(address.address, "alerts@example.com");
+        assert_eq!(address.to_string(), "\"Alerts Bot\" <alerts@example.com>");
+
+        let address = EmailAddress::new("a@example.com", Some("Zoë".into()));
+        assert_eq!(address.to_string(), "=?UTF-8?B?Wm/Dqw==?= <a@example.com>");
+
+        for invalid in [
+            "",
+            "user",
+            "Name <user>",
+            "us er@example.com",
+            "@example.com",
+        ] {
+            assert!(invalid.parse::<EmailAddress>().is_err(), "{}", invalid);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_verified() -> Result<(), Error> {
+        let identities: HashSet<StackString> = ["example.com", "ops@other.org"]
+            .iter()
+            .copied()
+            .map(Into::into)
+            .collect();
+        for verified in [
+            "a@example.com",
+            "a@EXAMPLE.com",
+            "a@mail.example.com",
+            "a@eu.mail.example.com",
+            "ops@other.org",
+        ] {
+            let address: EmailAddress = verified.parse()?;
+            assert!(address.is_verified(&identities), "{}", verified);
+        }
+        for unverified in [
+            "a@badexample.com",
+            "a@example.com.evil.net",
+            "b@other.org",
+            "ops@mail.other.org",
+        ] {
+            let address: EmailAddress = unverified.parse()?;
+            assert!(!address.is_verified(&identities), "{}", unverified);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_email_request() -> Result<(), Error> {
+        let request: EmailRequest = serde_json::from_str(
+            r#"{"from": "Bot <bot@example.com>", "to": ["a@example.com"], "subject": "s", "html": "<b>b</b>"}"#,
+        )?;
+        assert_eq!(request.from.name.as_ref().unwrap(), "Bot");
+        assert!(request.validate().is_ok());
+        let (text, html) = request.parts()?;
+        assert_eq!(text, "b");
+        assert_eq!(html.unwrap(), "<b>b</b>");
+
+        let mut request = EmailRequest {
+            to: Vec::new(),
+            ..request
+        };
+        assert!(request.validate().is_err());
+        request.bcc = vec!["b@example.com".parse()?; MAX_RECIPIENTS + 1];
+        assert!(request.validate().is_err());
+
+        let request = EmailRequest {
+            to: vec!["a@example.com".parse()?],
+            ..EmailRequest::default()
+        };
+        assert!(request.validate().is_err());
+        assert!(request.parts().is_err());
+        assert!(request.tags().is_empty());
+
+        let request = EmailRequest {
+            token: Some("billing".into()),
+            template: Some("invoice.v2 (eu)".into()),
+            ..request
+        };
+        assert_eq!(
+            request.tags(),
+            [
+                ("token", "billing".into()),
+                ("template", "invoice_v2__eu_".into())
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_html_to_text() {
+        let html = r#"<html><head><title>t</title><style>p { color: red; }</style></head>
+            <body>
+              <h1>Disk   usage</h1>
+              <p>Host <b>web-1</b> is at 95%&nbsp;&amp; rising.<br>Check &lt;now&gt;&#33;</p>
+              <ul><li>one</li><li>two</li></ul>
+              <p>See <a href="https://example.com/d">the dashboard</a>.</p>
+              <script>alert("x")</script>
+            </body></html>"#;
+        assert_eq!(
+            html_to_text(html),
+            "Disk usage\n\nHost web-1 is at 95% & rising.\nCheck <now>!\n\n- one\n- two\n\nSee \
+             the dashboard (https://example.com/d)."
+        );
+        assert_eq!(html_to_text("plain & simple"), "plain & simple");
+    }
+}
@@ -0,0 +1,234 @@
+use anyhow::{format_err, Error};
+use std::{str::FromStr, time::Instant};
+
+use crate::{
+    circuit_breaker::CircuitOpen,
+    config::{ApiTokenConfig, Config},
+    email::EmailRequest,
+    metrics,
+    send_throttle::QuotaExhausted,
+    ses_client::SesInstance,
+    sesv2_client::SesV2Instance,
+    smtp_client::SmtpInstance,
+    templates::Channel,
+};
+
+/// Which service delivers email, set by `EMAIL_BACKEND`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EmailBackend {
+    #[default]
+    Ses,
+    SesV2,
+    Smtp,
+}
+
+impl EmailBackend {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Ses => "ses",
+            Self::SesV2 => "sesv2",
+            Self::Smtp => "smtp",
+        }
+    }
+}
+
+impl FromStr for EmailBackend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ses" => Ok(Self::Ses),
+            "sesv2" => Ok(Self::SesV2),
+            "smtp" => Ok(Self::Smtp),
+            _ => Err(format_err!(
+                "Invalid email backend {s}, expected ses, sesv2 or smtp"
+            )),
+        }
+    }
+}
+
+/// The configured email transport
+#[derive(Clone, Debug)]
+pub enum EmailSender {
+    Ses(SesInstance),
+    SesV2(SesV2Instance),
+    Smtp(SmtpInstance),
+}
+
+impl EmailSender {
+    /// # Errors
+    /// Return error if `EMAIL_BACKEND` or the settings of the selected
+    /// backend are invalid
+    pub async fn from_config(config: &Config) -> Result<Self, Error> {
+        match config.email_backend()? {
+            EmailBackend::Ses => Ok(Self::Ses(SesInstance::from_config(config).await)),
+            EmailBackend::SesV2 => Ok(Self::SesV2(SesV2Instance::from_config(config).await)),
+            EmailBackend::Smtp => SmtpInstance::from_config(config).map(Self::Smtp),
+        }
+    }
+
+    #[must_use]
+    pub fn backend(&self) -> EmailBackend {
+        match self {
+            Self::Ses(_) => EmailBackend::Ses,
+            Self::SesV2(_) => EmailBackend::SesV2,
+            Self::Smtp(_) => EmailBackend::Smtp,
+        }
+    }
+
+    /// The SES client, for quota and statistics which only SES provides
+    #[must_use]
+    pub fn ses(&self) -> Option<&SesInstance> {
+        match self {
+            Self::Ses(ses) => Some(ses),
+            Self::SesV2(sesv2) => Some(sesv2.ses()),
+            Self::Smtp(_) => None,
+        }
+    }
+
+    /// Refuse `request` up front when it would dip into the reserve of the
+    /// SES daily quota, instead of queueing an email that cannot be sent
+    /// # Errors
+    /// Return [`QuotaExhausted`] if the quota is down to its reserve
+    pub fn check_quota(&self, request: &EmailRequest) -> Result<(), QuotaExhausted> {
+        match self.ses().and_then(SesInstance::throttle) {
+            Some(throttle) => throttle.check(request.recipient_count()),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether a failed send may succeed later, because the SES quota ran out
+    /// or the circuit of the backend is open
+    #[must_use]
+    pub fn is_transient(error: &Error) -> bool {
+        error.is::<QuotaExhausted>() || error.is::<CircuitOpen>()
+    }
+
+    /// Check the sender of every token against the verified SES identities,
+    /// an SMTP relay does its own checks
+    /// # Errors
+    /// Return error if the identities cannot be fetched or a sender is not
+    /// verified
+    pub async fn validate_senders(&self, token_config: &ApiTokenConfig) -> Result<(), Error> {
+        match self.ses() {
+            Some(ses) if !token_config.email_senders().is_empty() => {
+                let identities = ses.verified_identities().await?;
+                token_config.validate_senders(Some(&identities))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Send `request`, counting it as delivered or failed for its token
+    /// # Errors
+    /// Return error if the request is invalid or the backend fails to send it
+    pub async fn send_email(&self, request: &EmailRequest) -> Result<(), Error> {
+        let queued = request.queued.unwrap_or_else(Instant::now);
+        let result = match self {
+            Self::Ses(ses) => ses.send_email(request).await,
+            Self::SesV2(sesv2) => sesv2.send_email(request).await,
+            Self::Smtp(smtp) => smtp.send_email(request).await,
+        };
+        let labels = [
+            Channel::Email.as_str(),
+            metrics::token_label(request.token.as_ref()),
+        ];
+        if result.is_ok() {
+            metrics::MESSAGES_DELIVERED.with_label_values(&labels).inc();
+            metrics::latency_histogram(Channel::Email.as_str())
+                .observe(queued.elapsed().as_secs_f64());
+        } else {
+            metrics::MESSAGES_FAILED.with_label_values(&labels).inc();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    use crate::{
+        config::{ApiTokenConfig, Config, ConfigInner},
+        email::EmailRequest,
+        email_sender::{EmailBackend, EmailSender},
+        metrics,
+        ses_client::{tests::fake_ses, SesInstance},
+        smtp_client::{tests::smtp_stand_in, SmtpSecurity},
+    };
+
+    #[tokio::test]
+    async fn test_email_sender() -> Result<(), Error> {
+        let (port, mut sessions, task) = smtp_stand_in(SmtpSecurity::None).await?;
+        let config: Config = ConfigInner {
+            email_backend: Some("smtp".into()),
+            smtp_host: Some("127.0.0.1".into()),
+            smtp_port: Some(port),
+            smtp_security: Some("none".into()),
+            ..ConfigInner::default()
+        }
+        .into();
+        let sender = EmailSender::from_config(&config).await?;
+        assert_eq!(sender.backend(), EmailBackend::Smtp);
+        assert!(sender.ses().is_none());
+
+        let request = EmailRequest {
+            from: "bot@example.com".parse()?,
+            to: vec!["a@example.com".parse()?],
+            subject: "s".into(),
+            html: Some("<p>hello</p>".into()),
+            ..EmailRequest::default()
+        };
+        let labels = ["email", "unknown"];
+        let delivered = metrics::MESSAGES_DELIVERED.with_label_values(&labels).get();
+        sender.send_email(&request).await?;
+        task.await??;
+        assert!(metrics::MESSAGES_DELIVERED.with_label_values(&labels).get() > delivered);
+        let session = sessions.recv().await.unwrap();
+        assert!(session.message.contains("multipart/alternative"));
+
+        let config: Config = ConfigInner {
+            email_backend: Some("smtp".into()),
+            ..ConfigInner::default()
+        }
+        .into();
+        assert!(EmailSender::from_config(&config).await.is_err());
+        assert!("pigeon".parse::<EmailBackend>().is_err());
+        assert_eq!("SESv2".parse::<EmailBackend>()?, EmailBackend::SesV2);
+        assert_eq!(EmailBackend::SesV2.as_str(), "sesv2");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_validate_senders() -> Result<(), Error> {
+        let (endpoint, _) = fake_ses().await?;
+        let config: Config = ConfigInner {
+            ses_region: Some("us-west-2".into()),
+            ses_endpoint_url: Some(endpoint.parse()?),
+            ..ConfigInner::default()
+        }
+        .into();
+        let sdk_config = SesInstance::loader(&config).test_credentials().load().await;
+        let sender = EmailSender::Ses(SesInstance::new(&sdk_config));
+
+        let mut temp = NamedTempFile::new()?;
+        temp.write_all(
+            b"[ops]\napi_token = \"ops-token\"\n[ops.email_sender]\naddress = \
+              \"alerts@example.com\"\n",
+        )?;
+        sender
+            .validate_senders(&ApiTokenConfig::new(temp.path()).await?)
+            .await?;
+        let mut temp = NamedTempFile::new()?;
+        temp.write_all(
+            b"[ops]\napi_token = \"ops-token\"\n[ops.email_sender]\naddress = \
+              \"alerts@pending.com\"\n",
+        )?;
+        let tokens = ApiTokenConfig::new(temp.path()).await?;
+        assert!(sender.validate_senders(&tokens).await.is_err());
+        Ok(())
+    }
+}
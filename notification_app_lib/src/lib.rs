@@ -5,8 +5,21 @@
 #![allow(clippy::cast_possible_truncation)]
 #![allow(clippy::cognitive_complexity)]
 
+pub mod circuit_breaker;
 pub mod config;
+pub mod config_file;
+pub mod email;
+pub mod email_sender;
+pub mod metrics;
+pub mod mime_message;
+pub mod send_throttle;
 pub mod ses_client;
+pub mod sesv2_client;
+pub mod smtp_client;
+pub mod sns;
+pub mod structured;
+pub mod suppression;
+pub mod templates;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,213 @@
+use anyhow::Error;
+use once_cell::sync::Lazy;
+use prometheus::{
+    exponential_buckets, Encoder, Gauge, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use stack_string::StackString;
+
+use crate::ses_client::SesInstance;
+
+pub static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+fn register<T>(metric: T) -> T
+where
+    T: prometheus::core::Collector + Clone + 'static,
+{
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("metric registered twice");
+    metric
+}
+
+fn counter_vec(name: &str, help: &str) -> IntCounterVec {
+    register(
+        IntCounterVec::new(Opts::new(name, help), &["channel", "token"]).expect("invalid metric"),
+    )
+}
+
+pub static MESSAGES_ACCEPTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    counter_vec(
+        "notification_messages_accepted_total",
+        "Messages accepted by the api",
+    )
+});
+pub static MESSAGES_DELIVERED: Lazy<IntCounterVec> = Lazy::new(|| {
+    counter_vec(
+        "notification_messages_delivered_total",
+        "Messages handed to the delivery channel",
+    )
+});
+pub static MESSAGES_FAILED: Lazy<IntCounterVec> = Lazy::new(|| {
+    counter_vec(
+        "notification_messages_failed_total",
+        "Messages where delivery returned an error",
+    )
+});
+pub static MESSAGES_UNDELIVERABLE: Lazy<IntCounterVec> = Lazy::new(|| {
+    counter_vec(
+        "notification_messages_undeliverable_total",
+        "Messages with an unknown recipient or no chat id",
+    )
+});
+pub static DELIVERY_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "notification_delivery_latency_seconds",
+                "Time from queueing a message until it is delivered",
+            )
+            .buckets(exponential_buckets(0.001, 4.0, 10).expect("invalid buckets")),
+            &["channel"],
+        )
+        .expect("invalid metric"),
+    )
+});
+pub static QUEUE_DEPTH: Lazy<IntGauge> = Lazy::new(|| {
+    register(
+        IntGauge::new("notification_queue_depth", "Messages waiting in the queue")
+            .expect("invalid metric"),
+    )
+});
+pub static CIRCUIT_STATE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register(
+        IntGaugeVec::new(
+            Opts::new(
+                "notification_circuit_state",
+                "Circuit breaker state per dependency, 0 closed 1 half open 2 open",
+            ),
+            &["dependency"],
+        )
+        .expect("invalid metric"),
+    )
+});
+pub static BOT_RESTARTS: Lazy<IntCounter> = Lazy::new(|| {
+    register(
+        IntCounter::new(
+            "notification_bot_update_loop_restarts_total",
+            "Times the telegram update loop was restarted",
+        )
+        .expect("invalid metric"),
+    )
+});
+pub static TASK_RESTARTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "notification_bot_task_restarts_total",
+                "Times a supervised bot task was restarted",
+            ),
+            &["task"],
+        )
+        .expect("invalid metric"),
+    )
+});
+pub static SES_MAX_24_HOUR_SEND: Lazy<Gauge> = Lazy::new(|| {
+    register(
+        Gauge::new(
+            "notification_ses_max_24_hour_send",
+            "SES 24 hour send quota",
+        )
+        .expect("invalid metric"),
+    )
+});
+pub static SES_SENT_LAST_24_HOURS: Lazy<Gauge> = Lazy::new(|| {
+    register(
+        Gauge::new(
+            "notification_ses_sent_last_24_hours",
+            "Emails sent through SES in the last 24 hours",
+        )
+        .expect("invalid metric"),
+    )
+});
+pub static SES_MAX_SEND_RATE: Lazy<Gauge> = Lazy::new(|| {
+    register(
+        Gauge::new(
+            "notification_ses_max_send_rate",
+            "SES maximum emails per second",
+        )
+        .expect("invalid metric"),
+    )
+});
+pub static EMAIL_FEEDBACK: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "notification_email_feedback_total",
+                "Bounced and complaining recipients reported by SES",
+            ),
+            &["kind"],
+        )
+        .expect("invalid metric"),
+    )
+});
+
+/// Label for the token that sent a message
+#[must_use]
+pub fn token_label(sender: Option<&StackString>) -> &str {
+    sender.map_or("unknown", StackString::as_str)
+}
+
+#[must_use]
+pub fn latency_histogram(channel: &str) -> Histogram {
+    DELIVERY_LATENCY.with_label_values(&[channel])
+}
+
+/// # Errors
+/// Return error if the SES api call fails
+pub async fn update_ses_metrics(ses: &SesInstance) -> Result<(), Error> {
+    let (quota, _) = ses.get_statistics().await?;
+    SES_MAX_24_HOUR_SEND.set(quota.max_24_hour_send);
+    SES_SENT_LAST_24_HOURS.set(quota.sent_last_24_hours);
+    SES_MAX_SEND_RATE.set(quota.max_send_rate);
+    Ok(())
+}
+
+/// Render every registered metric in the prometheus text format
+/// # Errors
+/// Return error if encoding fails
+pub fn encode_metrics() -> Result<String, Error> {
+    Lazy::force(&MESSAGES_ACCEPTED);
+    Lazy::force(&MESSAGES_DELIVERED);
+    Lazy::force(&MESSAGES_FAILED);
+    Lazy::force(&MESSAGES_UNDELIVERABLE);
+    Lazy::force(&DELIVERY_LATENCY);
+    Lazy::force(&QUEUE_DEPTH);
+    Lazy::force(&CIRCUIT_STATE);
+    Lazy::force(&BOT_RESTARTS);
+    Lazy::force(&TASK_RESTARTS);
+    Lazy::force(&SES_MAX_24_HOUR_SEND);
+    Lazy::force(&SES_SENT_LAST_24_HOURS);
+    Lazy::force(&SES_MAX_SEND_RATE);
+    Lazy::force(&EMAIL_FEEDBACK);
+
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer)?;
+    String::from_utf8(buffer).map_err(Into::into)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+
+    use crate::metrics::{encode_metrics, latency_histogram, MESSAGES_ACCEPTED, QUEUE_DEPTH};
+
+    #[test]
+    fn test_encode_metrics() -> Result<(), Error> {
+        MESSAGES_ACCEPTED
+            .with_label_values(&["telegram", "user"])
+            .inc();
+        QUEUE_DEPTH.set(3);
+        latency_histogram("telegram").observe(0.5);
+        let output = encode_metrics()?;
+        assert!(output.contains(
+            "notification_messages_accepted_total{channel=\"telegram\",token=\"user\"} 1"
+        ));
+        assert!(output.contains("notification_queue_depth 3"));
+        assert!(
+            output.contains("notification_delivery_latency_seconds_count{channel=\"telegram\"} 1")
+        );
+        assert!(output.contains("# TYPE notification_bot_update_loop_restarts_total counter"));
+        Ok(())
+    }
+}
@@ -0,0 +1,447 @@
+use anyhow::{format_err, Error};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use stack_string::{format_sstr, StackString};
+use std::{fmt::Write, path::Path};
+use time::{format_description::well_known::Rfc2822, OffsetDateTime};
+use tokio::fs;
+use uuid::Uuid;
+
+use crate::email::{EmailAddress, EmailRequest};
+
+/// Headers set by the message builder, they cannot be given as custom headers
+const RESERVED_HEADERS: [&str; 12] = [
+    "bcc",
+    "cc",
+    "content-disposition",
+    "content-transfer-encoding",
+    "content-type",
+    "date",
+    "from",
+    "message-id",
+    "mime-version",
+    "reply-to",
+    "subject",
+    "to",
+];
+
+const LINE_LENGTH: usize = 76;
+
+/// A file sent with an email, shown inline when it has a `content_id` that
+/// the html body references as `cid:<content_id>`
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Attachment {
+    pub filename: StackString,
+    pub content_type: StackString,
+    #[serde(
+        serialize_with = "serialize_base64",
+        deserialize_with = "deserialize_base64"
+    )]
+    pub data: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_id: Option<StackString>,
+}
+
+fn serialize_base64<S>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&STANDARD.encode(data))
+}
+
+fn deserialize_base64<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let data = String::deserialize(deserializer)?;
+    STANDARD.decode(data).map_err(serde::de::Error::custom)
+}
+
+/// Content type for common attachment extensions
+#[must_use]
+pub fn content_type_for(filename: &str) -> &'static str {
+    let extension = filename
+        .rsplit_once('.')
+        .map(|(_, e)| e.to_ascii_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "csv" => "text/csv",
+        "txt" | "log" => "text/plain",
+        "html" | "htm" => "text/html",
+        "json" => "application/json",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        _ => "application/octet-stream",
+    }
+}
+
+impl Attachment {
+    #[must_use]
+    pub fn new(filename: impl Into<StackString>, data: Vec<u8>) -> Self {
+        let filename = filename.into();
+        Self {
+            content_type: content_type_for(&filename).into(),
+            filename,
+            data,
+            content_id: None,
+        }
+    }
+
+    /// # Errors
+    /// Return error if reading `path` fails
+    pub async fn from_path(path: &Path) -> Result<Self, Error> {
+        let filename = path
+            .file_name()
+            .ok_or_else(|| format_err!("{} is not a file", path.display()))?
+            .to_string_lossy();
+        let data = fs::read(path).await?;
+        Ok(Self::new(filename.as_ref(), data))
+    }
+
+    /// Show the attachment inline, referenced from html as `cid:<content_id>`
+    #[must_use]
+    pub fn inline(mut self, content_id: impl Into<StackString>) -> Self {
+        self.content_id = Some(content_id.into());
+        self
+    }
+
+    /// The file name, content type and content id are written into the
+    /// headers of the attachment part
+    /// # Errors
+    /// Return error if any of them contains a control character
+    pub fn validate(&self) -> Result<(), Error> {
+        validate_header_value("Content-Disposition", &self.filename)?;
+        validate_header_value("Content-Type", &self.content_type)?;
+        if let Some(content_id) = &self.content_id {
+            validate_header_value("Content-ID", content_id)?;
+        }
+        Ok(())
+    }
+}
+
+/// Encode a header value as an RFC 2047 encoded word if it is not ascii
+fn encode_header(value: &str) -> StackString {
+    if value.is_ascii() {
+        value.into()
+    } else {
+        format_sstr!("=?UTF-8?B?{}?=", STANDARD.encode(value))
+    }
+}
+
+/// `filename` parameter, using the RFC 2231 form for non-ascii names
+fn filename_parameter(filename: &str) -> StackString {
+    if filename.is_ascii() {
+        let escaped = filename.replace('\\', "\\\\").replace('"', "\\\"");
+        format_sstr!("filename=\"{escaped}\"")
+    } else {
+        let mut encoded = StackString::new();
+        for b in filename.bytes() {
+            if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+                encoded.push(char::from(b));
+            } else {
+                write!(encoded, "%{b:02X}").ok();
+            }
+        }
+        format_sstr!("filename*=UTF-8''{encoded}")
+    }
+}
+
+/// # Errors
+/// Return error if `name` is not a valid header name, is set by the builder
+/// itself, or `value` contains a line break or another control character
+pub fn validate_header(name: &str, value: &str) -> Result<(), Error> {
+    let valid_name = !name.is_empty() && name.bytes().all(|b| b.is_ascii_graphic() && b != b':');
+    if !valid_name {
+        return Err(format_err!("Invalid header name {name:?}"));
+    }
+    if RESERVED_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+        return Err(format_err!("Header {name} cannot be overridden"));
+    }
+    validate_header_value(name, value)
+}
+
+/// Values written into a header, such as the subject or a display name, may
+/// not contain a line break that would start a new header
+/// # Errors
+/// Return error if `value` contains a control character other than tab
+pub fn validate_header_value(name: &str, value: &str) -> Result<(), Error> {
+    if value.chars().any(|c| c.is_control() && c != '\t') {
+        return Err(format_err!("Header {name} contains a control character"));
+    }
+    Ok(())
+}
+
+/// `<unique@domain>` using the domain of the sender
+fn message_id(from: &EmailAddress) -> StackString {
+    let domain = from
+        .address
+        .rsplit_once('@')
+        .map_or("localhost", |(_, domain)| domain);
+    format_sstr!("<{}@{domain}>", Uuid::new_v4().simple())
+}
+
+fn address_list(addresses: &[EmailAddress]) -> StackString {
+    let addresses: Vec<_> = addresses.iter().map(ToString::to_string).collect();
+    addresses.join(", ").into()
+}
+
+fn push_base64(out: &mut String, data: &[u8]) {
+    let encoded = STANDARD.encode(data);
+    for line in encoded.as_bytes().chunks(LINE_LENGTH) {
+        // base64 output is ascii
+        out.push_str(std::str::from_utf8(line).unwrap_or_default());
+        out.push_str("\r\n");
+    }
+}
+
+fn push_text_part(out: &mut String, content_type: &str, body: &str) {
+    write!(
+        out,
+        "Content-Type: {content_type}; charset=UTF-8\r\nContent-Transfer-Encoding: base64\r\n\r\n"
+    )
+    .ok();
+    push_base64(out, body.as_bytes());
+}
+
+fn push_attachment(out: &mut String, attachment: &Attachment) {
+    let disposition = if attachment.content_id.is_some() {
+        "inline"
+    } else {
+        "attachment"
+    };
+    write!(
+        out,
+        "Content-Type: {}\r\nContent-Transfer-Encoding: base64\r\nContent-Disposition: {disposition}; {}\r\n",
+        attachment.content_type,
+        filename_parameter(&attachment.filename),
+    )
+    .ok();
+    if let Some(content_id) = &attachment.content_id {
+        write!(out, "Content-ID: <{content_id}>\r\n").ok();
+    }
+    out.push_str("\r\n");
+    push_base64(out, &attachment.data);
+}
+
+fn boundary() -> StackString {
+    format_sstr!("=_{}", Uuid::new_v4().simple())
+}
+
+/// Build the raw MIME message for `request`: a `multipart/mixed` message
+/// holding the `multipart/alternative` text and html bodies (wrapped with
+/// inline images in `multipart/related`) followed by the attachments. Bcc
+/// recipients are left out of the headers.
+/// # Errors
+/// Return error if the request has no body, an invalid custom header or a
+/// control character in the subject or a display name
+pub fn build_mime(request: &EmailRequest) -> Result<Vec<u8>, Error> {
+    let (text, html) = request.parts()?;
+    request.validate_headers()?;
+    let mut out = String::new();
+
+    let date = OffsetDateTime::now_utc().format(&Rfc2822)?;
+    write!(out, "Date: {date}\r\n").ok();
+    write!(out, "Message-ID: {}\r\n", message_id(&request.from)).ok();
+    write!(out, "From: {}\r\n", request.from).ok();
+    if !request.to.is_empty() {
+        write!(out, "To: {}\r\n", address_list(&request.to)).ok();
+    }
+    if !request.cc.is_empty() {
+        write!(out, "Cc: {}\r\n", address_list(&request.cc)).ok();
+    }
+    if !request.reply_to.is_empty() {
+        write!(out, "Reply-To: {}\r\n", address_list(&request.reply_to)).ok();
+    }
+    write!(out, "Subject: {}\r\n", encode_header(&request.subject)).ok();
+    for (name, value) in &request.headers {
+        write!(out, "{name}: {}\r\n", encode_header(value)).ok();
+    }
+    out.push_str("MIME-Version: 1.0\r\n");
+
+    let mixed = boundary();
+    write!(
+        out,
+        "Content-Type: multipart/mixed; boundary=\"{mixed}\"\r\n\r\n--{mixed}\r\n"
+    )
+    .ok();
+
+    let (inline, attached): (Vec<_>, Vec<_>) = request
+        .attachments
+        .iter()
+        .partition(|a| a.content_id.is_some());
+    let related = if inline.is_empty() {
+        None
+    } else {
+        let related = boundary();
+        write!(
+            out,
+            "Content-Type: multipart/related; boundary=\"{related}\"\r\n\r\n--{related}\r\n"
+        )
+        .ok();
+        Some(related)
+    };
+
+    match html {
+        Some(html) => {
+            let alternative = boundary();
+            write!(
+                out,
+                "Content-Type: multipart/alternative; boundary=\"{alternative}\"\r\n\r\n--{alternative}\r\n"
+            )
+            .ok();
+            push_text_part(&mut out, "text/plain", &text);
+            write!(out, "--{alternative}\r\n").ok();
+            push_text_part(&mut out, "text/html", html);
+            write!(out, "--{alternative}--\r\n").ok();
+        }
+        None => push_text_part(&mut out, "text/plain", &text),
+    }
+
+    if let Some(related) = related {
+        for attachment in inline {
+            write!(out, "--{related}\r\n").ok();
+            push_attachment(&mut out, attachment);
+        }
+        write!(out, "--{related}--\r\n").ok();
+    }
+    for attachment in attached {
+        write!(out, "--{mixed}\r\n").ok();
+        push_attachment(&mut out, attachment);
+    }
+    write!(out, "--{mixed}--\r\n").ok();
+    Ok(out.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    use crate::{
+        email::{EmailAddress, EmailRequest},
+        mime_message::{build_mime, content_type_for, validate_header, Attachment},
+    };
+
+    fn request() -> Result<EmailRequest, Error> {
+        Ok(EmailRequest {
+            from: "Reports <reports@example.com>".parse()?,
+            to: vec!["a@example.com".parse()?],
+            cc: vec!["b@example.com".parse()?],
+            bcc: vec!["hidden@example.com".parse()?],
+            subject: "Daily report".into(),
+            html: Some("<p>See <img src=\"cid:chart\"></p>".into()),
+            attachments: vec![
+                Attachment::new("report.csv", b"a,b\n1,2\n".to_vec()),
+                Attachment::new("chart.png", vec![0x89, b'P', b'N', b'G']).inline("chart"),
+            ],
+            headers: vec![("X-Report-Id".into(), "42".into())],
+            ..EmailRequest::default()
+        })
+    }
+
+    #[test]
+    fn test_build_mime() -> Result<(), Error> {
+        let mime = String::from_utf8(build_mime(&request()?)?)?;
+        let headers = mime.split("\r\n\r\n").next().unwrap();
+        assert!(headers.starts_with("Date: "));
+        assert!(headers.contains(" +0000\r\nMessage-ID: <"));
+        assert!(headers.contains("@example.com>\r\n"));
+        assert!(headers.contains("From: \"Reports\" <reports@example.com>\r\n"));
+        assert!(headers.contains("To: a@example.com\r\nCc: b@example.com\r\n"));
+        assert!(headers.contains("Subject: Daily report\r\n"));
+        assert!(headers.contains("X-Report-Id: 42\r\n"));
+        assert!(headers.contains("Content-Type: multipart/mixed; boundary="));
+        assert!(!mime.contains("hidden@example.com"));
+
+        assert!(mime.contains("Content-Type: multipart/related; boundary="));
+        assert!(mime.contains("Content-Type: multipart/alternative; boundary="));
+        assert!(mime.contains(&STANDARD.encode("See")[..3]));
+        assert!(mime.contains(
+            "Content-Type: text/csv\r\nContent-Transfer-Encoding: base64\r\nContent-Disposition: \
+             attachment; filename=\"report.csv\"\r\n\r\n"
+        ));
+        assert!(mime.contains(&format!("{}\r\n", STANDARD.encode("a,b\n1,2\n"))));
+        assert!(mime.contains(
+            "Content-Disposition: inline; filename=\"chart.png\"\r\nContent-ID: <chart>\r\n"
+        ));
+        // related part closes before the attachment in the mixed part
+        let related_end = mime.rfind("--\r\n--=_").unwrap();
+        assert!(related_end < mime.find("filename=\"report.csv\"").unwrap());
+        assert!(mime.ends_with("--\r\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_text_only_mime() -> Result<(), Error> {
+        let request = EmailRequest {
+            subject: "Zoë".into(),
+            text: Some("plain".into()),
+            html: None,
+            attachments: vec![Attachment::new("résumé.pdf", vec![1, 2, 3])],
+            headers: Vec::new(),
+            ..request()?
+        };
+        let mime = String::from_utf8(build_mime(&request)?)?;
+        assert!(mime.contains("Subject: =?UTF-8?B?Wm/Dqw==?=\r\n"));
+        assert!(!mime.contains("multipart/alternative"));
+        assert!(!mime.contains("multipart/related"));
+        assert!(mime.contains("Content-Type: text/plain; charset=UTF-8\r\n"));
+        assert!(mime.contains("filename*=UTF-8''r%C3%A9sum%C3%A9.pdf"));
+        assert!(mime.contains("Content-Type: application/pdf\r\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_header_injection() -> Result<(), Error> {
+        let injected = EmailRequest {
+            subject: "Hello\r\nBcc: victim@example.com".into(),
+            ..request()?
+        };
+        assert!(build_mime(&injected).is_err());
+        assert!(injected.validate().is_err());
+
+        let mut injected = request()?;
+        injected.to[0].name = Some("Bob\r\nBcc: victim@example.com".into());
+        assert!(build_mime(&injected).is_err());
+        assert!(injected.validate().is_err());
+
+        let mut injected = request()?;
+        injected.from.name = Some("Reports\u{0}".into());
+        assert!(build_mime(&injected).is_err());
+
+        for attachment in [
+            Attachment::new("a.csv\r\nBcc: victim@example.com", b"x".to_vec()),
+            Attachment {
+                content_type: "text/csv\r\nBcc: victim@example.com".into(),
+                ..Attachment::new("a.csv", b"x".to_vec())
+            },
+            Attachment::new("a.png", b"x".to_vec()).inline("a\r\nBcc: victim@example.com"),
+        ] {
+            let injected = EmailRequest {
+                attachments: vec![attachment],
+                ..request()?
+            };
+            assert!(build_mime(&injected).is_err());
+            assert!(injected.validate().is_err());
+        }
+        assert!("\"Bob\nBcc: x@example.com\" <bob@example.com>"
+            .parse::<EmailAddress>()
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_headers() {
+        assert!(validate_header("X-Campaign", "spring").is_ok());
+        assert!(validate_header("Bcc", "x@example.com").is_err());
+        assert!(validate_header("X Bad", "value").is_err());
+        assert!(validate_header("X-Injected", "a\r\nBcc: x@example.com").is_err());
+        assert_eq!(content_type_for("REPORT.PDF"), "application/pdf");
+        assert_eq!(content_type_for("archive"), "application/octet-stream");
+    }
+}
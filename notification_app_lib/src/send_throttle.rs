@@ -0,0 +1,273 @@
+use std::{
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{config::Config, ses_client::SesQuotas};
+
+/// Returned when the SES daily quota stays too close to its limit for longer
+/// than the configured wait
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuotaExhausted {
+    /// Sends left in the 24 hour window when giving up
+    pub remaining: f64,
+    pub max_24_hour_send: f64,
+}
+
+impl fmt::Display for QuotaExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "SES daily sending quota exhausted, {:.0} of {:.0} left",
+            self.remaining.max(0.0),
+            self.max_24_hour_send
+        )
+    }
+}
+
+impl std::error::Error for QuotaExhausted {}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ThrottleSettings {
+    /// How often the quota is fetched from SES
+    pub refresh: Duration,
+    /// Fraction of the daily quota kept in reserve, sends that would dip
+    /// into it are held back
+    pub reserve: f64,
+    /// How long a held back send waits for quota before failing
+    pub max_wait: Duration,
+}
+
+impl From<&Config> for ThrottleSettings {
+    fn from(config: &Config) -> Self {
+        Self {
+            refresh: Duration::from_secs(config.ses_quota_refresh_seconds),
+            reserve: config.ses_quota_reserve,
+            max_wait: Duration::from_secs(config.ses_quota_wait_seconds),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ThrottleState {
+    quota: Option<SesQuotas>,
+    fetched: Option<Instant>,
+    /// Recipients sent to since the quota was fetched
+    sent_since_fetch: f64,
+    /// Earliest time the next send may start
+    next_slot: Instant,
+}
+
+impl ThrottleState {
+    /// Sends left in the 24 hour window, `None` when the quota is unknown or
+    /// unlimited
+    fn remaining(&self) -> Option<f64> {
+        let quota = self.quota.filter(|q| q.max_24_hour_send >= 0.0)?;
+        Some(quota.max_24_hour_send - quota.sent_last_24_hours - self.sent_since_fetch)
+    }
+
+    /// Sends left when sending to `recipients` would dip into the reserve
+    fn quota_low(&self, recipients: f64, reserve: f64) -> Option<f64> {
+        let max_24_hour_send = self.quota?.max_24_hour_send;
+        self.remaining()
+            .filter(|remaining| remaining - recipients < max_24_hour_send * reserve)
+    }
+}
+
+/// Result of asking to send to a number of recipients
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reservation {
+    /// Send after this delay, which keeps within `max_send_rate`
+    Ready(Duration),
+    /// The send would dip into the reserve of the daily quota
+    QuotaLow { remaining: f64 },
+}
+
+/// Local view of the SES quota, spacing sends to the allowed rate and
+/// holding them back when the daily quota is nearly used up
+#[derive(Debug)]
+pub struct SendThrottle {
+    settings: ThrottleSettings,
+    state: Mutex<ThrottleState>,
+}
+
+impl SendThrottle {
+    #[must_use]
+    pub fn new(settings: ThrottleSettings) -> Self {
+        Self {
+            settings,
+            state: Mutex::new(ThrottleState {
+                quota: None,
+                fetched: None,
+                sent_since_fetch: 0.0,
+                next_slot: Instant::now(),
+            }),
+        }
+    }
+
+    #[must_use]
+    pub fn settings(&self) -> ThrottleSettings {
+        self.settings
+    }
+
+    fn with_state<T>(&self, f: impl FnOnce(&mut ThrottleState) -> T) -> T {
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        f(&mut state)
+    }
+
+    #[must_use]
+    pub fn needs_refresh(&self, now: Instant) -> bool {
+        self.with_state(|state| {
+            state
+                .fetched
+                .is_none_or(|fetched| now >= fetched + self.settings.refresh)
+        })
+    }
+
+    /// Replace the local quota with one freshly fetched from SES
+    pub fn update(&self, quota: SesQuotas, now: Instant) {
+        self.with_state(|state| {
+            state.quota = Some(quota);
+            state.fetched = Some(now);
+            state.sent_since_fetch = 0.0;
+        });
+    }
+
+    /// Sends left in the 24 hour window, `None` when the quota is unknown or
+    /// unlimited
+    #[must_use]
+    pub fn remaining(&self) -> Option<f64> {
+        self.with_state(|state| state.remaining())
+    }
+
+    /// Fail without waiting when sending to `recipients` addresses would
+    /// already dip into the reserve, reserves nothing
+    /// # Errors
+    /// Return [`QuotaExhausted`] if the quota is down to its reserve
+    pub fn check(&self, recipients: usize) -> Result<(), QuotaExhausted> {
+        let remaining =
+            self.with_state(|state| state.quota_low(recipients as f64, self.settings.reserve));
+        match remaining {
+            Some(remaining) => Err(self.exhausted(remaining)),
+            None => Ok(()),
+        }
+    }
+
+    /// Reserve a send to `recipients` addresses, SES counts each recipient
+    /// against both the rate and the daily quota. Sends are not held back
+    /// before the quota has been fetched.
+    pub fn reserve(&self, recipients: usize, now: Instant) -> Reservation {
+        let recipients = recipients as f64;
+        let reserve = self.settings.reserve;
+        self.with_state(|state| {
+            let Some(quota) = state.quota else {
+                return Reservation::Ready(Duration::ZERO);
+            };
+            if let Some(remaining) = state.quota_low(recipients, reserve) {
+                return Reservation::QuotaLow { remaining };
+            }
+            state.sent_since_fetch += recipients;
+            if quota.max_send_rate <= 0.0 {
+                return Reservation::Ready(Duration::ZERO);
+            }
+            let slot = state.next_slot.max(now);
+            state.next_slot = slot + Duration::from_secs_f64(recipients / quota.max_send_rate);
+            Reservation::Ready(slot - now)
+        })
+    }
+
+    #[must_use]
+    pub fn exhausted(&self, remaining: f64) -> QuotaExhausted {
+        let max_24_hour_send =
+            self.with_state(|state| state.quota.map_or(0.0, |quota| quota.max_24_hour_send));
+        QuotaExhausted {
+            remaining,
+            max_24_hour_send,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::{
+        send_throttle::{Reservation, SendThrottle, ThrottleSettings},
+        ses_client::SesQuotas,
+    };
+
+    #[test]
+    fn test_send_throttle() {
+        let throttle = SendThrottle::new(ThrottleSettings {
+            refresh: Duration::from_secs(60),
+            reserve: 0.05,
+            max_wait: Duration::from_secs(120),
+        });
+        let now = Instant::now();
+        assert!(throttle.needs_refresh(now));
+        assert_eq!(
+            throttle.reserve(10, now),
+            Reservation::Ready(Duration::ZERO)
+        );
+
+        throttle.update(
+            SesQuotas {
+                max_24_hour_send: 200.0,
+                max_send_rate: 2.0,
+                sent_last_24_hours: 150.0,
+            },
+            now,
+        );
+        assert!(!throttle.needs_refresh(now + Duration::from_secs(59)));
+        assert!(throttle.needs_refresh(now + Duration::from_secs(60)));
+
+        // two per second: the second send of 3 waits for the first
+        assert_eq!(throttle.reserve(3, now), Reservation::Ready(Duration::ZERO));
+        assert_eq!(
+            throttle.reserve(1, now),
+            Reservation::Ready(Duration::from_millis(1500))
+        );
+        let later = now + Duration::from_secs(10);
+        assert_eq!(
+            throttle.reserve(1, later),
+            Reservation::Ready(Duration::ZERO)
+        );
+        assert_eq!(throttle.remaining(), Some(45.0));
+        assert_eq!(throttle.check(35), Ok(()));
+        assert_eq!(throttle.check(36), Err(throttle.exhausted(45.0)));
+
+        // 10 of the 200 are held in reserve
+        assert_eq!(
+            throttle.reserve(35, later),
+            Reservation::Ready(Duration::from_millis(500))
+        );
+        assert_eq!(
+            throttle.reserve(1, later + Duration::from_secs(60)),
+            Reservation::QuotaLow { remaining: 10.0 }
+        );
+        let error = throttle.exhausted(10.0);
+        assert_eq!(
+            error.to_string(),
+            "SES daily sending quota exhausted, 10 of 200 left"
+        );
+
+        throttle.update(
+            SesQuotas {
+                max_24_hour_send: -1.0,
+                max_send_rate: 0.0,
+                sent_last_24_hours: 5000.0,
+            },
+            later,
+        );
+        assert_eq!(throttle.remaining(), None);
+        assert_eq!(throttle.check(50), Ok(()));
+        assert_eq!(
+            throttle.reserve(50, later),
+            Reservation::Ready(Duration::ZERO)
+        );
+    }
+}
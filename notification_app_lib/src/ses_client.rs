@@ -1,15 +1,40 @@
 use anyhow::Error;
-use aws_config::SdkConfig;
+use aws_config::{BehaviorVersion, ConfigLoader, SdkConfig};
 use aws_sdk_ses::{
-    types::{Body, Content, Destination, Message},
+    config::Region,
+    primitives::Blob,
+    types::{Body, Content, Destination, Message, RawMessage, VerificationStatus},
     Client as SesClient,
 };
-use std::fmt;
+use stack_string::{format_sstr, StackString};
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use time::OffsetDateTime;
+use tokio::time::sleep;
+
+use crate::{
+    circuit_breaker::CIRCUITS,
+    config::Config,
+    email::{EmailAddress, EmailRequest},
+    send_throttle::{Reservation, SendThrottle, ThrottleSettings},
+};
+
+fn addresses(list: &[EmailAddress]) -> Option<Vec<String>> {
+    if list.is_empty() {
+        None
+    } else {
+        Some(list.iter().map(ToString::to_string).collect())
+    }
+}
 
 #[derive(Clone)]
 pub struct SesInstance {
     ses_client: SesClient,
+    throttle: Option<Arc<SendThrottle>>,
 }
 
 impl fmt::Debug for SesInstance {
@@ -27,45 +52,232 @@ impl SesInstance {
     fn from_conf(config: &SdkConfig) -> Self {
         Self {
             ses_client: SesClient::new(config),
+            throttle: None,
         }
     }
 
-    /// # Errors
-    /// Return error if api call fails
-    pub async fn send_email(
-        &self,
-        src: impl Into<String>,
-        dest: impl Into<String>,
-        sub: impl Into<String>,
-        msg: impl Into<String>,
-    ) -> Result<(), Error> {
-        let destination = Destination::builder()
-            .set_to_addresses(Some(vec![dest.into()]))
-            .build();
+    /// Keep sends within the SES sending rate and daily quota
+    #[must_use]
+    pub fn with_throttle(mut self, settings: ThrottleSettings) -> Self {
+        self.throttle = Some(Arc::new(SendThrottle::new(settings)));
+        self
+    }
+
+    #[must_use]
+    pub fn throttle(&self) -> Option<&SendThrottle> {
+        self.throttle.as_deref()
+    }
+
+    /// Client for the region, endpoint and credentials profile set by
+    /// `SES_REGION`, `SES_ENDPOINT_URL` and `SES_PROFILE`, anything unset
+    /// falls back to the usual AWS environment variables and profile. Sends
+    /// are throttled to the account quota.
+    pub async fn from_config(config: &Config) -> Self {
+        Self::new(&Self::loader(config).load().await).with_throttle(config.into())
+    }
+
+    pub(crate) fn loader(config: &Config) -> ConfigLoader {
+        let mut loader = aws_config::defaults(BehaviorVersion::latest());
+        if let Some(region) = &config.ses_region {
+            loader = loader.region(Region::new(region.to_string()));
+        }
+        if let Some(endpoint_url) = &config.ses_endpoint_url {
+            loader = loader.endpoint_url(endpoint_url.as_str());
+        }
+        if let Some(profile) = &config.ses_profile {
+            loader = loader.profile_name(profile.as_str());
+        }
+        loader
+    }
+
+    fn destination(request: &EmailRequest) -> Destination {
+        Destination::builder()
+            .set_to_addresses(addresses(&request.to))
+            .set_cc_addresses(addresses(&request.cc))
+            .set_bcc_addresses(addresses(&request.bcc))
+            .build()
+    }
+
+    fn message(request: &EmailRequest) -> Result<Message, Error> {
         let subject = Content::builder()
             .set_charset(Some("UTF-8".into()))
-            .set_data(Some(sub.into()))
+            .set_data(Some(request.subject.to_string()))
             .build()?;
-        let html = Content::builder()
+        let (text, html) = request.parts()?;
+        let text = Content::builder()
             .set_charset(Some("UTF-8".into()))
-            .set_data(Some(msg.into()))
+            .set_data(Some(text.into()))
             .build()?;
-        let body = Body::builder().text(html.clone()).html(html).build();
-        let message = Message::builder().subject(subject).body(body).build();
-        self.ses_client
+        let html = html
+            .map(|html| {
+                Content::builder()
+                    .set_charset(Some("UTF-8".into()))
+                    .set_data(Some(html.to_string()))
+                    .build()
+            })
+            .transpose()?;
+        let body = Body::builder().text(text).set_html(html).build();
+        Ok(Message::builder().subject(subject).body(body).build())
+    }
+
+    /// Wait until sending to `recipients` addresses keeps within the quota,
+    /// refreshing it from SES when stale. While the daily quota is down to
+    /// its reserve the send is held for up to `SES_QUOTA_WAIT_SECONDS` as
+    /// earlier sends age out of the 24 hour window.
+    /// # Errors
+    /// Return [`QuotaExhausted`](crate::send_throttle::QuotaExhausted) if
+    /// the quota does not recover in time
+    pub async fn wait_for_quota(&self, recipients: usize) -> Result<(), Error> {
+        let Some(throttle) = &self.throttle else {
+            return Ok(());
+        };
+        let settings = throttle.settings();
+        let deadline = Instant::now() + settings.max_wait;
+        loop {
+            if throttle.needs_refresh(Instant::now()) {
+                // a failed fetch keeps the last known quota, the send itself
+                // reports the error if SES is unreachable
+                if let Ok(quota) = self.get_send_quota().await {
+                    throttle.update(quota, Instant::now());
+                }
+            }
+            let now = Instant::now();
+            match throttle.reserve(recipients, now) {
+                Reservation::Ready(delay) => {
+                    if !delay.is_zero() {
+                        sleep(delay).await;
+                    }
+                    return Ok(());
+                }
+                Reservation::QuotaLow { remaining } => {
+                    if now >= deadline {
+                        return Err(throttle.exhausted(remaining).into());
+                    }
+                    let pause = settings.refresh.max(Duration::from_secs(1));
+                    sleep(pause.min(deadline - now)).await;
+                }
+            }
+        }
+    }
+
+    /// # Errors
+    /// Return error if the request has no recipients, the api call fails,
+    /// the SES circuit is open or the daily quota is exhausted
+    pub async fn send_email(&self, request: &EmailRequest) -> Result<(), Error> {
+        if request.is_raw() {
+            return self.send_raw_email(request).await;
+        }
+        request.validate()?;
+        self.wait_for_quota(request.recipient_count()).await?;
+        let message = Self::message(request)?;
+        let admission = CIRCUITS.ses.check()?;
+        let result = self
+            .ses_client
             .send_email()
-            .destination(destination)
-            .source(src)
+            .destination(Self::destination(request))
+            .set_reply_to_addresses(addresses(&request.reply_to))
+            .source(request.from.to_string())
             .message(message)
             .send()
-            .await?;
+            .await;
+        CIRCUITS.ses.record(admission, &result);
+        result?;
         Ok(())
     }
 
+    /// Send `request` as a raw MIME message, needed for attachments, inline
+    /// images and custom headers. Bcc recipients are only passed as
+    /// destinations, never written into the message.
+    /// # Errors
+    /// Return error if the request is invalid, the api call fails, the SES
+    /// circuit is open or the daily quota is exhausted
+    pub async fn send_raw_email(&self, request: &EmailRequest) -> Result<(), Error> {
+        request.validate()?;
+        self.wait_for_quota(request.recipient_count()).await?;
+        let raw_message = RawMessage::builder()
+            .data(Blob::new(request.to_mime()?))
+            .build()?;
+        let destinations = request
+            .to
+            .iter()
+            .chain(&request.cc)
+            .chain(&request.bcc)
+            .map(|address| address.address.to_string())
+            .collect();
+        let admission = CIRCUITS.ses.check()?;
+        let result = self
+            .ses_client
+            .send_raw_email()
+            .set_destinations(Some(destinations))
+            .source(request.from.to_string())
+            .raw_message(raw_message)
+            .send()
+            .await;
+        CIRCUITS.ses.record(admission, &result);
+        result?;
+        Ok(())
+    }
+
+    /// # Errors
+    /// Returns error if api call fails or the SES circuit is open
+    pub async fn get_send_quota(&self) -> Result<SesQuotas, Error> {
+        let admission = CIRCUITS.ses.check()?;
+        let quota = self.ses_client.get_send_quota().send().await;
+        CIRCUITS.ses.record(admission, &quota);
+        let quota = quota?;
+        Ok(SesQuotas {
+            max_24_hour_send: quota.max24_hour_send,
+            max_send_rate: quota.max_send_rate,
+            sent_last_24_hours: quota.sent_last24_hours,
+        })
+    }
+
+    /// Addresses and domains SES has verified for sending
     /// # Errors
-    /// Returns error if api call fails
+    /// Returns error if an api call fails
+    pub async fn verified_identities(&self) -> Result<HashSet<StackString>, Error> {
+        let mut identities = Vec::new();
+        let mut next_token = None;
+        loop {
+            let output = self
+                .ses_client
+                .list_identities()
+                .set_next_token(next_token)
+                .send()
+                .await?;
+            identities.extend(output.identities);
+            next_token = output.next_token;
+            if next_token.is_none() {
+                break;
+            }
+        }
+        let mut verified = HashSet::new();
+        // at most 100 identities per verification request
+        for chunk in identities.chunks(100) {
+            let output = self
+                .ses_client
+                .get_identity_verification_attributes()
+                .set_identities(Some(chunk.to_vec()))
+                .send()
+                .await?;
+            verified.extend(
+                output
+                    .verification_attributes
+                    .into_iter()
+                    .filter(|(_, a)| a.verification_status == VerificationStatus::Success)
+                    .map(|(identity, _)| identity.into()),
+            );
+        }
+        Ok(verified)
+    }
+
+    /// # Errors
+    /// Returns error if api call fails or the SES circuit is open
     pub async fn get_statistics(&self) -> Result<(SesQuotas, EmailStats), Error> {
-        let quota = self.ses_client.get_send_quota().send().await?;
+        let quota = self.get_send_quota().await?;
+        if let Some(throttle) = &self.throttle {
+            throttle.update(quota, Instant::now());
+        }
         let stats = self
             .ses_client
             .get_send_statistics()
@@ -99,23 +311,25 @@ impl SesInstance {
                 }
                 stats
             });
-        let quota = SesQuotas {
-            max_24_hour_send: quota.max24_hour_send,
-            max_send_rate: quota.max_send_rate,
-            sent_last_24_hours: quota.sent_last24_hours,
-        };
         Ok((quota, stats))
     }
 }
 
-#[derive(Default, Debug)]
+/// SES puts an account under review at a 5% bounce rate and may pause
+/// sending at 10%
+pub const BOUNCE_RATE_WARNING: f64 = 0.05;
+/// SES puts an account under review at a 0.1% complaint rate and may pause
+/// sending at 0.5%
+pub const COMPLAINT_RATE_WARNING: f64 = 0.001;
+
+#[derive(Default, Debug, Clone, Copy)]
 pub struct SesQuotas {
     pub max_24_hour_send: f64,
     pub max_send_rate: f64,
     pub sent_last_24_hours: f64,
 }
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone, Copy)]
 pub struct EmailStats {
     pub bounces: i64,
     pub complaints: i64,
@@ -124,3 +338,344 @@ pub struct EmailStats {
     pub min_timestamp: Option<OffsetDateTime>,
     pub max_timestamp: Option<OffsetDateTime>,
 }
+
+impl EmailStats {
+    fn rate(&self, count: i64) -> f64 {
+        if self.delivery_attempts > 0 {
+            count as f64 / self.delivery_attempts as f64
+        } else {
+            0.0
+        }
+    }
+
+    /// Bounces per delivery attempt
+    #[must_use]
+    pub fn bounce_rate(&self) -> f64 {
+        self.rate(self.bounces)
+    }
+
+    /// Complaints per delivery attempt
+    #[must_use]
+    pub fn complaint_rate(&self) -> f64 {
+        self.rate(self.complaints)
+    }
+
+    /// Warnings for bounce and complaint rates at which SES reviews, and
+    /// eventually pauses, the account
+    #[must_use]
+    pub fn warnings(&self) -> Vec<StackString> {
+        let mut warnings = Vec::new();
+        if self.bounce_rate() >= BOUNCE_RATE_WARNING {
+            warnings.push(format_sstr!(
+                "Bounce rate {:.2}% is at or above the {:.0}% SES review threshold",
+                self.bounce_rate() * 100.0,
+                BOUNCE_RATE_WARNING * 100.0
+            ));
+        }
+        if self.complaint_rate() >= COMPLAINT_RATE_WARNING {
+            warnings.push(format_sstr!(
+                "Complaint rate {:.2}% is at or above the {:.1}% SES review threshold",
+                self.complaint_rate() * 100.0,
+                COMPLAINT_RATE_WARNING * 100.0
+            ));
+        }
+        warnings
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use anyhow::{format_err, Error};
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::{
+        config::{Config, ConfigInner},
+        email::EmailRequest,
+        mime_message::Attachment,
+        send_throttle::{QuotaExhausted, ThrottleSettings},
+        ses_client::SesInstance,
+    };
+
+    type Requests = Arc<Mutex<Vec<HashMap<String, String>>>>;
+
+    fn ses_response(action: &str, result: &str) -> String {
+        format!(
+            "<{action}Response xmlns=\"http://ses.amazonaws.com/doc/2010-12-01/\">\
+             <{action}Result>{result}</{action}Result>\
+             <ResponseMetadata><RequestId>test</RequestId></ResponseMetadata>\
+             </{action}Response>"
+        )
+    }
+
+    fn data_point(timestamp: &str, attempts: i64, bounces: i64) -> String {
+        format!(
+            "<member><Timestamp>{timestamp}</Timestamp>\
+             <DeliveryAttempts>{attempts}</DeliveryAttempts><Bounces>{bounces}</Bounces>\
+             <Complaints>0</Complaints><Rejects>1</Rejects></member>"
+        )
+    }
+
+    fn verification(identity: &str, status: &str) -> String {
+        format!(
+            "<entry><key>{identity}</key><value>\
+             <VerificationStatus>{status}</VerificationStatus></value></entry>"
+        )
+    }
+
+    async fn handle_connection(stream: TcpStream, requests: Requests) -> Result<(), Error> {
+        let mut stream = BufReader::new(stream);
+        loop {
+            let mut content_length = 0;
+            let mut line = String::new();
+            if stream.read_line(&mut line).await? == 0 {
+                return Ok(());
+            }
+            loop {
+                line.clear();
+                stream.read_line(&mut line).await?;
+                let header = line.trim_end();
+                if header.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse()?;
+                    }
+                }
+            }
+            let mut body = vec![0; content_length];
+            stream.read_exact(&mut body).await?;
+            let params: HashMap<String, String> =
+                url::form_urlencoded::parse(&body).into_owned().collect();
+            let action = params.get("Action").cloned().unwrap_or_default();
+            let first_page = !params.contains_key("NextToken");
+            requests.lock().unwrap().push(params);
+            let response = match action.as_str() {
+                "SendEmail" | "SendRawEmail" => {
+                    ses_response(&action, "<MessageId>test-message</MessageId>")
+                }
+                "GetSendQuota" => ses_response(
+                    &action,
+                    "<Max24HourSend>200</Max24HourSend><MaxSendRate>1</MaxSendRate>\
+                     <SentLast24Hours>12</SentLast24Hours>",
+                ),
+                "GetSendStatistics" => ses_response(
+                    &action,
+                    &format!(
+                        "<SendDataPoints>{}{}</SendDataPoints>",
+                        data_point("2024-01-01T00:00:00Z", 10, 1),
+                        data_point("2024-01-01T00:15:00Z", 5, 0),
+                    ),
+                ),
+                "ListIdentities" if first_page => ses_response(
+                    &action,
+                    "<Identities><member>example.com</member></Identities>\
+                     <NextToken>page2</NextToken>",
+                ),
+                "ListIdentities" => ses_response(
+                    &action,
+                    "<Identities><member>bot@other.com</member>\
+                     <member>pending.com</member></Identities>",
+                ),
+                "GetIdentityVerificationAttributes" => ses_response(
+                    &action,
+                    &format!(
+                        "<VerificationAttributes>{}{}{}</VerificationAttributes>",
+                        verification("example.com", "Success"),
+                        verification("bot@other.com", "Success"),
+                        verification("pending.com", "Pending"),
+                    ),
+                ),
+                _ => return Err(format_err!("Unexpected action {action}")),
+            };
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\nContent-Length: {}\r\n\r\n",
+                response.len()
+            );
+            stream.get_mut().write_all(head.as_bytes()).await?;
+            stream.get_mut().write_all(response.as_bytes()).await?;
+        }
+    }
+
+    /// Local stand-in for the SES query api, returns its endpoint and the
+    /// form parameters of every request
+    pub(crate) async fn fake_ses() -> Result<(String, Requests), Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let endpoint = format!("http://{}", listener.local_addr()?);
+        let requests = Requests::default();
+        let server_requests = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handle_connection(stream, server_requests.clone()));
+            }
+        });
+        Ok((endpoint, requests))
+    }
+
+    #[tokio::test]
+    async fn test_fake_ses() -> Result<(), Error> {
+        let (endpoint, requests) = fake_ses().await?;
+        let config: Config = ConfigInner {
+            ses_region: Some("us-west-2".into()),
+            ses_endpoint_url: Some(endpoint.parse()?),
+            ..ConfigInner::default()
+        }
+        .into();
+        let sdk_config = SesInstance::loader(&config).test_credentials().load().await;
+        assert_eq!(sdk_config.region().unwrap().as_ref(), "us-west-2");
+        let ses = SesInstance::new(&sdk_config);
+
+        let request = EmailRequest {
+            from: "Bot <bot@example.com>".parse()?,
+            to: vec!["a@example.com".parse()?],
+            bcc: vec!["b@example.com".parse()?],
+            subject: "Disk usage".into(),
+            text: Some("95%".into()),
+            ..EmailRequest::default()
+        };
+        ses.send_email(&request).await?;
+        let raw_request = EmailRequest {
+            attachments: vec![Attachment::new("usage.csv", b"host,usage".to_vec())],
+            ..request.clone()
+        };
+        ses.send_email(&raw_request).await?;
+
+        let (quota, stats) = ses.get_statistics().await?;
+        assert_eq!(quota.max_24_hour_send, 200.0);
+        assert_eq!(quota.max_send_rate, 1.0);
+        assert_eq!(quota.sent_last_24_hours, 12.0);
+        assert_eq!(stats.delivery_attempts, 15);
+        assert_eq!(stats.bounces, 1);
+        assert_eq!(stats.rejects, 2);
+        assert!(stats.min_timestamp < stats.max_timestamp);
+        assert!((stats.bounce_rate() - 1.0 / 15.0).abs() < 1e-9);
+        assert_eq!(stats.complaint_rate(), 0.0);
+        let warnings = stats.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(
+            warnings[0].starts_with("Bounce rate 6.67%"),
+            "{}",
+            warnings[0]
+        );
+
+        let settings = ThrottleSettings {
+            refresh: Duration::from_secs(60),
+            reserve: 0.05,
+            max_wait: Duration::ZERO,
+        };
+        let throttled = SesInstance::new(&sdk_config).with_throttle(settings);
+        throttled.send_email(&request).await?;
+        assert_eq!(throttled.throttle().unwrap().remaining(), Some(186.0));
+        // 188 left is inside a 190 reserve
+        let reserved = SesInstance::new(&sdk_config).with_throttle(ThrottleSettings {
+            reserve: 0.95,
+            ..settings
+        });
+        let error = reserved.send_email(&request).await.unwrap_err();
+        let exhausted = error.downcast_ref::<QuotaExhausted>().unwrap();
+        assert_eq!(exhausted.remaining, 188.0);
+
+        let identities = ses.verified_identities().await?;
+        assert_eq!(identities.len(), 2);
+        assert!(identities.contains("bot@other.com"));
+        assert!(!identities.contains("pending.com"));
+
+        let requests = requests.lock().unwrap();
+        let actions: Vec<_> = requests.iter().map(|r| r["Action"].as_str()).collect();
+        assert_eq!(
+            actions,
+            [
+                "SendEmail",
+                "SendRawEmail",
+                "GetSendQuota",
+                "GetSendStatistics",
+                "GetSendQuota",
+                "SendEmail",
+                "GetSendQuota",
+                "ListIdentities",
+                "ListIdentities",
+                "GetIdentityVerificationAttributes"
+            ]
+        );
+        let send = &requests[0];
+        assert_eq!(send["Source"], "\"Bot\" <bot@example.com>");
+        assert_eq!(send["Destination.ToAddresses.member.1"], "a@example.com");
+        assert_eq!(send["Destination.BccAddresses.member.1"], "b@example.com");
+        assert_eq!(send["Message.Subject.Data"], "Disk usage");
+        assert_eq!(send["Message.Body.Text.Data"], "95%");
+        let raw = &requests[1];
+        assert_eq!(raw["Destinations.member.2"], "b@example.com");
+        let mime = String::from_utf8(STANDARD.decode(&raw["RawMessage.Data"])?)?;
+        assert!(mime.contains("filename=\"usage.csv\""));
+        Ok(())
+    }
+
+    #[test]
+    fn test_destination() -> Result<(), Error> {
+        let request = EmailRequest {
+            to: vec!["a@example.com".parse()?, "B <b@example.com>".parse()?],
+            bcc: vec!["c@example.com".parse()?],
+            ..EmailRequest::default()
+        };
+        let destination = SesInstance::destination(&request);
+        assert_eq!(
+            destination.to_addresses(),
+            ["a@example.com", "\"B\" <b@example.com>"]
+        );
+        assert!(destination.cc_addresses.is_none());
+        assert_eq!(destination.bcc_addresses(), ["c@example.com"]);
+        Ok(())
+    }
+
+    fn body_parts(request: &EmailRequest) -> Result<(String, Option<String>), Error> {
+        let message = SesInstance::message(request)?;
+        let body = message.body().unwrap();
+        let text = body.text().unwrap().data().to_string();
+        let html = body.html().map(|html| html.data().to_string());
+        Ok((text, html))
+    }
+
+    #[test]
+    fn test_message() -> Result<(), Error> {
+        let request = EmailRequest {
+            subject: "subject".into(),
+            text: Some("plain".into()),
+            ..EmailRequest::default()
+        };
+        let message = SesInstance::message(&request)?;
+        assert_eq!(message.subject().unwrap().data(), "subject");
+        assert_eq!(message.subject().unwrap().charset(), Some("UTF-8"));
+        assert_eq!(body_parts(&request)?, ("plain".into(), None));
+
+        let request = EmailRequest {
+            html: Some("<p>Hello <b>there</b></p>".into()),
+            ..request
+        };
+        assert_eq!(
+            body_parts(&request)?,
+            ("plain".into(), Some("<p>Hello <b>there</b></p>".into()))
+        );
+
+        let request = EmailRequest {
+            text: None,
+            ..request
+        };
+        assert_eq!(
+            body_parts(&request)?,
+            (
+                "Hello there".into(),
+                Some("<p>Hello <b>there</b></p>".into())
+            )
+        );
+        Ok(())
+    }
+}
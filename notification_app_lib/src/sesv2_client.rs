@@ -0,0 +1,366 @@
+use anyhow::{format_err, Error};
+use aws_config::SdkConfig;
+use aws_sdk_sesv2::{
+    error::DisplayErrorContext,
+    primitives::Blob,
+    types::{
+        Body, Content, Destination, EmailContent, ListManagementOptions, Message, MessageTag,
+        RawMessage,
+    },
+    Client as SesV2Client,
+};
+use stack_string::StackString;
+use std::fmt;
+
+use crate::{
+    circuit_breaker::CIRCUITS,
+    config::Config,
+    email::{EmailAddress, EmailRequest},
+    ses_client::SesInstance,
+};
+
+fn addresses(list: &[EmailAddress]) -> Option<Vec<String>> {
+    if list.is_empty() {
+        None
+    } else {
+        Some(list.iter().map(ToString::to_string).collect())
+    }
+}
+
+fn utf8(data: impl Into<String>) -> Result<Content, Error> {
+    Content::builder()
+        .charset("UTF-8")
+        .data(data)
+        .build()
+        .map_err(Into::into)
+}
+
+/// Sends through the SESv2 api, which adds configuration sets, message tags
+/// and contact list unsubscribe links. Quota and statistics still come from
+/// the SES client since both apis share the account quota.
+#[derive(Clone)]
+pub struct SesV2Instance {
+    ses: SesInstance,
+    sesv2_client: SesV2Client,
+    configuration_set: Option<StackString>,
+    contact_list: Option<StackString>,
+    contact_list_topic: Option<StackString>,
+}
+
+impl fmt::Debug for SesV2Instance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SesV2Instance")
+    }
+}
+
+impl SesV2Instance {
+    #[must_use]
+    pub fn new(sdk_config: &SdkConfig) -> Self {
+        Self {
+            ses: SesInstance::new(sdk_config),
+            sesv2_client: SesV2Client::new(sdk_config),
+            configuration_set: None,
+            contact_list: None,
+            contact_list_topic: None,
+        }
+    }
+
+    /// Configuration set whose event destinations receive sends, bounces
+    /// and complaints
+    #[must_use]
+    pub fn with_configuration_set(mut self, name: Option<StackString>) -> Self {
+        self.configuration_set = name;
+        self
+    }
+
+    /// Contact list (and optionally topic) used for the unsubscribe link SES
+    /// adds to every email
+    #[must_use]
+    pub fn with_contact_list(
+        mut self,
+        list: Option<StackString>,
+        topic: Option<StackString>,
+    ) -> Self {
+        self.contact_list = list;
+        self.contact_list_topic = topic;
+        self
+    }
+
+    /// Client for the same region, endpoint and profile as
+    /// [`SesInstance::from_config`], using `SES_CONFIGURATION_SET`,
+    /// `SES_CONTACT_LIST` and `SES_CONTACT_LIST_TOPIC`
+    pub async fn from_config(config: &Config) -> Self {
+        let sdk_config = SesInstance::loader(config).load().await;
+        let mut sesv2 = Self::new(&sdk_config)
+            .with_configuration_set(config.ses_configuration_set.clone())
+            .with_contact_list(
+                config.ses_contact_list.clone(),
+                config.ses_contact_list_topic.clone(),
+            );
+        sesv2.ses = sesv2.ses.with_throttle(config.into());
+        sesv2
+    }
+
+    /// The SES client used for quota and statistics
+    #[must_use]
+    pub fn ses(&self) -> &SesInstance {
+        &self.ses
+    }
+
+    fn content(request: &EmailRequest) -> Result<(Destination, EmailContent), Error> {
+        if request.is_raw() {
+            // the raw message carries to and cc itself, bcc is only ever a
+            // destination
+            let recipients = request
+                .to
+                .iter()
+                .chain(&request.cc)
+                .chain(&request.bcc)
+                .map(|address| address.address.to_string())
+                .collect();
+            let destination = Destination::builder()
+                .set_bcc_addresses(Some(recipients))
+                .build();
+            let raw = RawMessage::builder()
+                .data(Blob::new(request.to_mime()?))
+                .build()?;
+            return Ok((destination, EmailContent::builder().raw(raw).build()));
+        }
+        let destination = Destination::builder()
+            .set_to_addresses(addresses(&request.to))
+            .set_cc_addresses(addresses(&request.cc))
+            .set_bcc_addresses(addresses(&request.bcc))
+            .build();
+        let (text, html) = request.parts()?;
+        let body = Body::builder()
+            .text(utf8(text)?)
+            .set_html(html.map(|html| utf8(html.as_str())).transpose()?)
+            .build();
+        let message = Message::builder()
+            .subject(utf8(request.subject.as_str())?)
+            .body(body)
+            .build();
+        Ok((destination, EmailContent::builder().simple(message).build()))
+    }
+
+    fn list_management(&self) -> Result<Option<ListManagementOptions>, Error> {
+        self.contact_list
+            .as_ref()
+            .map(|list| {
+                ListManagementOptions::builder()
+                    .contact_list_name(list.as_str())
+                    .set_topic_name(self.contact_list_topic.as_ref().map(ToString::to_string))
+                    .build()
+            })
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    async fn send(&self, request: &EmailRequest) -> Result<(), Error> {
+        let (destination, content) = Self::content(request)?;
+        let tags = request
+            .tags()
+            .into_iter()
+            .map(|(name, value)| MessageTag::builder().name(name).value(value).build())
+            .collect::<Result<Vec<_>, _>>()?;
+        self.sesv2_client
+            .send_email()
+            .from_email_address(request.from.to_string())
+            .destination(destination)
+            .set_reply_to_addresses(addresses(&request.reply_to))
+            .content(content)
+            .set_email_tags(Some(tags).filter(|tags| !tags.is_empty()))
+            .set_configuration_set_name(self.configuration_set.as_ref().map(ToString::to_string))
+            .set_list_management_options(self.list_management()?)
+            .send()
+            .await
+            .map_err(|e| format_err!("SESv2 SendEmail failed: {}", DisplayErrorContext(e)))?;
+        Ok(())
+    }
+
+    /// Send `request` as simple content, or raw MIME when it has attachments,
+    /// inline images or custom headers, tagged with its token and template
+    /// # Errors
+    /// Return error if the request is invalid, the api call fails, the SES
+    /// circuit is open or the daily quota is exhausted
+    pub async fn send_email(&self, request: &EmailRequest) -> Result<(), Error> {
+        request.validate()?;
+        self.ses.wait_for_quota(request.recipient_count()).await?;
+        let admission = CIRCUITS.ses.check()?;
+        let result = self.send(request).await;
+        CIRCUITS.ses.record(admission, &result);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+    use serde_json::Value;
+    use std::sync::{Arc, Mutex};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::{
+        config::{Config, ConfigInner},
+        email::EmailRequest,
+        mime_message::Attachment,
+        ses_client::SesInstance,
+        sesv2_client::SesV2Instance,
+    };
+
+    type Requests = Arc<Mutex<Vec<(String, String, Value)>>>;
+
+    async fn handle_connection(stream: TcpStream, requests: Requests) -> Result<(), Error> {
+        let mut stream = BufReader::new(stream);
+        loop {
+            let mut request_line = String::new();
+            if stream.read_line(&mut request_line).await? == 0 {
+                return Ok(());
+            }
+            let mut content_length = 0;
+            let mut authorization = String::new();
+            let mut line = String::new();
+            loop {
+                line.clear();
+                stream.read_line(&mut line).await?;
+                let header = line.trim_end();
+                if header.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse()?;
+                    } else if name.eq_ignore_ascii_case("authorization") {
+                        authorization = value.trim().into();
+                    }
+                }
+            }
+            let mut body = vec![0; content_length];
+            stream.read_exact(&mut body).await?;
+            let body: Value = serde_json::from_slice(&body)?;
+            let rejected = body["FromEmailAddress"]
+                .as_str()
+                .is_some_and(|from| from.contains("unverified"));
+            requests
+                .lock()
+                .unwrap()
+                .push((request_line.trim_end().into(), authorization, body));
+            let (status, error_type, response) = if rejected {
+                (
+                    "400 Bad Request",
+                    "x-amzn-ErrorType: MessageRejected\r\n",
+                    r#"{"message": "Email address is not verified."}"#,
+                )
+            } else {
+                ("200 OK", "", r#"{"MessageId": "test-message"}"#)
+            };
+            let head = format!(
+                "HTTP/1.1 {status}\r\nContent-Type: application/json\r\n{error_type}Content-Length: \
+                 {}\r\n\r\n",
+                response.len()
+            );
+            stream.get_mut().write_all(head.as_bytes()).await?;
+            stream.get_mut().write_all(response.as_bytes()).await?;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fake_sesv2() -> Result<(), Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let endpoint = format!("http://{}", listener.local_addr()?);
+        let requests = Requests::default();
+        let server_requests = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handle_connection(stream, server_requests.clone()));
+            }
+        });
+
+        let config: Config = ConfigInner {
+            ses_region: Some("us-west-2".into()),
+            ses_endpoint_url: Some(endpoint.parse()?),
+            ..ConfigInner::default()
+        }
+        .into();
+        let sdk_config = SesInstance::loader(&config).test_credentials().load().await;
+        let sesv2 = SesV2Instance::new(&sdk_config)
+            .with_configuration_set(Some("tracked".into()))
+            .with_contact_list(Some("newsletter".into()), Some("weekly".into()));
+
+        let request = EmailRequest {
+            from: "Bot <bot@example.com>".parse()?,
+            to: vec!["a@example.com".parse()?],
+            bcc: vec!["hidden@example.com".parse()?],
+            subject: "Disk usage".into(),
+            text: Some("95%".into()),
+            token: Some("monitoring".into()),
+            template: Some("disk.usage".into()),
+            ..EmailRequest::default()
+        };
+        sesv2.send_email(&request).await?;
+        let raw_request = EmailRequest {
+            attachments: vec![Attachment::new("usage.csv", b"host,usage".to_vec())],
+            token: None,
+            template: None,
+            ..request.clone()
+        };
+        sesv2.send_email(&raw_request).await?;
+        let rejected = EmailRequest {
+            from: "unverified@example.com".parse()?,
+            ..request.clone()
+        };
+        let error = sesv2.send_email(&rejected).await.unwrap_err().to_string();
+        assert!(error.contains("Email address is not verified"), "{}", error);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        let (request_line, authorization, body) = &requests[0];
+        assert!(
+            request_line.starts_with("POST /v2/email/outbound-emails "),
+            "{}",
+            request_line
+        );
+        assert!(
+            authorization.starts_with("AWS4-HMAC-SHA256 ")
+                && authorization.contains("/us-west-2/ses/aws4_request"),
+            "{}",
+            authorization
+        );
+        assert_eq!(body["ConfigurationSetName"], "tracked");
+        assert_eq!(
+            body["ListManagementOptions"],
+            serde_json::json!({"ContactListName": "newsletter", "TopicName": "weekly"})
+        );
+        assert_eq!(
+            body["EmailTags"],
+            serde_json::json!([
+                {"Name": "token", "Value": "monitoring"},
+                {"Name": "template", "Value": "disk_usage"},
+            ])
+        );
+        assert_eq!(body["Destination"]["BccAddresses"][0], "hidden@example.com");
+        assert_eq!(body["Content"]["Simple"]["Body"]["Text"]["Data"], "95%");
+
+        let (_, _, body) = &requests[1];
+        assert!(body.get("EmailTags").is_none());
+        assert!(body["Destination"].get("ToAddresses").is_none());
+        assert_eq!(
+            body["Destination"]["BccAddresses"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+        let raw = body["Content"]["Raw"]["Data"].as_str().unwrap();
+        let raw = String::from_utf8(base64::Engine::decode(
+            &base64::engine::general_purpose::STANDARD,
+            raw,
+        )?)?;
+        assert!(raw.contains("usage.csv"));
+        assert!(!raw.contains("hidden@example.com"));
+        Ok(())
+    }
+}
@@ -0,0 +1,467 @@
+use anyhow::{format_err, Error};
+use lettre::{
+    address::Envelope,
+    transport::smtp::{
+        authentication::Credentials,
+        client::{Certificate, Tls, TlsParameters},
+        extension::ClientId,
+    },
+    Address, AsyncSmtpTransport, AsyncTransport, Tokio1Executor,
+};
+use stack_string::StackString;
+use std::{fmt, fs, path::Path, str::FromStr, sync::Arc, time::Duration};
+
+use crate::{circuit_breaker::CIRCUITS, config::Config, email::EmailRequest};
+
+/// Limit for connecting and for each reply from the server
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How the connection to the SMTP relay is secured
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmtpSecurity {
+    /// Plain text, only for relays on a trusted network
+    None,
+    /// Upgrade a plain connection with `STARTTLS`, usually on port 587
+    StartTls,
+    /// TLS from the start, usually on port 465
+    Tls,
+}
+
+impl SmtpSecurity {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::StartTls => "starttls",
+            Self::Tls => "tls",
+        }
+    }
+
+    #[must_use]
+    pub fn default_port(self) -> u16 {
+        match self {
+            Self::None => 25,
+            Self::StartTls => 587,
+            Self::Tls => 465,
+        }
+    }
+}
+
+impl FromStr for SmtpSecurity {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "starttls" => Ok(Self::StartTls),
+            "tls" => Ok(Self::Tls),
+            _ => Err(format_err!(
+                "Invalid smtp security {s}, expected none, starttls or tls"
+            )),
+        }
+    }
+}
+
+/// Sends email through an SMTP relay
+#[derive(Clone)]
+pub struct SmtpInstance {
+    host: StackString,
+    port: u16,
+    security: SmtpSecurity,
+    credentials: Option<(StackString, StackString)>,
+    root_certificates: Vec<Arc<[u8]>>,
+    hello_name: StackString,
+}
+
+impl fmt::Debug for SmtpInstance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "SmtpInstance({}:{} {})",
+            self.host,
+            self.port,
+            self.security.as_str()
+        )
+    }
+}
+
+impl SmtpInstance {
+    /// A relay at `host:port`, trusting the public web PKI roots
+    #[must_use]
+    pub fn new(host: impl Into<StackString>, port: u16, security: SmtpSecurity) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            security,
+            credentials: None,
+            root_certificates: Vec::new(),
+            hello_name: "localhost".into(),
+        }
+    }
+
+    /// # Errors
+    /// Return error if `SMTP_HOST` is not set, `SMTP_SECURITY` is invalid,
+    /// credentials are set without TLS or `SMTP_CA_PATH` cannot be loaded
+    pub fn from_config(config: &Config) -> Result<Self, Error> {
+        let host = config
+            .smtp_host
+            .as_ref()
+            .ok_or_else(|| format_err!("SMTP_HOST is not set"))?;
+        let security: SmtpSecurity = config
+            .smtp_security
+            .as_ref()
+            .map_or("starttls", StackString::as_str)
+            .parse()?;
+        let port = config.smtp_port.unwrap_or_else(|| security.default_port());
+        let mut smtp = Self::new(host.clone(), port, security);
+        if let Some(username) = &config.smtp_username {
+            let password = config
+                .smtp_password
+                .as_ref()
+                .ok_or_else(|| format_err!("SMTP_USERNAME is set without SMTP_PASSWORD"))?;
+            smtp = smtp.with_credentials(username.clone(), password.clone());
+        }
+        if let Some(path) = &config.smtp_ca_path {
+            smtp = smtp.with_root_certificates(path)?;
+        }
+        smtp.check_credentials()?;
+        Ok(smtp)
+    }
+
+    #[must_use]
+    pub fn with_credentials(
+        mut self,
+        username: impl Into<StackString>,
+        password: impl Into<StackString>,
+    ) -> Self {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+
+    /// Also trust the certificates in the pem file at `path`, for relays with
+    /// a private CA
+    /// # Errors
+    /// Return error if the file cannot be read or parsed
+    pub fn with_root_certificates(mut self, path: &Path) -> Result<Self, Error> {
+        let pem = fs::read(path).map_err(|e| format_err!("{}: {e}", path.display()))?;
+        Certificate::from_pem(&pem).map_err(|e| format_err!("{}: {e}", path.display()))?;
+        self.root_certificates.push(pem.into());
+        Ok(self)
+    }
+
+    /// Credentials are never sent over a plain text connection
+    fn check_credentials(&self) -> Result<(), Error> {
+        if self.credentials.is_some() && self.security == SmtpSecurity::None {
+            Err(format_err!(
+                "SMTP credentials require SMTP_SECURITY starttls or tls"
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn tls_parameters(&self) -> Result<TlsParameters, Error> {
+        let mut builder = TlsParameters::builder(self.host.to_string());
+        for pem in &self.root_certificates {
+            builder = builder.add_root_certificate(Certificate::from_pem(pem)?);
+        }
+        builder.build_rustls().map_err(Into::into)
+    }
+
+    fn transport(&self) -> Result<AsyncSmtpTransport<Tokio1Executor>, Error> {
+        let tls = match self.security {
+            SmtpSecurity::None => Tls::None,
+            SmtpSecurity::StartTls => Tls::Required(self.tls_parameters()?),
+            SmtpSecurity::Tls => Tls::Wrapper(self.tls_parameters()?),
+        };
+        let mut builder =
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(self.host.as_str())
+                .port(self.port)
+                .tls(tls)
+                .hello_name(ClientId::Domain(self.hello_name.to_string()))
+                .timeout(Some(SMTP_TIMEOUT));
+        if let Some((username, password)) = &self.credentials {
+            builder =
+                builder.credentials(Credentials::new(username.to_string(), password.to_string()));
+        }
+        Ok(builder.build())
+    }
+
+    async fn send(&self, request: &EmailRequest, message: &[u8]) -> Result<(), Error> {
+        let from: Address = request.from.address.parse()?;
+        let recipients = request
+            .to
+            .iter()
+            .chain(&request.cc)
+            .chain(&request.bcc)
+            .map(|recipient| recipient.address.parse())
+            .collect::<Result<Vec<Address>, _>>()?;
+        let envelope = Envelope::new(Some(from), recipients)?;
+        self.transport()?.send_raw(&envelope, message).await?;
+        Ok(())
+    }
+
+    /// Send `request` as a MIME message, bcc recipients are only part of the
+    /// envelope
+    /// # Errors
+    /// Return error if the request is invalid, credentials are set without
+    /// TLS, the relay rejects the message or the SMTP circuit is open
+    pub async fn send_email(&self, request: &EmailRequest) -> Result<(), Error> {
+        request.validate()?;
+        self.check_credentials()?;
+        let message = request.to_mime()?;
+        let admission = CIRCUITS.smtp.check()?;
+        let result = self.send(request, &message).await;
+        CIRCUITS.smtp.record(admission, &result);
+        result
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use anyhow::{format_err, Error};
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use stack_string::StackString;
+    use std::{path::PathBuf, sync::Arc};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+        net::TcpListener,
+        sync::mpsc,
+        task::JoinHandle,
+    };
+    use tokio_rustls::{
+        rustls::{
+            crypto::ring::default_provider,
+            pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+            ServerConfig,
+        },
+        TlsAcceptor,
+    };
+
+    use crate::{
+        config::{Config, ConfigInner},
+        email::EmailRequest,
+        smtp_client::{SmtpInstance, SmtpSecurity},
+    };
+
+    fn test_data() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../tests/data")
+    }
+
+    fn acceptor() -> Result<TlsAcceptor, Error> {
+        let data = test_data();
+        let certs = CertificateDer::pem_file_iter(data.join("test_tls_cert.pem"))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let key = PrivateKeyDer::from_pem_file(data.join("test_tls_key.pem"))?;
+        let config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    /// What the stand-in server saw: commands, then the message body
+    #[derive(Debug, Default)]
+    pub(crate) struct Session {
+        pub commands: Vec<StackString>,
+        pub message: StackString,
+    }
+
+    enum Step {
+        Quit,
+        StartTls,
+    }
+
+    async fn serve<S>(
+        stream: S,
+        tls: bool,
+        offer_tls: bool,
+        session: &mut Session,
+    ) -> Result<(Step, S), Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut stream = BufReader::new(stream);
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await? == 0 {
+                return Err(format_err!("client disconnected"));
+            }
+            let command = line.trim_end();
+            session.commands.push(command.into());
+            let verb = command
+                .split([' ', ':'])
+                .next()
+                .unwrap_or("")
+                .to_ascii_uppercase();
+            let reply = match verb.as_str() {
+                "EHLO" if offer_tls && !tls => "250-test\r\n250 STARTTLS\r\n",
+                "EHLO" => "250-test\r\n250 AUTH PLAIN LOGIN\r\n",
+                "STARTTLS" => {
+                    stream.get_mut().write_all(b"220 ready\r\n").await?;
+                    return Ok((Step::StartTls, stream.into_inner()));
+                }
+                "AUTH" if command.starts_with("AUTH PLAIN ") => {
+                    let token = STANDARD.decode(&command[11..])?;
+                    if token == b"\0user\0secret" {
+                        "235 ok\r\n"
+                    } else {
+                        "535 bad credentials\r\n"
+                    }
+                }
+                "MAIL" | "RCPT" => "250 ok\r\n",
+                "DATA" => {
+                    stream.get_mut().write_all(b"354 go ahead\r\n").await?;
+                    let mut message = String::new();
+                    loop {
+                        let mut line = String::new();
+                        stream.read_line(&mut line).await?;
+                        if line == ".\r\n" || line.is_empty() {
+                            break;
+                        }
+                        message.push_str(&line);
+                    }
+                    session.message = message.into();
+                    "250 queued\r\n"
+                }
+                "QUIT" => {
+                    stream.get_mut().write_all(b"221 bye\r\n").await?;
+                    return Ok((Step::Quit, stream.into_inner()));
+                }
+                _ => "500 unknown command\r\n",
+            };
+            stream.get_mut().write_all(reply.as_bytes()).await?;
+        }
+    }
+
+    /// Minimal in-process SMTP server handling one session in the given
+    /// security mode, returns its port and the recorded session
+    pub(crate) async fn smtp_stand_in(
+        security: SmtpSecurity,
+    ) -> Result<(u16, mpsc::Receiver<Session>, JoinHandle<Result<(), Error>>), Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let acceptor = acceptor()?;
+        let (send, recv) = mpsc::channel(1);
+        let task = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let mut session = Session::default();
+            if security == SmtpSecurity::Tls {
+                let mut stream = acceptor.accept(stream).await?;
+                stream.write_all(b"220 test ESMTP\r\n").await?;
+                serve(stream, true, false, &mut session).await?;
+            } else {
+                let mut stream = stream;
+                stream.write_all(b"220 test ESMTP\r\n").await?;
+                let offer_tls = security == SmtpSecurity::StartTls;
+                if let (Step::StartTls, stream) =
+                    serve(stream, false, offer_tls, &mut session).await?
+                {
+                    let stream = acceptor.accept(stream).await?;
+                    serve(stream, true, false, &mut session).await?;
+                }
+            }
+            send.send(session).await?;
+            Ok(())
+        });
+        Ok((port, recv, task))
+    }
+
+    fn request() -> Result<EmailRequest, Error> {
+        Ok(EmailRequest {
+            from: "Bot <bot@example.com>".parse()?,
+            to: vec!["a@example.com".parse()?],
+            bcc: vec!["hidden@example.com".parse()?],
+            subject: "Disk usage".into(),
+            text: Some("Disk usage at 95%".into()),
+            ..EmailRequest::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_smtp_starttls() -> Result<(), Error> {
+        let (port, mut sessions, task) = smtp_stand_in(SmtpSecurity::StartTls).await?;
+        let smtp = SmtpInstance::new("localhost", port, SmtpSecurity::StartTls)
+            .with_credentials("user", "secret")
+            .with_root_certificates(&test_data().join("test_tls_ca.pem"))?;
+        smtp.send_email(&request()?).await?;
+        task.await??;
+        let session = sessions.recv().await.unwrap();
+        let commands: Vec<_> = session.commands.iter().map(StackString::as_str).collect();
+        assert_eq!(
+            commands,
+            [
+                "EHLO localhost",
+                "STARTTLS",
+                "EHLO localhost",
+                "AUTH PLAIN AHVzZXIAc2VjcmV0",
+                "MAIL FROM:<bot@example.com>",
+                "RCPT TO:<a@example.com>",
+                "RCPT TO:<hidden@example.com>",
+                "DATA",
+                "QUIT"
+            ]
+        );
+        assert!(session.message.contains("Subject: Disk usage\r\n"));
+        assert!(!session.message.contains("hidden@example.com"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_smtp_tls_and_plain() -> Result<(), Error> {
+        let (port, mut sessions, task) = smtp_stand_in(SmtpSecurity::Tls).await?;
+        let smtp = SmtpInstance::new("localhost", port, SmtpSecurity::Tls)
+            .with_root_certificates(&test_data().join("test_tls_ca.pem"))?;
+        smtp.send_email(&request()?).await?;
+        task.await??;
+        let session = sessions.recv().await.unwrap();
+        assert_eq!(session.commands[0], "EHLO localhost");
+        assert!(!session.commands.iter().any(|c| c.starts_with("AUTH")));
+
+        let (port, mut sessions, task) = smtp_stand_in(SmtpSecurity::None).await?;
+        let smtp = SmtpInstance::new("127.0.0.1", port, SmtpSecurity::None);
+        let request = EmailRequest {
+            attachments: vec![crate::mime_message::Attachment::new(
+                "notes.txt",
+                b"notes".to_vec(),
+            )],
+            ..request()?
+        };
+        smtp.send_email(&request).await?;
+        task.await??;
+        let session = sessions.recv().await.unwrap();
+        assert!(session.message.contains("filename=\"notes.txt\""));
+
+        // STARTTLS required but the untrusted certificate is rejected
+        let (port, _sessions, _task) = smtp_stand_in(SmtpSecurity::StartTls).await?;
+        let smtp = SmtpInstance::new("localhost", port, SmtpSecurity::StartTls);
+        assert!(smtp.send_email(&request).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_smtp_auth_requires_tls() -> Result<(), Error> {
+        let smtp = SmtpInstance::new("127.0.0.1", 25, SmtpSecurity::None)
+            .with_credentials("user", "secret");
+        assert!(smtp.send_email(&request()?).await.is_err());
+
+        let config: Config = ConfigInner {
+            smtp_host: Some("127.0.0.1".into()),
+            smtp_security: Some("none".into()),
+            smtp_username: Some("user".into()),
+            smtp_password: Some("secret".into()),
+            ..ConfigInner::default()
+        }
+        .into();
+        assert!(SmtpInstance::from_config(&config).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_smtp_security() -> Result<(), Error> {
+        assert_eq!("STARTTLS".parse::<SmtpSecurity>()?, SmtpSecurity::StartTls);
+        assert_eq!(SmtpSecurity::Tls.default_port(), 465);
+        assert!("ssl".parse::<SmtpSecurity>().is_err());
+        Ok(())
+    }
+}
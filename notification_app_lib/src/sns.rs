@@ -0,0 +1,361 @@
+use anyhow::{format_err, Error};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::signature::{
+    UnparsedPublicKey, VerificationAlgorithm, RSA_PKCS1_2048_8192_SHA1_FOR_LEGACY_USE_ONLY,
+    RSA_PKCS1_2048_8192_SHA256,
+};
+use serde::Deserialize;
+use stack_string::StackString;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+use url::Url;
+use x509_parser::pem::parse_x509_pem;
+
+use crate::config::Config;
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SnsMessageType {
+    SubscriptionConfirmation,
+    Notification,
+    UnsubscribeConfirmation,
+}
+
+/// The JSON body SNS posts to an HTTP(S) subscription
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct SnsMessage {
+    #[serde(rename = "Type")]
+    pub message_type: SnsMessageType,
+    pub message_id: StackString,
+    pub token: Option<StackString>,
+    pub topic_arn: StackString,
+    pub subject: Option<StackString>,
+    pub message: StackString,
+    pub timestamp: StackString,
+    pub signature_version: StackString,
+    pub signature: StackString,
+    #[serde(rename = "SigningCertURL")]
+    pub signing_cert_url: StackString,
+    #[serde(rename = "SubscribeURL")]
+    pub subscribe_url: Option<StackString>,
+}
+
+impl SnsMessage {
+    /// # Errors
+    /// Return error if `body` is not an SNS message
+    pub fn from_json(body: &[u8]) -> Result<Self, Error> {
+        serde_json::from_slice(body).map_err(|e| format_err!("Invalid SNS message: {e}"))
+    }
+
+    /// The canonical text SNS signs, `Key\nvalue\n` for each signed field
+    /// in a fixed order
+    /// # Errors
+    /// Return error if a field required for the message type is missing
+    pub fn string_to_sign(&self) -> Result<String, Error> {
+        let missing = |key| format_err!("SNS message has no {key}");
+        let mut fields = vec![("Message", self.message.as_str())];
+        fields.push(("MessageId", &self.message_id));
+        match self.message_type {
+            SnsMessageType::Notification => {
+                if let Some(subject) = &self.subject {
+                    fields.push(("Subject", subject));
+                }
+                fields.push(("Timestamp", &self.timestamp));
+                fields.push(("TopicArn", &self.topic_arn));
+                fields.push(("Type", "Notification"));
+            }
+            SnsMessageType::SubscriptionConfirmation | SnsMessageType::UnsubscribeConfirmation => {
+                let subscribe_url = self
+                    .subscribe_url
+                    .as_ref()
+                    .ok_or_else(|| missing("SubscribeURL"))?;
+                let token = self.token.as_ref().ok_or_else(|| missing("Token"))?;
+                fields.push(("SubscribeURL", subscribe_url));
+                fields.push(("Timestamp", &self.timestamp));
+                fields.push(("Token", token));
+                fields.push(("TopicArn", &self.topic_arn));
+                fields.push((
+                    "Type",
+                    if self.message_type == SnsMessageType::SubscriptionConfirmation {
+                        "SubscriptionConfirmation"
+                    } else {
+                        "UnsubscribeConfirmation"
+                    },
+                ));
+            }
+        }
+        Ok(fields
+            .into_iter()
+            .map(|(key, value)| format!("{key}\n{value}\n"))
+            .collect())
+    }
+
+    /// Check the signature against the PEM signing certificate
+    /// # Errors
+    /// Return error if the certificate is invalid or expired, the signature
+    /// version is unknown or the signature does not match
+    pub fn verify_signature(&self, certificate: &[u8]) -> Result<(), Error> {
+        let (_, pem) = parse_x509_pem(certificate)
+            .map_err(|e| format_err!("Invalid SNS signing certificate: {e}"))?;
+        let certificate = pem
+            .parse_x509()
+            .map_err(|e| format_err!("Invalid SNS signing certificate: {e}"))?;
+        if !certificate.validity().is_valid() {
+            return Err(format_err!("SNS signing certificate has expired"));
+        }
+        let algorithm: &'static dyn VerificationAlgorithm = match self.signature_version.as_str() {
+            "1" => &RSA_PKCS1_2048_8192_SHA1_FOR_LEGACY_USE_ONLY,
+            "2" => &RSA_PKCS1_2048_8192_SHA256,
+            version => {
+                return Err(format_err!("Unknown SNS signature version {version}"));
+            }
+        };
+        let signature = STANDARD.decode(self.signature.as_bytes())?;
+        let key = &certificate.public_key().subject_public_key.data;
+        UnparsedPublicKey::new(algorithm, key)
+            .verify(self.string_to_sign()?.as_bytes(), &signature)
+            .map_err(|_| format_err!("Invalid SNS signature"))
+    }
+}
+
+/// Whether `url` is an https url of the SNS service, the only place signing
+/// certificates and subscription confirmations may come from
+#[must_use]
+pub fn is_sns_url(url: &str) -> bool {
+    let Ok(url) = Url::parse(url) else {
+        return false;
+    };
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    let region = host
+        .strip_suffix(".amazonaws.com")
+        .or_else(|| host.strip_suffix(".amazonaws.com.cn"))
+        .and_then(|h| h.strip_prefix("sns."));
+    url.scheme() == "https"
+        && region.is_some_and(|r| {
+            !r.is_empty() && r.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Verifies messages posted by SNS, caching signing certificates by url
+pub struct SnsVerifier {
+    topic_arns: HashSet<StackString>,
+    client: reqwest::Client,
+    certificates: Mutex<HashMap<StackString, Arc<Vec<u8>>>>,
+}
+
+impl SnsVerifier {
+    #[must_use]
+    pub fn new(topic_arns: HashSet<StackString>) -> Self {
+        Self {
+            topic_arns,
+            client: reqwest::Client::new(),
+            certificates: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Accept messages from the topics in `SNS_TOPIC_ARNS`
+    #[must_use]
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(config.sns_topic_arns())
+    }
+
+    /// Use `certificate` for `url` instead of downloading it
+    pub fn add_certificate(&self, url: &str, certificate: Vec<u8>) {
+        self.certificates
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .insert(url.into(), Arc::new(certificate));
+    }
+
+    async fn certificate(&self, url: &str) -> Result<Arc<Vec<u8>>, Error> {
+        if let Some(certificate) = self
+            .certificates
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .get(url)
+        {
+            return Ok(certificate.clone());
+        }
+        if !is_sns_url(url) || !url.ends_with(".pem") {
+            return Err(format_err!("Untrusted SNS signing certificate url {url}"));
+        }
+        let certificate = self
+            .client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?
+            .to_vec();
+        self.add_certificate(url, certificate.clone());
+        Ok(Arc::new(certificate))
+    }
+
+    /// # Errors
+    /// Return error if the message comes from a topic not in
+    /// `SNS_TOPIC_ARNS` or its signature cannot be verified
+    pub async fn verify(&self, message: &SnsMessage) -> Result<(), Error> {
+        if !self.topic_arns.contains(&message.topic_arn) {
+            return Err(format_err!("Unexpected SNS topic {}", message.topic_arn));
+        }
+        let certificate = self.certificate(&message.signing_cert_url).await?;
+        message.verify_signature(&certificate)
+    }
+
+    /// Confirm a subscription by visiting its `SubscribeURL`
+    /// # Errors
+    /// Return error if the url is not an SNS url or the request fails
+    pub async fn confirm_subscription(&self, message: &SnsMessage) -> Result<(), Error> {
+        let url = message
+            .subscribe_url
+            .as_ref()
+            .filter(|url| is_sns_url(url))
+            .ok_or_else(|| {
+                format_err!(
+                    "Untrusted SubscribeURL {}",
+                    message
+                        .subscribe_url
+                        .as_ref()
+                        .map_or("", StackString::as_str)
+                )
+            })?;
+        self.client
+            .get(url.as_str())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Topic arns are comma separated
+#[must_use]
+pub fn parse_topic_arns(value: &str) -> HashSet<StackString> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|arn| !arn.is_empty())
+        .map(Into::into)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use ring::{
+        rand::SystemRandom,
+        signature::{RsaKeyPair, RSA_PKCS1_SHA256},
+    };
+
+    use crate::sns::{is_sns_url, parse_topic_arns, SnsMessage, SnsMessageType, SnsVerifier};
+
+    const CERT_URL: &str = "https://sns.us-east-1.amazonaws.com/SimpleNotificationService-1.pem";
+    const TOPIC: &str = "arn:aws:sns:us-east-1:123456789012:ses-feedback";
+
+    fn pem_der(pem: &str) -> Result<Vec<u8>, Error> {
+        let body: String = pem.lines().filter(|l| !l.starts_with("-----")).collect();
+        STANDARD.decode(body).map_err(Into::into)
+    }
+
+    /// Sign `message` with the test key whose certificate is
+    /// `tests/data/test_tls_cert.pem`
+    fn sign(message: &mut SnsMessage) -> Result<(), Error> {
+        let key = pem_der(include_str!("../../tests/data/test_tls_key.pem"))?;
+        let key = RsaKeyPair::from_pkcs8(&key).map_err(|e| anyhow::format_err!("{e}"))?;
+        let mut signature = vec![0; key.public().modulus_len()];
+        key.sign(
+            &RSA_PKCS1_SHA256,
+            &SystemRandom::new(),
+            message.string_to_sign()?.as_bytes(),
+            &mut signature,
+        )
+        .map_err(|e| anyhow::format_err!("{e}"))?;
+        message.signature_version = "2".into();
+        message.signature = STANDARD.encode(signature).into();
+        Ok(())
+    }
+
+    fn notification() -> Result<SnsMessage, Error> {
+        SnsMessage::from_json(
+            format!(
+                r#"{{"Type": "Notification", "MessageId": "22b80b92",
+                "TopicArn": "{TOPIC}", "Message": "{{\"notificationType\":\"Bounce\"}}",
+                "Timestamp": "2024-01-01T00:00:00.000Z", "SignatureVersion": "1",
+                "Signature": "", "SigningCertURL": "{CERT_URL}"}}"#
+            )
+            .as_bytes(),
+        )
+    }
+
+    #[test]
+    fn test_string_to_sign() -> Result<(), Error> {
+        let message = notification()?;
+        assert_eq!(message.message_type, SnsMessageType::Notification);
+        assert_eq!(
+            message.string_to_sign()?,
+            format!(
+                "Message\n{{\"notificationType\":\"Bounce\"}}\nMessageId\n22b80b92\n\
+                 Timestamp\n2024-01-01T00:00:00.000Z\nTopicArn\n{TOPIC}\nType\nNotification\n"
+            )
+        );
+        let confirmation = SnsMessage {
+            message_type: SnsMessageType::SubscriptionConfirmation,
+            ..message
+        };
+        assert!(confirmation.string_to_sign().is_err());
+        let confirmation = SnsMessage {
+            token: Some("t0k".into()),
+            subscribe_url: Some("https://sns.us-east-1.amazonaws.com/?Action=Confirm".into()),
+            ..confirmation
+        };
+        assert!(confirmation
+            .string_to_sign()?
+            .contains("SubscribeURL\nhttps://sns.us-east-1.amazonaws.com/?Action=Confirm\n"));
+
+        assert!(is_sns_url(CERT_URL));
+        assert!(is_sns_url("https://sns.cn-north-1.amazonaws.com.cn/x.pem"));
+        assert!(!is_sns_url("http://sns.us-east-1.amazonaws.com/x.pem"));
+        assert!(!is_sns_url(
+            "https://sns.us-east-1.amazonaws.com.evil.com/x.pem"
+        ));
+        assert!(!is_sns_url("https://evil.com/sns.us-east-1.amazonaws.com"));
+        let arns = parse_topic_arns(" a, ,b");
+        assert_eq!(arns.len(), 2);
+        assert!(arns.contains("a") && arns.contains("b"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify() -> Result<(), Error> {
+        let verifier = SnsVerifier::new(parse_topic_arns(TOPIC));
+        verifier.add_certificate(
+            CERT_URL,
+            include_bytes!("../../tests/data/test_tls_cert.pem").to_vec(),
+        );
+        let mut message = notification()?;
+        sign(&mut message)?;
+        verifier.verify(&message).await?;
+
+        let mut tampered = message.clone();
+        tampered.message = "{\"notificationType\":\"Complaint\"}".into();
+        assert!(verifier.verify(&tampered).await.is_err());
+
+        let mut other_topic = message.clone();
+        other_topic.topic_arn = "arn:aws:sns:us-east-1:999999999999:other".into();
+        sign(&mut other_topic)?;
+        let error = verifier.verify(&other_topic).await.unwrap_err();
+        assert!(error.to_string().contains("Unexpected SNS topic"));
+
+        let mut untrusted = message;
+        untrusted.signing_cert_url = "https://example.com/cert.pem".into();
+        let error = verifier.verify(&untrusted).await.unwrap_err();
+        assert!(error.to_string().contains("Untrusted"), "{}", error);
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+use serde::{Deserialize, Serialize};
+use stack_string::{format_sstr, StackString};
+use std::fmt::Write;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+    Error,
+    Critical,
+}
+
+impl Severity {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Info => "INFO",
+            Self::Warning => "WARNING",
+            Self::Error => "ERROR",
+            Self::Critical => "CRITICAL",
+        }
+    }
+
+    #[must_use]
+    pub fn icon(self) -> &'static str {
+        match self {
+            Self::Info => "\u{2139}\u{fe0f}",
+            Self::Warning => "\u{26a0}\u{fe0f}",
+            Self::Error => "\u{274c}",
+            Self::Critical => "\u{1f6a8}",
+        }
+    }
+
+    #[must_use]
+    pub fn color(self) -> &'static str {
+        match self {
+            Self::Info => "#2b7bb9",
+            Self::Warning => "#e0a800",
+            Self::Error => "#d9534f",
+            Self::Critical => "#8b0000",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MessageField {
+    pub name: StackString,
+    pub value: StackString,
+}
+
+/// Alert with a fixed layout, rendered the same way for every sender
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct StructuredMessage {
+    pub title: StackString,
+    #[serde(default)]
+    pub severity: Severity,
+    #[serde(default)]
+    pub fields: Vec<MessageField>,
+    pub url: Option<StackString>,
+    pub footer: Option<StackString>,
+}
+
+#[must_use]
+pub fn escape_html(s: &str) -> StackString {
+    let mut output = StackString::new();
+    for c in s.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '"' => output.push_str("&quot;"),
+            c => output.push(c),
+        }
+    }
+    output
+}
+
+impl StructuredMessage {
+    /// Render using the subset of HTML supported by the Telegram bot api
+    #[must_use]
+    pub fn telegram_html(&self) -> StackString {
+        let mut output = format_sstr!(
+            "{icon} <b>[{severity}] {title}</b>\n",
+            icon = self.severity.icon(),
+            severity = self.severity.as_str(),
+            title = escape_html(&self.title),
+        );
+        for field in &self.fields {
+            writeln!(
+                output,
+                "<b>{}:</b> {}",
+                escape_html(&field.name),
+                escape_html(&field.value)
+            )
+            .ok();
+        }
+        if let Some(url) = &self.url {
+            let url = escape_html(url);
+            writeln!(output, "<a href=\"{url}\">{url}</a>").ok();
+        }
+        if let Some(footer) = &self.footer {
+            writeln!(output, "<i>{}</i>", escape_html(footer)).ok();
+        }
+        output.trim_end().into()
+    }
+
+    #[must_use]
+    pub fn email_html(&self) -> StackString {
+        let mut output = format_sstr!(
+            "<html><body>\n<h2 style=\"color: {color}\">[{severity}] {title}</h2>\n",
+            color = self.severity.color(),
+            severity = self.severity.as_str(),
+            title = escape_html(&self.title),
+        );
+        if !self.fields.is_empty() {
+            output.push_str("<table>\n");
+            for field in &self.fields {
+                writeln!(
+                    output,
+                    "<tr><th align=\"left\">{}</th><td>{}</td></tr>",
+                    escape_html(&field.name),
+                    escape_html(&field.value)
+                )
+                .ok();
+            }
+            output.push_str("</table>\n");
+        }
+        if let Some(url) = &self.url {
+            let url = escape_html(url);
+            writeln!(output, "<p><a href=\"{url}\">{url}</a></p>").ok();
+        }
+        if let Some(footer) = &self.footer {
+            writeln!(output, "<p><small>{}</small></p>", escape_html(footer)).ok();
+        }
+        output.push_str("</body></html>");
+        output
+    }
+
+    #[must_use]
+    pub fn text(&self) -> StackString {
+        let mut output = format_sstr!(
+            "[{severity}] {title}\n",
+            severity = self.severity.as_str(),
+            title = self.title,
+        );
+        for field in &self.fields {
+            writeln!(output, "{}: {}", field.name, field.value).ok();
+        }
+        if let Some(url) = &self.url {
+            writeln!(output, "{url}").ok();
+        }
+        if let Some(footer) = &self.footer {
+            writeln!(output, "\n{footer}").ok();
+        }
+        output.trim_end().into()
+    }
+
+    #[must_use]
+    pub fn subject(&self) -> StackString {
+        format_sstr!(
+            "[{severity}] {title}",
+            severity = self.severity.as_str(),
+            title = self.title
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::structured::{MessageField, Severity, StructuredMessage};
+
+    #[test]
+    fn test_structured_message() {
+        let message = StructuredMessage {
+            title: "Disk <full>".into(),
+            severity: Severity::Error,
+            fields: vec![MessageField {
+                name: "host".into(),
+                value: "db1 & db2".into(),
+            }],
+            url: Some("https://example.com/?a=1&b=2".into()),
+            footer: Some("cron".into()),
+        };
+        assert_eq!(
+            message.telegram_html(),
+            "\u{274c} <b>[ERROR] Disk &lt;full&gt;</b>\n<b>host:</b> db1 &amp; db2\n<a \
+             href=\"https://example.com/?a=1&amp;b=2\">https://example.com/?a=1&amp;b=2</a>\n<i>cron</i>"
+        );
+        assert_eq!(
+            message.text(),
+            "[ERROR] Disk <full>\nhost: db1 & db2\nhttps://example.com/?a=1&b=2\n\ncron"
+        );
+        let html = message.email_html();
+        assert!(html.contains("<th align=\"left\">host</th><td>db1 &amp; db2</td>"));
+        assert!(html.contains("<p><small>cron</small></p>"));
+        assert_eq!(message.subject(), "[ERROR] Disk <full>");
+
+        let message: StructuredMessage = serde_json::from_str(r#"{"title": "hello"}"#).unwrap();
+        assert_eq!(message.severity, Severity::Info);
+        assert!(message.fields.is_empty());
+    }
+}
@@ -0,0 +1,346 @@
+use anyhow::{format_err, Error};
+use serde::{Deserialize, Serialize};
+use stack_string::StackString;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+};
+use time::OffsetDateTime;
+use tokio::{fs, sync::Mutex};
+
+use crate::{
+    email::{EmailAddress, EmailRequest},
+    metrics,
+};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SuppressionReason {
+    Bounce,
+    Complaint,
+}
+
+impl SuppressionReason {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Bounce => "bounce",
+            Self::Complaint => "complaint",
+        }
+    }
+}
+
+impl fmt::Display for SuppressionReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An address that bounced permanently or complained, emails to it are
+/// not sent until the entry is cleared
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SuppressionEntry {
+    pub address: StackString,
+    pub reason: SuppressionReason,
+    /// Bounce sub type or complaint feedback type reported by SES
+    pub detail: Option<StackString>,
+    /// SES message id of the email that bounced or was complained about
+    pub message_id: Option<StackString>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created: OffsetDateTime,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Recipient {
+    email_address: StackString,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Bounce {
+    bounce_type: StackString,
+    bounce_sub_type: Option<StackString>,
+    #[serde(default)]
+    bounced_recipients: Vec<Recipient>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Complaint {
+    complaint_feedback_type: Option<StackString>,
+    #[serde(default)]
+    complained_recipients: Vec<Recipient>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct Mail {
+    message_id: Option<StackString>,
+}
+
+/// The `Message` of an SNS notification published by SES, either a
+/// notification (`notificationType`) or a configuration set event
+/// (`eventType`)
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SesNotification {
+    #[serde(alias = "eventType")]
+    notification_type: StackString,
+    bounce: Option<Bounce>,
+    complaint: Option<Complaint>,
+    #[serde(default)]
+    mail: Mail,
+}
+
+/// Normalized form used as the key of the suppression list
+fn normalize(address: &str) -> StackString {
+    let address = address
+        .parse::<EmailAddress>()
+        .map_or_else(|_| address.trim().into(), |a| a.address);
+    address.to_lowercase().into()
+}
+
+impl SesNotification {
+    /// # Errors
+    /// Return error if `message` is not an SES notification
+    pub fn from_json(message: &str) -> Result<Self, Error> {
+        serde_json::from_str(message).map_err(|e| format_err!("Invalid SES notification: {e}"))
+    }
+
+    /// Entries for permanently bounced and complaining recipients, transient
+    /// bounces and deliveries are only counted
+    #[must_use]
+    pub fn suppressions(&self, now: OffsetDateTime) -> Vec<SuppressionEntry> {
+        let entry =
+            |recipient: &Recipient, reason, detail: &Option<StackString>| SuppressionEntry {
+                address: normalize(&recipient.email_address),
+                reason,
+                detail: detail.clone(),
+                message_id: self.mail.message_id.clone(),
+                created: now,
+            };
+        match (
+            self.notification_type.as_str(),
+            &self.bounce,
+            &self.complaint,
+        ) {
+            ("Bounce", Some(bounce), _) => {
+                let kind = if bounce.bounce_type == "Permanent" {
+                    "bounce_permanent"
+                } else {
+                    "bounce_transient"
+                };
+                metrics::EMAIL_FEEDBACK
+                    .with_label_values(&[kind])
+                    .inc_by(bounce.bounced_recipients.len() as u64);
+                if bounce.bounce_type != "Permanent" {
+                    return Vec::new();
+                }
+                bounce
+                    .bounced_recipients
+                    .iter()
+                    .map(|r| entry(r, SuppressionReason::Bounce, &bounce.bounce_sub_type))
+                    .collect()
+            }
+            ("Complaint", _, Some(complaint)) => {
+                metrics::EMAIL_FEEDBACK
+                    .with_label_values(&["complaint"])
+                    .inc_by(complaint.complained_recipients.len() as u64);
+                complaint
+                    .complained_recipients
+                    .iter()
+                    .map(|r| {
+                        entry(
+                            r,
+                            SuppressionReason::Complaint,
+                            &complaint.complaint_feedback_type,
+                        )
+                    })
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Addresses that must not be emailed, keyed by lower case address and
+/// persisted to `path` after every change
+pub struct SuppressionList {
+    path: Option<PathBuf>,
+    entries: Mutex<BTreeMap<StackString, SuppressionEntry>>,
+}
+
+impl SuppressionList {
+    /// # Errors
+    /// Return error if an existing file cannot be read or parsed
+    pub async fn new(path: Option<&Path>) -> Result<Self, Error> {
+        let mut entries = BTreeMap::new();
+        if let Some(path) = path {
+            if path.exists() {
+                let data = fs::read(path).await?;
+                let stored: Vec<SuppressionEntry> = serde_json::from_slice(&data)?;
+                entries.extend(stored.into_iter().map(|e| (e.address.clone(), e)));
+            }
+        }
+        Ok(Self {
+            path: path.map(Into::into),
+            entries: Mutex::new(entries),
+        })
+    }
+
+    async fn persist(
+        &self,
+        entries: &BTreeMap<StackString, SuppressionEntry>,
+    ) -> Result<(), Error> {
+        if let Some(path) = &self.path {
+            let data = serde_json::to_vec_pretty(&entries.values().collect::<Vec<_>>())?;
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, &data).await?;
+            fs::rename(&tmp, path).await?;
+        }
+        Ok(())
+    }
+
+    /// Add or replace entries, the most recent event for an address wins
+    /// # Errors
+    /// Return error if persisting fails
+    pub async fn add(&self, new_entries: Vec<SuppressionEntry>) -> Result<(), Error> {
+        if new_entries.is_empty() {
+            return Ok(());
+        }
+        let mut entries = self.entries.lock().await;
+        for entry in new_entries {
+            entries.insert(entry.address.clone(), entry);
+        }
+        self.persist(&entries).await
+    }
+
+    pub async fn list(&self) -> Vec<SuppressionEntry> {
+        self.entries.lock().await.values().cloned().collect()
+    }
+
+    pub async fn is_suppressed(&self, address: &str) -> bool {
+        self.entries.lock().await.contains_key(&normalize(address))
+    }
+
+    /// Returns false if `address` was not suppressed
+    /// # Errors
+    /// Return error if persisting fails
+    pub async fn remove(&self, address: &str) -> Result<bool, Error> {
+        let mut entries = self.entries.lock().await;
+        if entries.remove(&normalize(address)).is_none() {
+            return Ok(false);
+        }
+        self.persist(&entries).await?;
+        Ok(true)
+    }
+
+    /// Remove every entry, returning how many there were
+    /// # Errors
+    /// Return error if persisting fails
+    pub async fn clear(&self) -> Result<usize, Error> {
+        let mut entries = self.entries.lock().await;
+        let count = entries.len();
+        entries.clear();
+        self.persist(&entries).await?;
+        Ok(count)
+    }
+
+    /// Drop suppressed addresses from the to, cc and bcc lists of `request`,
+    /// returning the dropped addresses
+    pub async fn filter(&self, request: &mut EmailRequest) -> Vec<EmailAddress> {
+        let entries = self.entries.lock().await;
+        let mut removed = Vec::new();
+        for list in [&mut request.to, &mut request.cc, &mut request.bcc] {
+            list.retain(|address| {
+                if entries.contains_key(&normalize(&address.address)) {
+                    removed.push(address.clone());
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+    use tempfile::TempDir;
+    use time::macros::datetime;
+
+    use crate::{
+        email::EmailRequest,
+        suppression::{SesNotification, SuppressionList, SuppressionReason},
+    };
+
+    const PERMANENT_BOUNCE: &str = r#"{
+        "notificationType": "Bounce",
+        "bounce": {
+            "bounceType": "Permanent",
+            "bounceSubType": "General",
+            "bouncedRecipients": [{"emailAddress": "Gone@Example.com"}]
+        },
+        "mail": {"messageId": "0100-abc"}
+    }"#;
+
+    #[tokio::test]
+    async fn test_suppression_list() -> Result<(), Error> {
+        let now = datetime!(2024-01-01 00:00 UTC);
+        let bounce = SesNotification::from_json(PERMANENT_BOUNCE)?.suppressions(now);
+        assert_eq!(bounce.len(), 1);
+        assert_eq!(bounce[0].address, "gone@example.com");
+        assert_eq!(bounce[0].detail.as_ref().unwrap(), "General");
+        assert_eq!(bounce[0].message_id.as_ref().unwrap(), "0100-abc");
+
+        let transient = PERMANENT_BOUNCE.replace("Permanent", "Transient");
+        assert!(SesNotification::from_json(&transient)?
+            .suppressions(now)
+            .is_empty());
+        let complaint = SesNotification::from_json(
+            r#"{"eventType": "Complaint", "complaint": {"complaintFeedbackType": "abuse",
+                "complainedRecipients": [{"emailAddress": "angry@example.com"}]}}"#,
+        )?
+        .suppressions(now);
+        assert_eq!(complaint[0].reason, SuppressionReason::Complaint);
+        assert!(
+            SesNotification::from_json(r#"{"notificationType": "Delivery"}"#)?
+                .suppressions(now)
+                .is_empty()
+        );
+        assert!(SesNotification::from_json("not json").is_err());
+
+        let dir = TempDir::new()?;
+        let path = dir.path().join("suppression_list.json");
+        let list = SuppressionList::new(Some(&path)).await?;
+        list.add(bounce).await?;
+        list.add(complaint).await?;
+        assert!(list.is_suppressed("GONE@example.com").await);
+
+        let mut request = EmailRequest {
+            to: vec!["gone@example.com".parse()?, "ok@example.com".parse()?],
+            bcc: vec!["Angry <angry@example.com>".parse()?],
+            ..EmailRequest::default()
+        };
+        let removed = list.filter(&mut request).await;
+        assert_eq!(removed.len(), 2);
+        assert_eq!(request.to, ["ok@example.com".parse()?]);
+        assert!(request.bcc.is_empty());
+
+        let reloaded = SuppressionList::new(Some(&path)).await?;
+        assert_eq!(reloaded.list().await.len(), 2);
+        assert!(reloaded.remove("gone@example.com").await?);
+        assert!(!reloaded.remove("gone@example.com").await?);
+        assert_eq!(reloaded.clear().await?, 1);
+        assert!(SuppressionList::new(Some(&path))
+            .await?
+            .list()
+            .await
+            .is_empty());
+        Ok(())
+    }
+}
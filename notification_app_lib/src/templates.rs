@@ -0,0 +1,148 @@
+use anyhow::{format_err, Error};
+use serde_json::{Map, Value};
+use stack_string::{format_sstr, StackString};
+use std::{fmt, path::Path, sync::Arc};
+use tera::{Context, Tera};
+use tokio::fs;
+
+const TEMPLATE_EXTENSION: &str = "tera";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Channel {
+    Telegram,
+    Email,
+}
+
+impl Channel {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Telegram => "telegram",
+            Self::Email => "email",
+        }
+    }
+}
+
+/// Named templates loaded from `*.tera` files, a template `name` may be
+/// overridden for a single channel by a file named `name.telegram.tera` or
+/// `name.email.tera`
+#[derive(Clone, Default)]
+pub struct Templates(Arc<Tera>);
+
+impl fmt::Debug for Templates {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Templates")
+    }
+}
+
+impl Templates {
+    /// # Errors
+    /// Return error if reading the directory fails or any template fails to
+    /// parse
+    pub async fn new(directory: &Path) -> Result<Self, Error> {
+        let mut templates = Vec::new();
+        if directory.exists() {
+            let mut entries = fs::read_dir(directory).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if path.extension().and_then(|e| e.to_str()) != Some(TEMPLATE_EXTENSION) {
+                    continue;
+                }
+                let name = path
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .ok_or_else(|| format_err!("Invalid template name {path:?}"))?
+                    .to_string();
+                let data = fs::read_to_string(&path).await?;
+                templates.push((name, data));
+            }
+        }
+        Self::from_raw(templates)
+    }
+
+    /// # Errors
+    /// Return error if any template fails to parse
+    pub fn from_raw<I, N, T>(templates: I) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = (N, T)>,
+        N: AsRef<str>,
+        T: AsRef<str>,
+    {
+        let mut tera = Tera::default();
+        tera.autoescape_on(Vec::new());
+        for (name, data) in templates {
+            let name = name.as_ref();
+            tera.add_raw_template(name, data.as_ref())
+                .map_err(|e| format_err!("Template {name} is invalid: {e:?}"))?;
+        }
+        Ok(Self(Arc::new(tera)))
+    }
+
+    #[must_use]
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.get_template_names().any(|n| n == name)
+    }
+
+    /// # Errors
+    /// Return error if template does not exist or rendering fails
+    pub fn render(
+        &self,
+        name: &str,
+        channel: Channel,
+        vars: &Map<String, Value>,
+    ) -> Result<StackString, Error> {
+        let channel_name = format_sstr!("{name}.{c}", c = channel.as_str());
+        let template_name = if self.contains(&channel_name) {
+            channel_name.as_str()
+        } else if self.contains(name) {
+            name
+        } else {
+            return Err(format_err!("Template {name} not found"));
+        };
+        let context = Context::from_value(Value::Object(vars.clone()))?;
+        self.0
+            .render(template_name, &context)
+            .map(Into::into)
+            .map_err(|e| format_err!("Failed to render {template_name}: {e:?}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    use crate::templates::{Channel, Templates};
+
+    #[tokio::test]
+    async fn test_templates() -> Result<(), Error> {
+        let dir = TempDir::new()?;
+        tokio::fs::write(dir.path().join("alert.tera"), "{{ host }} is down").await?;
+        tokio::fs::write(
+            dir.path().join("alert.email.tera"),
+            "<b>{{ host }}</b> is down",
+        )
+        .await?;
+        tokio::fs::write(dir.path().join("README"), "{{ ignored").await?;
+        let templates = Templates::new(dir.path()).await?;
+
+        let vars = json!({"host": "example.com"});
+        let vars = vars.as_object().unwrap();
+        assert_eq!(
+            templates.render("alert", Channel::Telegram, vars)?,
+            "example.com is down"
+        );
+        assert_eq!(
+            templates.render("alert", Channel::Email, vars)?,
+            "<b>example.com</b> is down"
+        );
+        assert!(templates
+            .render("missing", Channel::Telegram, vars)
+            .is_err());
+
+        tokio::fs::write(dir.path().join("broken.tera"), "{{ host").await?;
+        assert!(Templates::new(dir.path()).await.is_err());
+        Ok(())
+    }
+}
@@ -1,37 +1,146 @@
 use anyhow::{format_err, Error};
 use clap::Parser;
+use serde_json::{Map, Value};
 use stack_string::{format_sstr, StackString};
+use std::path::PathBuf;
 
-use notification_app_lib::{config::Config, ses_client::SesInstance};
+use notification_app_lib::{
+    config::Config,
+    email::{EmailAddress, EmailRequest},
+    email_sender::EmailSender,
+    mime_message::{validate_header, Attachment},
+    suppression::SuppressionList,
+    templates::{Channel, Templates},
+};
 
 #[derive(Parser)]
 struct SendToEmailOpts {
+    /// Recipient, either `user@example.com` or `Name <user@example.com>`, may
+    /// be repeated
+    #[clap(short = 'e', long = "to", alias = "email", required = true)]
+    to: Vec<EmailAddress>,
+    #[clap(long)]
+    cc: Vec<EmailAddress>,
+    #[clap(long)]
+    bcc: Vec<EmailAddress>,
+    #[clap(long)]
+    reply_to: Vec<EmailAddress>,
+    /// Display name for the sending address, overrides `SENDING_EMAIL_NAME`
+    #[clap(long)]
+    from_name: Option<StackString>,
     #[clap(short, long)]
-    email: StackString,
+    subject: Option<StackString>,
+    #[clap(short, long, required_unless_present = "template")]
+    message: Option<StackString>,
+    /// Render the message from a named template
+    #[clap(short, long, conflicts_with = "message")]
+    template: Option<StackString>,
+    /// The message is html, the plain text part is generated from it
+    #[clap(long)]
+    html: bool,
+    /// Template variable as `name=value`, may be repeated
+    #[clap(short, long = "var", value_parser = parse_var)]
+    vars: Vec<(String, String)>,
+    /// File to attach, may be repeated
     #[clap(short, long)]
-    message: StackString,
+    attach: Vec<PathBuf>,
+    /// Image shown inline, referenced from the html as `cid:<file name>`,
+    /// may be repeated
+    #[clap(long)]
+    inline: Vec<PathBuf>,
+    /// Custom header as `Name: value`, may be repeated
+    #[clap(long = "header", value_parser = parse_header)]
+    headers: Vec<(StackString, StackString)>,
+}
+
+fn parse_var(s: &str) -> Result<(String, String), Error> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format_err!("Expected name=value, got {s}"))?;
+    Ok((key.into(), value.into()))
+}
+
+fn parse_header(s: &str) -> Result<(StackString, StackString), Error> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format_err!("Expected Name: value, got {s}"))?;
+    let (name, value) = (name.trim(), value.trim());
+    validate_header(name, value)?;
+    Ok((name.into(), value.into()))
+}
+
+async fn attachments(attach: &[PathBuf], inline: &[PathBuf]) -> Result<Vec<Attachment>, Error> {
+    let mut attachments = Vec::with_capacity(attach.len() + inline.len());
+    for path in attach {
+        attachments.push(Attachment::from_path(path).await?);
+    }
+    for path in inline {
+        let attachment = Attachment::from_path(path).await?;
+        let content_id = attachment.filename.clone();
+        attachments.push(attachment.inline(content_id));
+    }
+    Ok(attachments)
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     let opts = SendToEmailOpts::parse();
     let config = Config::init_config()?;
-    let sdk_config = aws_config::load_from_env().await;
+    let message = match (&opts.message, &opts.template) {
+        (Some(message), _) => message.clone(),
+        (None, Some(template)) => {
+            let templates_path = config
+                .templates_path
+                .as_ref()
+                .ok_or_else(|| format_err!("No templates path"))?;
+            let templates = Templates::new(templates_path).await?;
+            let vars: Map<String, Value> = opts
+                .vars
+                .iter()
+                .map(|(k, v)| (k.clone(), Value::String(v.clone())))
+                .collect();
+            templates.render(template, Channel::Email, &vars)?
+        }
+        (None, None) => return Err(format_err!("No message or template")),
+    };
+    let attachments = attachments(&opts.attach, &opts.inline).await?;
     tokio::spawn(async move {
-        let src_email = config
-            .sending_email_address
-            .as_ref()
-            .ok_or_else(|| format_err!("No sending email address"))?;
-        let ses = SesInstance::new(&sdk_config);
-        let sub = format_sstr!("Notification from {src_email}");
-
-        ses.send_email(
-            src_email.as_str(),
-            opts.email.as_str(),
-            &sub,
-            opts.message.as_str(),
-        )
-        .await
+        let mut from = config.sender()?;
+        if let Some(name) = opts.from_name {
+            from.name = Some(name);
+        }
+        let sender = EmailSender::from_config(&config).await?;
+        let subject = opts
+            .subject
+            .unwrap_or_else(|| format_sstr!("Notification from {}", from.address));
+        let (text, html) = if opts.html {
+            (None, Some(message))
+        } else {
+            (Some(message), None)
+        };
+        let mut request = EmailRequest {
+            from,
+            to: opts.to,
+            cc: opts.cc,
+            bcc: opts.bcc,
+            reply_to: opts.reply_to,
+            subject,
+            text,
+            html,
+            attachments,
+            headers: opts.headers,
+            token: None,
+            template: opts.template,
+            queued: None,
+        };
+        let suppression = SuppressionList::new(config.suppression_list_path.as_deref()).await?;
+        for address in suppression.filter(&mut request).await {
+            eprintln!("skipping suppressed address {}", address.address);
+        }
+        if request.recipient_count() == 0 {
+            return Err(format_err!("Every recipient is suppressed"));
+        }
+        sender.send_email(&request).await
     })
     .await
     .unwrap()
@@ -23,6 +23,7 @@ async fn main() -> Result<(), Error> {
     let payload = TelegramMessage {
         recipient: opts.recipient.clone(),
         message: opts.message.clone(),
+        ..TelegramMessage::default()
     };
     tokio::spawn(async move {
         let url = config